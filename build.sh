@@ -1,13 +1,17 @@
 #!/bin/bash
 
+# bootimage passes the kernel to run, which is a test kernel for `cargo test`, followed by any extra QEMU arguments
+KERNEL="${1:-target/pogostick/debug/bootimage-pogostick.bin}"
+shift
+
 echo "creating image"
 qemu-img.exe create disk.img 32M
 echo "copying kernel"
-dd conv=notrunc if=target/pogostick/debug/bootimage-pogostick.bin of=disk.img
+dd conv=notrunc if="${KERNEL}" of=disk.img
 echo "starting emulator"
 
 if [[ -z "${QEMU_PATH}" ]]; then
-  qemu-system-x86_64.exe -hdc "disk.img"
+  qemu-system-x86_64.exe -hdc "disk.img" -serial stdio -device isa-debug-exit,iobase=0xf4,iosize=0x04 "$@"
 else
-  qemu-system-x86_64.exe -L "${QEMU_PATH}" -hdc "disk.img"
+  qemu-system-x86_64.exe -L "${QEMU_PATH}" -hdc "disk.img" -serial stdio -device isa-debug-exit,iobase=0xf4,iosize=0x04 "$@"
 fi
@@ -1,8 +1,15 @@
 // Handles heap allocation.
-// Relies on LockedHeap to do pretty much everything except init.
+// Small allocations are served from lists of fixed-size blocks which are reused once freed,
+// so the many short-lived strings and vectors the shell creates don't fragment the heap.
+// Anything larger falls back to a linked list heap.
 
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::Heap;
+use spin::Mutex;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
@@ -12,8 +19,137 @@ use x86_64::{
 pub const HEAP_START: usize = 0x4444_4444_0000;
 pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MB
 
+/// Sizes of the fixed-size blocks.
+/// Each must be a power of two, as blocks are aligned to their size.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: CountingHeap = CountingHeap {
+    heap: Mutex::new(BlockAllocator::empty()),
+    used: AtomicUsize::new(0),
+    allocations: AtomicUsize::new(0),
+    deallocations: AtomicUsize::new(0),
+};
+
+/// Header of a free block, linking to the next free block of the same size.
+struct FreeBlock {
+    next: Option<&'static mut FreeBlock>,
+}
+
+/// Allocator which keeps a list of free blocks for each of the `BLOCK_SIZES`.
+/// Blocks are only taken from the fallback heap when their list is empty, and are returned to their list when freed.
+struct BlockAllocator {
+    free_lists: [Option<&'static mut FreeBlock>; BLOCK_SIZES.len()],
+    fallback: Heap,
+}
+
+impl BlockAllocator {
+    const fn empty() -> Self {
+        const EMPTY: Option<&'static mut FreeBlock> = None;
+        BlockAllocator {
+            free_lists: [EMPTY; BLOCK_SIZES.len()],
+            fallback: Heap::empty(),
+        }
+    }
+
+    /// Gets the index of the smallest block size which fits the layout, or `None` if it needs the fallback heap.
+    fn list_index(layout: &Layout) -> Option<usize> {
+        let required_size = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&size| size >= required_size)
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match BlockAllocator::list_index(&layout) {
+            Some(index) => match self.free_lists[index].take() {
+                Some(block) => {
+                    self.free_lists[index] = block.next.take();
+                    block as *mut FreeBlock as *mut u8
+                }
+                None => {
+                    // Allocate a new block from the fallback heap, aligned to its size
+                    let block_size = BLOCK_SIZES[index];
+                    let block_layout = Layout::from_size_align(block_size, block_size).unwrap();
+                    self.fallback_alloc(block_layout)
+                }
+            },
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match BlockAllocator::list_index(&layout) {
+            Some(index) => {
+                // Every block size is big enough and aligned enough to hold the header
+                let block = ptr as *mut FreeBlock;
+                block.write(FreeBlock {
+                    next: self.free_lists[index].take(),
+                });
+                self.free_lists[index] = Some(&mut *block);
+            }
+            None => self.fallback.deallocate(NonNull::new(ptr).unwrap(), layout),
+        }
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+// The free lists only point into the heap, which is only accessed through the mutex
+unsafe impl Send for BlockAllocator {}
+
+/// Wraps the heap to count allocations and the bytes in use.
+/// The heap is locked with interrupts disabled, so interrupt handlers (e.g. the status bar) can allocate too.
+struct CountingHeap {
+    heap: Mutex<BlockAllocator>,
+    used: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for CountingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = interrupts::without_interrupts(|| self.heap.lock().alloc(layout));
+        if !ptr.is_null() {
+            self.used.fetch_add(layout.size(), Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.heap.lock().dealloc(ptr, layout));
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Statistics about the heap.
+/// Sizes are in bytes, and only count what was requested, so space lost to rounding up to a block size counts as free.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub used: usize,
+    pub free: usize,
+    pub total: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+/// Get statistics about the heap.
+/// If the number of allocations keeps growing faster than the number of deallocations, memory is being leaked.
+pub fn stats() -> HeapStats {
+    let used = ALLOCATOR.used.load(Ordering::Relaxed);
+    HeapStats {
+        used,
+        free: HEAP_SIZE.saturating_sub(used),
+        total: HEAP_SIZE,
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed),
+        deallocations: ALLOCATOR.deallocations.load(Ordering::Relaxed),
+    }
+}
 
 /// Initialise heap allocation.
 /// This is done by iterating over all the pages of the heap and mapping them.
@@ -38,7 +174,7 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.heap.lock().fallback.init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
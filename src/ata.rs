@@ -1,16 +1,27 @@
-use crate::time::wait_nano;
-use alloc::{string::String, vec::Vec};
+use crate::block::{self, BlockDevice};
+use crate::time::{self, wait_nano};
+use crate::vga::err;
+use crate::ExitCode;
+use alloc::{boxed::Box, format, string::String, vec::Vec};
 use bit_field::BitField;
 use core::hint::spin_loop;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
+/// Seconds to wait for a drive to finish a command before giving up on it.
+const ATA_TIMEOUT: f64 = 2.0;
+/// Most times to check whether a drive has finished a command, in case the timer isn't running to time out with.
+const ATA_MAX_POLLS: usize = 10_000_000;
+
 /// Represents a command to send to the drive.
 #[repr(u16)]
 enum DriveCommand {
     Read = 0x20,
+    ReadExt = 0x24,
     Write = 0x30,
+    WriteExt = 0x34,
+    CacheFlush = 0xE7,
     Identify = 0xEC,
 }
 
@@ -54,6 +65,11 @@ pub struct Bus {
 }
 
 impl Bus {
+    /// Gets the bus's id, 0 for the primary bus and 1 for the secondary bus.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
     /// Initialises a new bus by creating port references.
     pub fn new(id: u8, io_base: u16, control_base: u16, irq: u8) -> Self {
         Self {
@@ -94,11 +110,21 @@ impl Bus {
     }
 
     /// Waits until the bus is no longer busy.
-    unsafe fn busy_loop(&mut self) {
+    /// Returns `TimeoutError` if it is still busy after `ATA_TIMEOUT` seconds (or `ATA_MAX_POLLS` checks),
+    /// so a hung drive can't hang the kernel.
+    unsafe fn busy_loop(&mut self) -> Result<(), ExitCode> {
         self.wait();
-        while self.is_busy() {
+        let deadline = time::uptime() + ATA_TIMEOUT;
+        for _ in 0..ATA_MAX_POLLS {
+            if !self.is_busy() {
+                return Ok(());
+            }
+            if time::uptime() > deadline {
+                break;
+            }
             spin_loop();
         }
+        Err(ExitCode::TimeoutError)
     }
 
     /// Detects if the bus is currently busy.
@@ -133,6 +159,21 @@ impl Bus {
         self.lba2_reg.write(block.get_bits(16..24) as u8);
     }
 
+    /// Sets up the given drive to read or write to a certain block using 48-bit LBA.
+    /// Each register is written twice, with the high bytes first then the low bytes.
+    unsafe fn setup48(&mut self, drive: u8, block: u64) {
+        let drive_id = 0x40 | (drive << 4);
+        self.drive_reg.write(drive_id);
+        self.sector_count_reg.write(0);
+        self.lba0_reg.write(block.get_bits(24..32) as u8);
+        self.lba1_reg.write(block.get_bits(32..40) as u8);
+        self.lba2_reg.write(block.get_bits(40..48) as u8);
+        self.sector_count_reg.write(1);
+        self.lba0_reg.write(block.get_bits(0..8) as u8);
+        self.lba1_reg.write(block.get_bits(8..16) as u8);
+        self.lba2_reg.write(block.get_bits(16..24) as u8);
+    }
+
     /// Sends an IDENTIFY command to the drive.
     /// Returns `Some([u16; 256])` if the drive successfully identified itself.
     /// Returns `None` if the drive did not identify itself.
@@ -150,7 +191,10 @@ impl Bus {
             return None;
         }
 
-        self.busy_loop();
+        if self.busy_loop().is_err() {
+            self.reset();
+            return None;
+        }
 
         let read1 = self.lba1_reg.read();
         let read2 = self.lba2_reg.read();
@@ -180,23 +224,50 @@ impl Bus {
     }
 
     /// Reads from the given block into the specified buffer.
-    pub unsafe fn read(&mut self, drive: u8, block: u32, buf: &mut [u8]) {
+    /// The block must be addressable with 28-bit LBA.
+    pub unsafe fn read(&mut self, drive: u8, block: u32, buf: &mut [u8]) -> Result<(), ExitCode> {
         self.setup(drive, block);
         self.command_reg.write(DriveCommand::Read as u8);
-        self.busy_loop();
+        self.read_data(buf)
+    }
+
+    /// Reads from the given block into the specified buffer using 48-bit LBA.
+    pub unsafe fn read48(&mut self, drive: u8, block: u64, buf: &mut [u8]) -> Result<(), ExitCode> {
+        self.setup48(drive, block);
+        self.command_reg.write(DriveCommand::ReadExt as u8);
+        self.read_data(buf)
+    }
+
+    /// Writes to the given block from the specified buffer.
+    /// The block must be addressable with 28-bit LBA.
+    pub unsafe fn write(&mut self, drive: u8, block: u32, buf: &[u8]) -> Result<(), ExitCode> {
+        self.setup(drive, block);
+        self.command_reg.write(DriveCommand::Write as u8);
+        self.write_data(buf)
+    }
+
+    /// Writes to the given block from the specified buffer using 48-bit LBA.
+    pub unsafe fn write48(&mut self, drive: u8, block: u64, buf: &[u8]) -> Result<(), ExitCode> {
+        self.setup48(drive, block);
+        self.command_reg.write(DriveCommand::WriteExt as u8);
+        self.write_data(buf)
+    }
+
+    /// Reads a sector of data once a read command has been sent.
+    unsafe fn read_data(&mut self, buf: &mut [u8]) -> Result<(), ExitCode> {
+        self.busy_loop()?;
 
         for i in 0..256 {
             let data = self.data_reg.read();
             buf[i * 2] = data.get_bits(0..8) as u8;
             buf[i * 2 + 1] = data.get_bits(8..16) as u8;
         }
+        Ok(())
     }
 
-    /// Writes to the given block from the specified buffer.
-    pub unsafe fn write(&mut self, drive: u8, block: u32, buf: &[u8]) {
-        self.setup(drive, block);
-        self.command_reg.write(DriveCommand::Write as u8);
-        self.busy_loop();
+    /// Writes a sector of data once a write command has been sent.
+    unsafe fn write_data(&mut self, buf: &[u8]) -> Result<(), ExitCode> {
+        self.busy_loop()?;
 
         for i in 0..256 {
             let mut data = 0 as u16;
@@ -205,92 +276,239 @@ impl Bus {
             self.data_reg.write(data);
         }
 
-        self.busy_loop();
+        self.busy_loop()
+    }
+
+    /// Tells the given drive to write its cache to the disk.
+    pub unsafe fn flush(&mut self, drive: u8) -> Result<(), ExitCode> {
+        self.select_drive(drive);
+        self.command_reg.write(DriveCommand::CacheFlush as u8);
+        self.busy_loop()
     }
 }
 
 lazy_static! {
     pub static ref BUSES: Mutex<Vec<Bus>> = Mutex::new(Vec::new());
-    pub static ref DRIVES: Mutex<Vec<Drive>> = Mutex::new(Vec::new());
 }
 
+/// Number of sectors which can be addressed with 28-bit LBA.
+pub const LBA28_SECTORS: u64 = 1 << 28;
+
 /// Represents a generic ATA drive
 pub struct Drive {
-    pub bus_index: u8,
+    /// Id of the drive's bus, 0 for the primary bus and 1 for the secondary bus.
+    pub bus_id: u8,
+    /// Position of the drive on its bus, 0 for the master and 1 for the slave.
     pub drive_index: u8,
     pub model: String,
     pub serial: String,
-    pub sectors: u32,
+    /// Capacity of the drive in sectors, which may be more than can currently be addressed.
+    pub sectors: u64,
+    /// Whether the drive supports 48-bit LBA.
+    pub lba48: bool,
 }
 
 impl Drive {
     /// Reads 512 bytes from the disk at the specified block.
     /// Writes these bytes to the given buffer.
-    pub fn read(&self, block: u32, mut buf: &mut [u8]) {
-        let mut buses = BUSES.lock();
-        unsafe { buses[self.bus_index as usize].read(self.drive_index, block, &mut buf) };
+    /// Blocks beyond the 28-bit LBA range are read with 48-bit LBA.
+    /// Returns `TimeoutError` if the drive doesn't respond.
+    pub fn read(&self, block: u32, mut buf: &mut [u8]) -> Result<(), ExitCode> {
+        self.with_bus(|bus| {
+            if block as u64 >= LBA28_SECTORS {
+                unsafe { bus.read48(self.drive_index, block as u64, &mut buf) }
+            } else {
+                unsafe { bus.read(self.drive_index, block, &mut buf) }
+            }
+        })
     }
 
     /// Writes a buffer of 512 bytes to the disk at the specified block.
     /// Buffer must be 512 bytes.
-    pub fn write(&self, block: u32, buf: &[u8]) {
+    /// Blocks beyond the 28-bit LBA range are written with 48-bit LBA.
+    /// Returns `TimeoutError` if the drive doesn't respond.
+    pub fn write(&self, block: u32, buf: &[u8]) -> Result<(), ExitCode> {
+        self.with_bus(|bus| {
+            if block as u64 >= LBA28_SECTORS {
+                unsafe { bus.write48(self.drive_index, block as u64, &buf) }
+            } else {
+                unsafe { bus.write(self.drive_index, block, &buf) }
+            }
+        })
+    }
+
+    /// Writes any data cached by the drive to the disk.
+    /// Returns `TimeoutError` if the drive doesn't respond.
+    pub fn flush(&self) -> Result<(), ExitCode> {
+        self.with_bus(|bus| unsafe { bus.flush(self.drive_index) })
+    }
+
+    /// Runs the given function on the drive's bus, which is locked while it runs.
+    /// Drives are only identified on buses in `BUSES`, so the bus is always there.
+    fn with_bus<T>(&self, f: impl FnOnce(&mut Bus) -> T) -> T {
         let mut buses = BUSES.lock();
-        unsafe { buses[self.bus_index as usize].write(self.drive_index, block, &buf) };
+        let bus = buses
+            .iter_mut()
+            .find(|bus| bus.id == self.bus_id)
+            .expect("drive on a bus which isn't in BUSES");
+        f(bus)
     }
 
-    /// Finds an available sector on the disk.
-    /// If none is found (e.g. the disk is full), returns None.
-    pub fn find_available_sector(&self) -> Option<u32> {
-        let mut current_sector = self.sectors - 1;
+    /// Reports that the drive didn't respond, suggesting how to recover.
+    fn report_error(&self, error_code: ExitCode) {
+        err(&format!(
+            "drive {} on bus {}: {}, try `ata reset {}`",
+            self.drive_index, self.bus_id, error_code, self.bus_id
+        ));
+    }
 
-        while current_sector > 0 {
-            let mut buf = [0_u8; 512];
-            self.read(current_sector, &mut buf);
-            if buf.iter().all(|el| *el == 0) {
-                return Some(current_sector);
-            }
-            current_sector -= 1;
+    /// Gets the number of sectors which can be read and written.
+    /// Block addresses are 32-bit, so larger drives are truncated, as are drives without 48-bit LBA
+    /// which report more sectors than 28-bit LBA can address.
+    pub fn addressable_sectors(&self) -> u32 {
+        if self.lba48 {
+            self.sectors.min(u32::MAX as u64) as u32
+        } else {
+            self.sectors.min(LBA28_SECTORS) as u32
         }
+    }
+}
+
+impl BlockDevice for Drive {
+    fn read(&self, block: u32, buf: &mut [u8]) -> Result<(), ExitCode> {
+        Drive::read(self, block, buf)
+    }
+
+    fn write(&self, block: u32, buf: &[u8]) -> Result<(), ExitCode> {
+        Drive::write(self, block, buf)
+    }
+
+    fn sectors(&self) -> u32 {
+        self.addressable_sectors()
+    }
 
-        None
+    fn as_ata(&self) -> Option<&Drive> {
+        Some(self)
+    }
+
+    fn description(&self) -> String {
+        format!(
+            "ATA bus {} {} {} {} ({} MB)",
+            self.bus_id,
+            if self.drive_index == 0 {
+                "master"
+            } else {
+                "slave"
+            },
+            self.model,
+            self.serial,
+            self.sectors / 2048
+        )
+    }
+
+    // Flushing can't report errors, so they are reported to the user instead
+    fn flush(&self) {
+        if let Err(error_code) = Drive::flush(self) {
+            self.report_error(error_code);
+        }
     }
 }
 
-/// Initialise and identify ATA drives
+/// Initialise and identify ATA drives, adding them to `block::DEVICES`.
 pub fn init() {
     let mut buses = BUSES.lock();
-    let mut drives = DRIVES.lock();
+    let mut drives = Vec::new();
 
-    //buses.push(Bus::new(0, 0x1F0, 0x3F6, 14)); doesn't work for some reason
+    // The primary bus is left out, as identifying drives on it doesn't work (the cause hasn't been found yet)
+    //buses.push(Bus::new(0, 0x1F0, 0x3F6, 14));
     buses.push(Bus::new(1, 0x170, 0x376, 15));
 
-    for drive in 0..2 {
-        if let Some(buf) = unsafe { buses[0_usize].identify_drive(drive) } {
-            let mut serial = String::new();
-            for i in 10..20 {
-                for &b in &buf[i].to_be_bytes() {
-                    serial.push(b as char);
-                }
+    // Drives are numbered in order of bus then position, so the master comes before the slave
+    for bus in buses.iter_mut() {
+        for drive in 0..2 {
+            if let Some(drive) = unsafe { identify(bus, drive) } {
+                drives.push(drive);
             }
-            serial = serial.trim().into();
+        }
+    }
+    drop(buses);
 
-            let mut model = String::new();
-            for i in 27..47 {
-                for &b in &buf[i].to_be_bytes() {
-                    model.push(b as char);
-                }
-            }
-            model = model.trim().into();
+    let mut devices = block::DEVICES.lock();
+    for drive in drives {
+        devices.push(Box::new(drive));
+    }
+}
+
+/// Resets the bus with the given id and identifies its drives again, replacing their entries in
+/// `block::DEVICES`.
+/// Drives on other buses are left alone, so running this repeatedly has the same effect as running it once.
+/// Returns the number of drives found on the bus, or `None` if there is no such bus.
+pub fn reset_bus(bus_id: u8) -> Option<usize> {
+    let mut buses = BUSES.lock();
+    let bus = buses.iter_mut().find(|bus| bus.id == bus_id)?;
+
+    // Identifying a drive starts with a soft reset, so this also resets the bus
+    let found: Vec<Box<dyn BlockDevice + Send>> = (0..2)
+        .filter_map(|drive| unsafe { identify(bus, drive) })
+        .map(|drive| Box::new(drive) as Box<dyn BlockDevice + Send>)
+        .collect();
+    let found_count = found.len();
+    drop(buses);
+
+    // Keep the drives in the same place so drive numbers don't change if the same drives are found
+    let mut devices = block::DEVICES.lock();
+    let on_bus = |device: &(dyn BlockDevice + Send)| {
+        device
+            .as_ata()
+            .map_or(false, |drive| drive.bus_id == bus_id)
+    };
+    let position = devices
+        .iter()
+        .position(|device| on_bus(device.as_ref()))
+        .unwrap_or_else(|| devices.len());
+    devices.retain(|device| !on_bus(device.as_ref()));
+    devices.splice(position..position, found);
+
+    Some(found_count)
+}
+
+/// Identifies a drive on the given bus, returning `None` if it doesn't respond.
+unsafe fn identify(bus: &mut Bus, drive: u8) -> Option<Drive> {
+    let buf = bus.identify_drive(drive)?;
 
-            let sectors = (buf[61] as u32) << 16 | (buf[60] as u32);
+    let mut serial = String::new();
+    for i in 10..20 {
+        for &b in &buf[i].to_be_bytes() {
+            serial.push(b as char);
+        }
+    }
+    serial = serial.trim().into();
 
-            drives.push(Drive {
-                bus_index: 0,
-                drive_index: drive,
-                model,
-                serial,
-                sectors,
-            });
+    let mut model = String::new();
+    for i in 27..47 {
+        for &b in &buf[i].to_be_bytes() {
+            model.push(b as char);
         }
     }
+    model = model.trim().into();
+
+    // Bit 10 of word 83 is set if 48-bit LBA is supported, in which case words 100-103 hold the capacity.
+    // Otherwise, words 60-61 hold the number of sectors addressable with 28-bit LBA.
+    let lba48 = buf[83].get_bit(10);
+    let sectors = if lba48 {
+        (100..104)
+            .rev()
+            .fold(0, |acc, word| acc << 16 | buf[word] as u64)
+    } else {
+        (buf[61] as u64) << 16 | (buf[60] as u64)
+    };
+
+    Some(Drive {
+        bus_id: bus.id,
+        drive_index: drive,
+        model,
+        serial,
+        sectors,
+        lba48,
+    })
 }
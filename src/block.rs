@@ -0,0 +1,180 @@
+// Block devices
+// Anything which stores 512 byte sectors, so the filesystem can run on RAM drives as well as ATA drives.
+
+use crate::{ata, ExitCode};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Size of a sector in bytes.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Represents a device which can read and write sectors.
+pub trait BlockDevice {
+    /// Reads 512 bytes from the device at the specified block into the given buffer.
+    /// If this fails, the buffer's contents are unspecified.
+    fn read(&self, block: u32, buf: &mut [u8]) -> Result<(), ExitCode>;
+
+    /// Writes a buffer of 512 bytes to the device at the specified block.
+    fn write(&self, block: u32, buf: &[u8]) -> Result<(), ExitCode>;
+
+    /// Gets the number of sectors on the device.
+    fn sectors(&self) -> u32;
+
+    /// Describes the device for listings, such as `RAM drive (32 KB)`.
+    fn description(&self) -> String {
+        format!("block device ({} KB)", self.sectors() / 2)
+    }
+
+    /// Writes any cached data to the device.
+    /// Does nothing by default.
+    fn flush(&self) {}
+
+    /// Gets the device as an ATA drive, so its bus and position can be found, or `None` if it isn't one.
+    fn as_ata(&self) -> Option<&ata::Drive> {
+        None
+    }
+
+    /// Writes a buffer of 512 bytes to the device at the specified block, then reads it back to check it was stored.
+    /// This is slower than `write`, so it's meant for critical sectors which would corrupt the filesystem if lost.
+    /// Returns `VerifyError` if the data read back doesn't match, or the device's error if it couldn't be accessed.
+    fn write_verified(&self, block: u32, buf: &[u8]) -> Result<(), ExitCode> {
+        self.write(block, buf)?;
+        self.flush();
+
+        let mut read_buf = [0_u8; SECTOR_SIZE];
+        self.read(block, &mut read_buf)?;
+        if read_buf[..] == buf[..SECTOR_SIZE] {
+            Ok(())
+        } else {
+            Err(ExitCode::VerifyError)
+        }
+    }
+
+    /// Checks whether the given sector is available, meaning it could be read and is entirely zero.
+    /// Sectors which can't be read are never available, as they may be in use.
+    fn is_available(&self, block: u32) -> bool {
+        let mut buf = [0_u8; SECTOR_SIZE];
+        self.read(block, &mut buf).is_ok() && buf.iter().all(|el| *el == 0)
+    }
+
+    /// Finds an available sector on the device, searching down from the end.
+    /// If none is found (e.g. the device is full), returns None.
+    fn find_available_sector(&self) -> Option<u32> {
+        (1..self.sectors())
+            .rev()
+            .find(|sector| self.is_available(*sector))
+    }
+
+    /// Finds the given number of available sectors, which needn't be consecutive, searching down from just below
+    /// the given sector so that sectors from there to the end (e.g. reserved for metadata) are never used.
+    /// Returns them in the order they were found, or None if there aren't enough (e.g. the device is full).
+    fn find_available_sectors(&self, below: u32, count: usize) -> Option<Vec<u32>> {
+        let mut sectors = Vec::with_capacity(count);
+        let mut current_sector = below.min(self.sectors()).saturating_sub(1);
+
+        while sectors.len() < count && current_sector > 0 {
+            if self.is_available(current_sector) {
+                sectors.push(current_sector);
+            }
+            current_sector -= 1;
+        }
+
+        if sectors.len() == count {
+            Some(sectors)
+        } else {
+            None
+        }
+    }
+
+    /// Finds a run of consecutive available sectors, searching down from just below the given sector like
+    /// `find_available_sectors`.
+    /// Returns the first sector of the run, or None if there isn't a long enough run.
+    fn find_available_run(&self, below: u32, count: u32) -> Option<u32> {
+        let mut run_length = 0;
+        let mut current_sector = below.min(self.sectors()).saturating_sub(1);
+
+        while current_sector > 0 && count > 0 {
+            if self.is_available(current_sector) {
+                run_length += 1;
+                if run_length == count {
+                    return Some(current_sector);
+                }
+            } else {
+                run_length = 0;
+            }
+            current_sector -= 1;
+        }
+
+        None
+    }
+}
+
+/// Represents a drive stored entirely in memory, which is lost when it is dropped.
+pub struct RamDrive {
+    data: Mutex<Vec<u8>>,
+    sectors: u32,
+}
+
+impl RamDrive {
+    /// Creates a zeroed RAM drive with the given number of sectors.
+    pub fn new(sectors: u32) -> Self {
+        Self {
+            data: Mutex::new(vec![0_u8; sectors as usize * SECTOR_SIZE]),
+            sectors,
+        }
+    }
+}
+
+impl RamDrive {
+    /// Gets where the given block starts in the drive's data.
+    /// Returns `NotFoundError` if the block is past the end of the drive, or the buffer is too small for a sector.
+    fn block_start(&self, block: u32, buf_len: usize) -> Result<usize, ExitCode> {
+        if block >= self.sectors || buf_len < SECTOR_SIZE {
+            return Err(ExitCode::NotFoundError);
+        }
+        Ok(block as usize * SECTOR_SIZE)
+    }
+}
+
+impl BlockDevice for RamDrive {
+    fn read(&self, block: u32, buf: &mut [u8]) -> Result<(), ExitCode> {
+        let start = self.block_start(block, buf.len())?;
+        buf[..SECTOR_SIZE].copy_from_slice(&self.data.lock()[start..start + SECTOR_SIZE]);
+        Ok(())
+    }
+
+    fn write(&self, block: u32, buf: &[u8]) -> Result<(), ExitCode> {
+        let start = self.block_start(block, buf.len())?;
+        self.data.lock()[start..start + SECTOR_SIZE].copy_from_slice(&buf[..SECTOR_SIZE]);
+        Ok(())
+    }
+
+    fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    fn description(&self) -> String {
+        format!("RAM drive ({} KB)", self.sectors / 2)
+    }
+}
+
+lazy_static! {
+    /// Every block device, such as ATA drives and RAM drives, numbered by their position.
+    /// ATA drives are added first when they are identified, so they come before any added later.
+    pub static ref DEVICES: Mutex<Vec<Box<dyn BlockDevice + Send>>> = Mutex::new(Vec::new());
+}
+
+/// Gets the total number of block devices.
+pub fn device_count() -> usize {
+    DEVICES.lock().len()
+}
+
+/// Runs the given function on the block device with the given index.
+/// Returns `None` if there is no such device.
+pub fn with_device<T>(drive_index: usize, f: impl FnOnce(&dyn BlockDevice) -> T) -> Option<T> {
+    DEVICES
+        .lock()
+        .get(drive_index)
+        .map(|device| f(device.as_ref()))
+}
@@ -0,0 +1,136 @@
+// Expression evaluation
+// Evaluates arithmetic expressions for the `calc` command, such as `2 * (3 + 4.5) / -5`.
+//
+// Parsing is recursive descent over this grammar, so `*` and `/` bind tighter than `+` and `-`, and operators of the
+// same precedence are evaluated from left to right:
+//   expression = term { ("+" | "-") term }
+//   term       = factor { ("*" | "/") factor }
+//   factor     = ("+" | "-") factor | number | "(" expression ")"
+
+use alloc::{string::String, vec::Vec};
+
+/// Deepest that brackets and signs can be nested, so a long expression can't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// Reason an expression couldn't be evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalcError {
+    /// The expression is malformed, such as `1 +` or `(2`, or nested too deeply.
+    Syntax,
+    /// Part of the expression divides by zero.
+    DivideByZero,
+}
+
+/// Evaluates an arithmetic expression with `+`, `-`, `*`, `/` and brackets, where spaces around numbers are ignored.
+pub fn evaluate(expression: &str) -> Result<f64, CalcError> {
+    let mut parser = Parser {
+        chars: expression.chars().collect(),
+        position: 0,
+        depth: 0,
+    };
+
+    let result = parser.expression()?;
+    parser.skip_spaces();
+    if parser.position == parser.chars.len() {
+        Ok(result)
+    } else {
+        // Something like `2 3` or `1)` was left over
+        Err(CalcError::Syntax)
+    }
+}
+
+/// Position in the expression being parsed, which each rule moves past what it parsed.
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    /// Moves past any spaces.
+    fn skip_spaces(&mut self) {
+        while self.chars.get(self.position) == Some(&' ') {
+            self.position += 1;
+        }
+    }
+
+    /// Moves past the next character other than a space if it is one of the given ones, returning it.
+    fn take(&mut self, options: &[char]) -> Option<char> {
+        self.skip_spaces();
+        let next = *self.chars.get(self.position)?;
+        if options.contains(&next) {
+            self.position += 1;
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    fn expression(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.term()?;
+        while let Some(operator) = self.take(&['+', '-']) {
+            let term = self.term()?;
+            value = if operator == '+' {
+                value + term
+            } else {
+                value - term
+            };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.factor()?;
+        while let Some(operator) = self.take(&['*', '/']) {
+            let factor = self.factor()?;
+            value = if operator == '*' {
+                value * factor
+            } else if factor == 0.0 {
+                return Err(CalcError::DivideByZero);
+            } else {
+                value / factor
+            };
+        }
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> Result<f64, CalcError> {
+        if self.depth == MAX_DEPTH {
+            return Err(CalcError::Syntax);
+        }
+
+        if let Some(sign) = self.take(&['+', '-']) {
+            self.depth += 1;
+            let factor = self.factor()?;
+            self.depth -= 1;
+            return Ok(if sign == '-' { -factor } else { factor });
+        }
+
+        if self.take(&['(']).is_some() {
+            self.depth += 1;
+            let value = self.expression()?;
+            self.depth -= 1;
+            return match self.take(&[')']) {
+                Some(_) => Ok(value),
+                None => Err(CalcError::Syntax),
+            };
+        }
+
+        self.skip_spaces();
+        let start = self.position;
+        while self
+            .chars
+            .get(self.position)
+            .map_or(false, |c| c.is_ascii_digit() || *c == '.')
+        {
+            self.position += 1;
+        }
+
+        // Nothing here is an error too, as an empty string doesn't parse
+        self.chars[start..self.position]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .map_err(|_| CalcError::Syntax)
+    }
+}
@@ -1,85 +1,650 @@
-use crate::vga::{err, info, okay, warn, Colour, ColourCode, BUFFER_HEIGHT, WRITER};
-use crate::{input::STDIN, println, time::DateTime, ExitCode};
+use crate::calc::CalcError;
+use crate::fs::{FileHandle, FileType, FileWriter, SortMode, FLAG_HIDDEN, FLAG_READ_ONLY};
+use crate::input::{Completer, Completion, InputEvent, SpecialKey, STDIN};
+use crate::vga::{
+    err, info, okay, progress, set_cursor_shape, set_cursor_visible, warn, Colour, ColourCode,
+    CursorShape, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER,
+};
+use crate::{println, time::DateTime, ExitCode};
 use alloc::{
     borrow::ToOwned,
     boxed::Box,
+    collections::VecDeque,
     format,
     string::{String, ToString},
+    vec,
     vec::Vec,
 };
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
 use spin::Mutex;
 use x86_64::instructions::interrupts;
 
+/// Number of commands kept in the history.
+const HISTORY_SIZE: usize = 32;
+/// Most times `repeat` will run a command, so a mistyped count can't tie up the console for hours.
+const MAX_REPEAT_COUNT: usize = 1000;
+/// Name of the hidden file in the root directory which the history is saved to.
+const HISTORY_FILE: &str = "history";
+/// Prompt format used until it is changed with `prompt`, see `parse_prompt`.
+const DEFAULT_PROMPT: &str = "pogo:$~\\p";
+
+/// Whether the `exit` command has been run, so the shell should return once it finishes.
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     pub static ref PATH: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static ref HISTORY: Mutex<VecDeque<String>> = Mutex::new(VecDeque::with_capacity(HISTORY_SIZE));
+    static ref BOOT_TIME: Mutex<Option<DateTime>> = Mutex::new(None);
+    static ref PROMPT: Mutex<String> = Mutex::new(String::from(DEFAULT_PROMPT));
+}
+
+/// Part of the prompt, which is shown before each command.
+enum PromptPart {
+    Text(String),
+    Path,
+    Time,
+    Uptime,
 }
 
-/// Provide a console input forever
+/// Provide a console input forever, starting a new shell whenever one exits
 pub fn console_loop() -> ! {
-    info(&format!(
-        "boot completed at {}\n\n",
-        DateTime::get().to_string()
-    ));
+    load_history();
+    let boot_time = DateTime::get();
+    info(&format!("boot completed at {}\n\n", boot_time.to_string()));
+    *BOOT_TIME.lock() = Some(boot_time);
+    crate::statusbar::init();
 
-    let prompt_colour = ColourCode::new(Colour::LightGreen, Colour::Black);
-    let path_colour = ColourCode::new(Colour::LightCyan, Colour::Black);
+    loop {
+        shell();
+        save_history();
 
-    let lock_write_colour = |text: &str, colour: ColourCode| {
-        interrupts::without_interrupts(|| {
-            WRITER.lock().write_string_colour(text, colour);
-        });
-    };
+        info("the shell has exited, press any key to start a new one\n");
+        STDIN.clear();
+        STDIN.get_event();
+        println!();
+    }
+}
+
+/// Run a shell, reading and executing commands until the `exit` command is run.
+/// Every lock taken by the shell is released by the time it returns.
+pub fn shell() -> ExitCode {
+    EXIT_REQUESTED.store(false, Ordering::Relaxed);
 
     loop {
+        let mounts = crate::fs::FILESYSTEM.lock();
+        let drive_display = match mounts.current {
+            Some(drive_index) if mounts.filesystems.len() > 1 => format!("{}:", drive_index),
+            _ => String::new(),
+        };
+        let free_kb = mounts
+            .get(None)
+            .and_then(|filesystem| filesystem.free_sectors().ok())
+            .map(|free_sectors| free_sectors / 2);
+        drop(mounts);
+
         let path_lock = PATH.lock();
         let path = path_lock.clone();
-        let mut path_display = path.iter().fold(String::from("/"), |mut acc, x| {
+        let path_display = path.iter().fold(drive_display + "/", |mut acc, x| {
             acc.extend(x.chars());
             acc.push('/');
             acc
         });
-        path_display.push(' ');
         drop(path_lock);
+        crate::statusbar::set(path_display.clone(), free_kb);
+
+        print_prompt(&path_display);
+        set_cursor_visible(true);
+        let completer = ShellCompleter { path_display };
+        let command_str = match expand_history(&STDIN.get_str_completing(&completer)) {
+            Some(command_str) => command_str,
+            None => {
+                err(&ExitCode::NotFoundError.to_string());
+                println!();
+                continue;
+            }
+        };
+        if !command_str.trim().is_empty() {
+            add_to_history(&command_str);
+        }
 
-        lock_write_colour("pogo:$~", prompt_colour);
-        lock_write_colour(&path_display, path_colour);
-        let command_str = STDIN.get_str();
         let command_split: Vec<&str> = command_str.split(" ").collect();
+        let name = command_split[0];
         let command = create_command(command_split);
 
+        set_cursor_visible(false);
         let status_code = command.execute();
         match status_code {
             ExitCode::Success => ExitCode::Success,
             _ => {
-                err(&status_code.to_string());
+                let suggestion = match status_code {
+                    ExitCode::InvalidCommandError => suggest_command(name),
+                    _ => None,
+                };
+                match suggestion {
+                    Some(suggestion) => {
+                        err(&format!("{}; did you mean `{}`?", status_code, suggestion))
+                    }
+                    None => err(&status_code.to_string()),
+                };
                 info("try running `help <command name>` for help\n");
                 ExitCode::Error
             }
         };
         println!();
+
+        if EXIT_REQUESTED.load(Ordering::Relaxed) {
+            return ExitCode::Success;
+        }
+    }
+}
+
+/// Shows the prompt in the format set with `prompt`, where the path is shown as given.
+fn print_prompt(path_display: &str) {
+    let prompt_colour = ColourCode::new(Colour::LightGreen, Colour::Black);
+    let path_colour = ColourCode::new(Colour::LightCyan, Colour::Black);
+
+    let lock_write_colour = |text: &str, colour: ColourCode| {
+        interrupts::without_interrupts(|| {
+            WRITER.lock().write_string_colour(text, colour);
+        });
+    };
+
+    // The format was checked when it was set, so it always parses
+    let prompt = parse_prompt(&PROMPT.lock()).unwrap_or_default();
+    for part in prompt {
+        match part {
+            PromptPart::Text(text) => lock_write_colour(&text, prompt_colour),
+            PromptPart::Path => lock_write_colour(path_display, path_colour),
+            PromptPart::Time => lock_write_colour(&DateTime::get().to_time_string(), prompt_colour),
+            PromptPart::Uptime => {
+                lock_write_colour(&format!("{:.0}s", crate::time::uptime()), prompt_colour)
+            }
+        }
+    }
+    lock_write_colour(" ", prompt_colour);
+}
+
+/// Completes command names at the prompt, showing the prompt for the given path again after listing them.
+struct ShellCompleter {
+    path_display: String,
+}
+
+impl Completer for ShellCompleter {
+    fn complete(&self, line: &str) -> Completion {
+        complete_command(line)
+    }
+    fn redraw_prompt(&self) {
+        print_prompt(&self.path_display);
+    }
+}
+
+/// Asks the user a yes or no question, returning true only if they answer with `y` or `Y`.
+/// The prompt is shown as a warning, followed by ` (y/n): `.
+pub fn confirm(prompt: &str) -> bool {
+    warn(&format!("{} (y/n): ", prompt));
+    let answer = STDIN.get_char();
+    println!();
+    matches!(answer, 'y' | 'Y')
+}
+
+/// Pauses long output after every screenful of lines until a key is pressed, so the start of it can be read.
+struct Pager {
+    lines: usize,
+}
+
+impl Pager {
+    fn new() -> Self {
+        Pager { lines: 0 }
+    }
+
+    /// Counts a line which is about to be printed, first waiting for a key if the screen is full.
+    /// Returns false if the user pressed `q` or escape to stop the output.
+    fn next_line(&mut self) -> bool {
+        // Leave a row for the prompt, and don't count rows taken by the status bar
+        let page_lines = interrupts::without_interrupts(|| WRITER.lock().scrolling_rows()) - 1;
+        if self.lines < page_lines {
+            self.lines += 1;
+            return true;
+        }
+
+        let prompt = "-- more -- (any key to continue, q to stop)";
+        let row = interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let row = writer.save_cursor().row;
+            writer.write_string_colour(prompt, ColourCode::new(Colour::Black, Colour::LightGray));
+            row
+        });
+
+        STDIN.clear();
+        let event = STDIN.get_event();
+
+        // Rub out the prompt so the output carries on in its place
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.goto(row, 0);
+            writer.write_string(&" ".repeat(prompt.len()));
+            writer.goto(row, 0);
+        });
+
+        self.lines = 1;
+        !matches!(
+            event,
+            InputEvent::Char('q') | InputEvent::Key(SpecialKey::Escape)
+        )
+    }
+}
+
+/// Resolves a path given by the user into the drive it refers to and its components.
+/// Paths starting with a drive index and colon (e.g. `1:/documents`) are absolute on that drive,
+/// paths starting with `/` are absolute on the current drive, and others are relative to `PATH`.
+/// A drive of `None` refers to the current drive.
+fn resolve_path(path: &str) -> (Option<u8>, Vec<String>) {
+    let (drive, path) = match path.split_once(':') {
+        Some((drive, rest)) => match drive.parse::<u8>() {
+            Ok(drive_index) => (Some(drive_index), rest),
+            Err(_) => (None, path),
+        },
+        None => (None, path),
+    };
+
+    let mut components = if drive.is_some() || path.starts_with('/') {
+        Vec::new()
+    } else {
+        PATH.lock().clone()
+    };
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name.to_owned()),
+        }
+    }
+
+    (drive, components)
+}
+
+/// Resolves a path given by the user like `resolve_path`, expanding a pattern (see `pattern`) in its last component
+/// into every file in the directory which matches, in alphabetical order and leaving out hidden files.
+/// Paths without `*`, `?` or `[` are returned as they are, even if they don't exist.
+fn resolve_glob(path: &str) -> (Option<u8>, Vec<Vec<String>>) {
+    let (drive, mut components) = resolve_path(path);
+    let pattern = match components.last() {
+        Some(last) if last.contains(&['*', '?', '['][..]) => components.pop().unwrap(),
+        _ => return (drive, vec![components]),
+    };
+
+    let mounts = crate::fs::FILESYSTEM.lock();
+    let entries = mounts
+        .get(drive)
+        .and_then(|filesystem| filesystem.list_entries(&components))
+        .unwrap_or_default();
+    drop(mounts);
+
+    let mut names: Vec<String> = entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            FileType::File(file)
+                if !file.is_hidden() && crate::pattern::matches(&pattern, &file.name) =>
+            {
+                Some(file.name)
+            }
+            _ => None,
+        })
+        .collect();
+    names.sort();
+
+    let paths = names
+        .into_iter()
+        .map(|name| {
+            let mut path = components.clone();
+            path.push(name);
+            path
+        })
+        .collect();
+    (drive, paths)
+}
+
+/// Iterator over the lines of a file, without their line endings.
+/// The file is read a sector at a time, so large files don't have to fit in memory,
+/// except for compressed files which the handle decodes all at once.
+struct FileLines {
+    handle: FileHandle,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl FileLines {
+    /// Opens the file at a path given by the user, which is resolved like `resolve_path`.
+    fn open(name: &str) -> Result<Self, ExitCode> {
+        let (drive, path) = resolve_path(name);
+        let handle = match crate::fs::FILESYSTEM.lock().get(drive) {
+            Some(filesystem) => filesystem.open_file(&path)?,
+            None => return Err(ExitCode::NotMountedError),
+        };
+
+        Ok(FileLines {
+            handle,
+            chunk: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Reads the next chunk of the file, returning false at the end of the file.
+    fn refill(&mut self) -> bool {
+        self.position = 0;
+        self.chunk.resize(crate::fs::DATA_SECTOR_CAPACITY, 0);
+        let read_bytes = self.handle.read(&mut self.chunk);
+        self.chunk.truncate(read_bytes);
+        read_bytes > 0
+    }
+}
+
+impl Iterator for FileLines {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut line = Vec::new();
+        loop {
+            if self.position == self.chunk.len() && !self.refill() {
+                // Like `str::lines`, the last line doesn't need a newline but an empty one isn't counted
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Some(line).filter(|line| !line.is_empty());
+            }
+
+            let byte = self.chunk[self.position];
+            self.position += 1;
+            if byte == b'\n' {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Some(line);
+            }
+            line.push(byte);
+        }
+    }
+}
+
+/// Prints bytes as text if they are UTF-8, or otherwise as hex with a warning.
+fn print_text_or_hex(file_bytes: &[u8]) {
+    if let Ok(file_text) = core::str::from_utf8(file_bytes) {
+        println!("{}", file_text)
+    } else {
+        let mut bytes = vec![0_u8; file_bytes.len() * 2];
+        hex::encode_to_slice(file_bytes, &mut bytes).unwrap();
+        warn("cannot detect encoding, printing as hex\n\n");
+        println!("{}", core::str::from_utf8(&bytes).unwrap());
+    }
+}
+
+/// Adds a command to the history, discarding the oldest command if it's full.
+fn add_to_history(command: &str) {
+    let mut history = HISTORY.lock();
+    if history.len() == HISTORY_SIZE {
+        history.pop_front();
+    }
+    history.push_back(command.to_owned());
+}
+
+/// Expands a `!n` command into the nth command of the history, printing the result.
+/// Other commands are returned unchanged, and `None` is returned if there is no such entry.
+fn expand_history(command: &str) -> Option<String> {
+    if let Some(index) = command.strip_prefix('!') {
+        let index = index.parse::<usize>().ok()?.checked_sub(1)?;
+        let expanded = HISTORY.lock().get(index)?.clone();
+        println!("{}", expanded);
+        Some(expanded)
+    } else {
+        Some(command.to_owned())
+    }
+}
+
+/// Parses a prompt format, where `\p` stands for the current path, `\t` the time, `\u` the uptime and `\\` a
+/// backslash. Returns the first unknown token as an error, such as `\x` or a backslash at the end.
+fn parse_prompt(format: &str) -> Result<Vec<PromptPart>, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = format.chars();
+
+    while let Some(character) = chars.next() {
+        if character != '\\' {
+            text.push(character);
+            continue;
+        }
+
+        let part = match chars.next() {
+            Some('\\') => {
+                text.push('\\');
+                continue;
+            }
+            Some('p') => PromptPart::Path,
+            Some('t') => PromptPart::Time,
+            Some('u') => PromptPart::Uptime,
+            Some(other) => return Err(format!("\\{}", other)),
+            None => return Err(String::from("\\")),
+        };
+        if !text.is_empty() {
+            parts.push(PromptPart::Text(core::mem::take(&mut text)));
+        }
+        parts.push(part);
+    }
+
+    if !text.is_empty() {
+        parts.push(PromptPart::Text(text));
+    }
+    Ok(parts)
+}
+
+/// Saves the history to a hidden file in the root of the current filesystem.
+pub fn save_history() -> ExitCode {
+    let mut mounts = crate::fs::FILESYSTEM.lock();
+    let filesystem = match mounts.get_mut(None) {
+        Some(filesystem) => filesystem,
+        None => return ExitCode::NotMountedError,
+    };
+
+    let path = vec![HISTORY_FILE.to_owned()];
+    let history: Vec<String> = HISTORY.lock().iter().cloned().collect();
+
+    if filesystem.get_file(&path).is_some() {
+        match filesystem.delete_file(&path) {
+            ExitCode::Success => (),
+            error_code => return error_code,
+        }
+    }
+    match filesystem.write_file(&path, history.join("\n").into_bytes()) {
+        ExitCode::Success => filesystem.set_flags(&path, FLAG_HIDDEN, true),
+        error_code => error_code,
+    }
+}
+
+/// Loads the history from the root of the current filesystem, if it was saved there.
+pub fn load_history() {
+    let mounts = crate::fs::FILESYSTEM.lock();
+    let file = mounts
+        .get(None)
+        .and_then(|filesystem| filesystem.get_file(&vec![HISTORY_FILE.to_owned()]));
+    drop(mounts);
+
+    if let Some(Ok(bytes)) = file.map(|file| file.read()) {
+        if let Ok(text) = core::str::from_utf8(&bytes) {
+            for command in text.lines().filter(|line| !line.is_empty()) {
+                add_to_history(command);
+            }
+        }
+    }
+}
+
+/// Creates a command from the arguments given after its name.
+type Constructor = fn(&[&str]) -> Box<dyn Command>;
+
+/// Every command the shell knows, by name, which unknown commands are also compared against to suggest one.
+const COMMANDS: &[(&str, Constructor)] = &[
+    ("cd", |args| CDCommand::new(args)),
+    ("echo", |args| Echo::new(args)),
+    ("clear", |_| ClearCommand::new(&[])),
+    ("exit", |_| ExitCommand::new(&[])),
+    ("add", |args| {
+        ArithmeticCommand::new_with(args, Operation::Add)
+    }),
+    ("sub", |args| {
+        ArithmeticCommand::new_with(args, Operation::Subtract)
+    }),
+    ("mul", |args| {
+        ArithmeticCommand::new_with(args, Operation::Multiply)
+    }),
+    ("div", |args| {
+        ArithmeticCommand::new_with(args, Operation::Divide)
+    }),
+    ("mod", |args| {
+        ArithmeticCommand::new_with(args, Operation::Modulo)
+    }),
+    ("calc", |args| CalcCommand::new(args)),
+    ("disk", |_| DiskInfoCommand::new(&[])),
+    ("ata", |args| AtaCommand::new(args)),
+    ("ls", |args| ListFilesCommand::new(args)),
+    ("dir", |args| ListFilesCommand::new(args)),
+    ("mkdir", |args| CreateDirCommand::new(args)),
+    ("wt", |args| WriteCommand::new(args)),
+    ("wx", |args| WriteHexCommand::new(args)),
+    ("rt", |args| ReadCommand::new(args)),
+    ("cat", |args| ConcatenateCommand::new(args)),
+    ("wc", |args| WordCountCommand::new(args)),
+    ("grep", |args| GrepCommand::new(args)),
+    ("sort", |args| SortCommand::new(args)),
+    ("uniq", |args| UniqCommand::new(args)),
+    ("head", |args| HeadTailCommand::new_with(args, false)),
+    ("tail", |args| HeadTailCommand::new_with(args, true)),
+    ("edit", |args| EditCommand::new(args)),
+    ("rename", |args| RenameCommand::new(args)),
+    ("cp", |args| CopyCommand::new(args)),
+    ("rm", |args| RemoveCommand::new(args)),
+    ("rmdir", |args| RemoveCommand::new(args)),
+    ("undelete", |args| UndeleteCommand::new(args)),
+    ("stat", |args| StatCommand::new(args)),
+    ("mount", |args| MountCommand::new(args)),
+    ("unmount", |args| UnmountCommand::new(args)),
+    ("sync", |_| SyncCommand::new(&[])),
+    ("fsreset", |_| FsResetCommand::new(&[])),
+    ("mkfs", |args| MakeFilesystemCommand::new(args)),
+    ("dump", |args| DumpCommand::new(args)),
+    ("writesec", |args| WriteSectorCommand::new(args)),
+    ("diskspeed", |args| DiskSpeedCommand::new(args)),
+    ("cpuinfo", |_| CpuInfoCommand::new(&[])),
+    ("mem", |_| MemCommand::new(&[])),
+    ("bootinfo", |_| BootInfoCommand::new(&[])),
+    ("sysinfo", |_| SysInfoCommand::new(&[])),
+    ("cursor", |args| CursorCommand::new(args)),
+    ("keyrate", |args| KeyRateCommand::new(args)),
+    ("screensaver", |args| ScreensaverCommand::new(args)),
+    ("scancode", |_| ScancodeCommand::new(&[])),
+    ("watch", |args| WatchCommand::new(args)),
+    ("repeat", |args| RepeatCommand::new(args)),
+    ("beep", |args| BeepCommand::new(args)),
+    ("gfx", |_| GraphicsCommand::new(&[])),
+    ("play", |args| PlayCommand::new(args)),
+    ("history", |_| HistoryCommand::new(&[])),
+    ("attrib", |args| AttribCommand::new(args)),
+    ("time", |_| TimeCommand::new(&[])),
+    ("uptime", |_| Uptime::new(&[])),
+    ("prompt", |args| PromptCommand::new(args)),
+    ("load", |_| LoadCommand::new(&[])),
+    ("interrupts", |_| InterruptsCommand::new(&[])),
+    ("help", |args| HelpCommand::new(args)),
+    ("selftest", |_| SelfTestCommand::new(&[])),
+];
+
+/// Gets the names of every command the shell knows.
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().map(|(name, _)| *name)
+}
+
+/// Counts the fewest single character insertions, deletions and substitutions which turn one string into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Only the previous row of the table is needed to work out the next
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + (a_char != *b_char) as usize;
+            row.push(substitution.min(previous[j + 1] + 1).min(row[j] + 1));
+        }
+        previous = row;
+    }
+
+    previous[b.len()]
+}
+
+/// Finds the known command closest to a mistyped one, if any is at most two edits away.
+/// Commands which would need every character changed aren't suggested, so `q` doesn't suggest `cd`.
+pub fn suggest_command(name: &str) -> Option<&'static str> {
+    command_names()
+        .map(|command| (edit_distance(name, command), command))
+        .filter(|(distance, command)| *distance <= 2 && *distance < name.len().max(command.len()))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, command)| command)
+}
+
+/// Completes the first word of a line into the command names starting with it.
+/// A single match is completed along with a space after it, and several are completed as far as they agree before
+/// being listed. Later words are left alone, as they are arguments rather than commands.
+pub fn complete_command(line: &str) -> Completion {
+    if line.contains(' ') {
+        return Completion::None;
+    }
+
+    let mut candidates: Vec<&str> = command_names()
+        .filter(|command| command.starts_with(line))
+        .collect();
+    candidates.sort_unstable();
+
+    match candidates.as_slice() {
+        [] => Completion::None,
+        [command] => Completion::Append(format!("{} ", &command[line.len()..])),
+        [first, rest @ ..] => {
+            // Commands are ASCII, so the shared prefix can be measured in bytes
+            let shared = rest.iter().fold(first.len(), |shared, command| {
+                first
+                    .bytes()
+                    .zip(command.bytes())
+                    .take(shared)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            });
+
+            if shared > line.len() {
+                Completion::Append(first[line.len()..shared].to_owned())
+            } else {
+                Completion::Candidates(
+                    candidates
+                        .iter()
+                        .map(|command| command.to_string())
+                        .collect(),
+                )
+            }
+        }
     }
 }
 
 /// Parses a command object by name
 fn create_command(args: Vec<&str>) -> Box<dyn Command> {
-    match args[0] {
-        "cd" => CDCommand::new(&args[1..]),
-        "echo" => Echo::new(&args[1..]),
-        "clear" => ClearCommand::new(&[]),
-        "add" => AddCommand::new(&args[1..]),
-        "disk" => DiskInfoCommand::new(&[]),
-        "ls" | "dir" => ListFilesCommand::new(&[]),
-        "mkdir" => CreateDirCommand::new(&args[1..]),
-        "wt" => WriteCommand::new(&args[1..]),
-        "rt" => ReadCommand::new(&args[1..]),
-        "rename" => RenameCommand::new(&args[1..]),
-        "rm" => RemoveFileCommand::new(&args[1..]),
-        "rmdir" => RemoveDirCommand::new(&args[1..]),
-        "time" => TimeCommand::new(&[]),
-        "uptime" => Uptime::new(&[]),
-        "help" => HelpCommand::new(&args[1..]),
-        _ => NullCommand::new(&[]),
+    // Left out of `COMMANDS` so it's never suggested, and only available in debug builds
+    if args[0] == "crashtest" && cfg!(debug_assertions) {
+        return CrashTestCommand::new(&args[1..]);
+    }
+
+    match COMMANDS.iter().find(|(name, _)| *name == args[0]) {
+        Some((_, constructor)) => constructor(&args[1..]),
+        None => NullCommand::new(&[]),
     }
 }
 
@@ -122,6 +687,49 @@ impl Command for Echo {
     }
 }
 
+/// Command to change the prompt shown before each command
+struct PromptCommand {
+    format: Option<String>,
+}
+
+impl Command for PromptCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        // The format can contain spaces, which split it into several arguments
+        let format = match args {
+            [] | [""] => None,
+            ["-r"] => Some(String::from(DEFAULT_PROMPT)),
+            args => Some(args.join(" ")),
+        };
+
+        Box::new(PromptCommand { format })
+    }
+    fn execute(&self) -> ExitCode {
+        match &self.format {
+            Some(format) => match parse_prompt(format) {
+                Ok(_) => {
+                    *PROMPT.lock() = format.clone();
+                    ExitCode::Success
+                }
+                Err(token) => err(&format!(
+                    "unknown token `{}`, use \\p, \\t, \\u or \\\\",
+                    token
+                )),
+            },
+            None => {
+                println!("{}", *PROMPT.lock());
+                ExitCode::Success
+            }
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            sets the prompt, where \\p is the path, \\t the time, \\u the uptime and \\\\ a backslash,
+                          or shows it if no prompt is given, use -r to go back to the default
+         usage:           prompt [-r] [format]
+         example command: prompt [\\t] \\p>
+         example output:  N/A"
+    }
+}
+
 /// Uptime command, prints system uptime to the console
 struct Uptime;
 
@@ -142,406 +750,2785 @@ impl Command for Uptime {
     }
 }
 
-/// Change directory command
-struct CDCommand {
-    pub new_dir: String,
-}
+/// Command to print roughly how busy the processor has been
+struct LoadCommand;
 
-impl Command for CDCommand {
-    fn new(args: &[&str]) -> Box<Self> {
-        Box::new(CDCommand {
-            new_dir: args[0].to_string(),
-        })
+impl Command for LoadCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(LoadCommand)
     }
     fn execute(&self) -> ExitCode {
-        let filesystem = crate::fs::FILESYSTEM.lock();
-        if let Some(fs) = filesystem.as_ref() {
-            let mut new_dir = self.new_dir.clone();
-            if new_dir.chars().nth(0) == Some('/') {
-                new_dir.remove(0);
-            }
-            if new_dir.chars().last() == Some('/') {
-                new_dir.pop();
-            }
+        let idle_percent = crate::time::cpu_idle_percent();
+        println!(
+            "cpu idle: {:.1}%, busy: {:.1}%",
+            idle_percent,
+            100.0 - idle_percent
+        );
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints roughly how much of the time since boot the cpu has been idle
+         usage:           load
+         example command: load
+         example output:  cpu idle: 98.2%, busy: 1.8%"
+    }
+}
 
-            let mut prospective_path = PATH.lock().clone();
-            if new_dir == "" {
-                prospective_path = Vec::new();
-            } else if new_dir == ".." {
-                prospective_path.pop();
-            } else {
-                prospective_path.extend(new_dir.split("/").map(|s| s.to_owned()));
-            }
+/// Command to print how many times each interrupt handler has run
+struct InterruptsCommand;
 
-            if fs.list_files(&prospective_path).is_some() {
-                *PATH.lock() = prospective_path.clone();
-                ExitCode::Success
-            } else {
-                ExitCode::NotFoundError
-            }
-        } else {
-            ExitCode::NotMountedError
-        }
+impl Command for InterruptsCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(InterruptsCommand)
     }
+    fn execute(&self) -> ExitCode {
+        // Rates are measured against the clock rather than the uptime, as the uptime is itself counted in timer ticks
+        let seconds = BOOT_TIME
+            .lock()
+            .as_ref()
+            .map(|boot_time| {
+                DateTime::get()
+                    .to_unix()
+                    .saturating_sub(boot_time.to_unix())
+            })
+            .filter(|seconds| *seconds > 0);
+
+        println!("vector  handler          count  per second");
+        for (vector, name, count) in crate::interrupts::counts() {
+            let rate = match seconds {
+                Some(seconds) => format!("{:.1}", count as f64 / seconds as f64),
+                None => "-".to_owned(),
+            };
+            println!("{:<7} {:<12} {:>9}  {}", vector, name, count, rate);
+        }
 
+        let mask = crate::interrupts::irq_mask();
+        let unmasked: Vec<String> = (0..16)
+            .filter(|irq| mask & 1 << irq == 0)
+            .map(|irq| irq.to_string())
+            .collect();
+        info(&format!("unmasked irqs: {}\n", unmasked.join(" ")));
+        info(&format!(
+            "the timer should run {:.1} times per second\n",
+            crate::time::pit_frequency()
+        ));
+        ExitCode::Success
+    }
     fn usage(&self) -> &str {
-        "help:            changes directory to the given directory
-         usage:           cd <new dir path or .. for up one>
-         example command: cd /
-         example output:  N/A"
+        "help:            prints how many times each interrupt handler has run, and how often per second by the clock
+                          since boot, to check that the timer and keyboard interrupts are arriving, then which irq
+                          lines aren't masked
+         usage:           interrupts
+         example command: interrupts
+         example output:  32      timer           600123  1000.2"
     }
 }
 
-/// Command to clear the screen
-struct ClearCommand;
+/// Command to print a summary of the system
+struct SysInfoCommand;
 
-impl Command for ClearCommand {
+impl Command for SysInfoCommand {
     fn new(_args: &[&str]) -> Box<Self> {
-        Box::new(ClearCommand)
+        Box::new(SysInfoCommand)
     }
     fn execute(&self) -> ExitCode {
-        interrupts::without_interrupts(|| {
-            for _ in 0..BUFFER_HEIGHT {
-                WRITER.lock().new_line();
+        info("system\n");
+        println!(
+            "  kernel:  {} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        );
+        if let Some(boot_time) = &*BOOT_TIME.lock() {
+            println!("  booted:  {}", boot_time);
+        }
+        println!("  uptime:  {:.0}s", crate::time::uptime());
+
+        info("drives\n");
+        for index in 0..crate::block::device_count() {
+            if let Some(description) = crate::block::with_device(index, |drive| drive.description())
+            {
+                println!("  {}: {}", index, description);
             }
-        });
+        }
+
+        info("filesystems\n");
+        let mounts = crate::fs::FILESYSTEM.lock();
+        if mounts.filesystems.is_empty() {
+            println!("  no filesystems mounted");
+        }
+        for (drive_index, filesystem) in &mounts.filesystems {
+            let current = if mounts.current == Some(*drive_index) {
+                " (current)"
+            } else {
+                ""
+            };
+            match filesystem.free_sectors() {
+                Ok(free_sectors) => {
+                    println!("  {}:{} {} KB free", drive_index, current, free_sectors / 2)
+                }
+                Err(error_code) => println!("  {}:{} {}", drive_index, current, error_code),
+            }
+        }
+
         ExitCode::Success
     }
     fn usage(&self) -> &str {
-        "help:            clears the screen
-         usage:           clear
-         example command: clear
-         example output:  N/A"
+        "help:            prints a summary of the kernel, drives and filesystems
+         usage:           sysinfo
+         example command: sysinfo
+         example output:  kernel:  pogostick 0.1.0"
     }
 }
 
-/// Command to get the current time
-struct TimeCommand;
+/// Command to print how much of the heap is in use
+struct MemCommand;
 
-impl Command for TimeCommand {
+impl Command for MemCommand {
     fn new(_args: &[&str]) -> Box<Self> {
-        Box::new(TimeCommand)
+        Box::new(MemCommand)
     }
     fn execute(&self) -> ExitCode {
-        println!("{}", DateTime::get().to_string());
+        let stats = crate::allocator::stats();
+        println!(
+            "heap: {} KB used, {} KB free of {} KB",
+            stats.used / 1024,
+            stats.free / 1024,
+            stats.total / 1024
+        );
+        println!(
+            "      {} allocations, {} deallocations ({} live)",
+            stats.allocations,
+            stats.deallocations,
+            stats.allocations.saturating_sub(stats.deallocations)
+        );
         ExitCode::Success
     }
     fn usage(&self) -> &str {
-        "help:            gets the current time
-         usage:           time
-         example command: time
-         example output:  13:50, Sunday 7 March 2021"
+        "help:            prints how much of the heap is in use
+         usage:           mem
+         example command: mem
+         example output:  heap: 12 KB used, 1012 KB free of 1024 KB"
     }
 }
 
-/// Command to add two numbers
-struct AddCommand {
-    number1: f64,
-    number2: f64,
-    parse_error: bool,
-}
+/// Command to print a summary of the memory map given by the bootloader
+struct BootInfoCommand;
 
-impl Command for AddCommand {
+impl Command for BootInfoCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(BootInfoCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        let summary = match crate::mem::memory_summary() {
+            Some(summary) => summary,
+            None => return ExitCode::NotFoundError,
+        };
+
+        println!(
+            "usable memory:          {} KB in {} regions",
+            summary.usable_bytes / 1024,
+            summary.usable_regions
+        );
+        println!("reserved regions:       {}", summary.reserved_regions);
+        println!("highest address:        {:#x}", summary.highest_addr);
+        println!(
+            "physical memory offset: {:#x}",
+            summary.physical_memory_offset
+        );
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints a summary of the memory map given by the bootloader
+         usage:           bootinfo
+         example command: bootinfo
+         example output:  usable memory:          130428 KB in 3 regions"
+    }
+}
+
+/// Command to print information about the processor
+struct CpuInfoCommand;
+
+impl Command for CpuInfoCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(CpuInfoCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        let features = crate::cpu::features();
+        let feature_list = [
+            ("tsc", features.tsc),
+            ("invariant-tsc", features.invariant_tsc),
+            ("apic", features.apic),
+            ("sse", features.sse),
+            ("sse2", features.sse2),
+            ("sse3", features.sse3),
+            ("sse4.1", features.sse4_1),
+            ("sse4.2", features.sse4_2),
+            ("avx", features.avx),
+        ];
+        let supported: Vec<&str> = feature_list
+            .iter()
+            .filter(|(_, supported)| *supported)
+            .map(|(name, _)| *name)
+            .collect();
+
+        println!("vendor:   {}", crate::cpu::vendor());
+        println!(
+            "brand:    {}",
+            crate::cpu::brand().unwrap_or_else(|| "unknown".into())
+        );
+        println!("features: {}", supported.join(" "));
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints the processor's vendor, brand and supported features
+         usage:           cpuinfo
+         example command: cpuinfo
+         example output:  vendor:   GenuineIntel"
+    }
+}
+
+/// Change directory command
+struct CDCommand {
+    pub new_dir: String,
+}
+
+impl Command for CDCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(CDCommand {
+            new_dir: args[0].to_string(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let (drive, prospective_path) = resolve_path(&self.new_dir);
+        let mut mounts = crate::fs::FILESYSTEM.lock();
+
+        if let Some(fs) = mounts.get(drive) {
+            if fs.list_files(&prospective_path).is_some() {
+                mounts.current = Some(fs.drive_index);
+                *PATH.lock() = prospective_path;
+                ExitCode::Success
+            } else {
+                ExitCode::NotFoundError
+            }
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+
+    fn usage(&self) -> &str {
+        "help:            changes directory to the given directory
+         usage:           cd <new dir path, .. for up one or <drive>: to switch drive>
+         example command: cd /
+         example output:  N/A"
+    }
+}
+
+/// Command to clear the screen
+struct ClearCommand;
+
+impl Command for ClearCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(ClearCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        interrupts::without_interrupts(|| {
+            for _ in 0..BUFFER_HEIGHT {
+                WRITER.lock().new_line();
+            }
+        });
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            clears the screen
+         usage:           clear
+         example command: clear
+         example output:  N/A"
+    }
+}
+
+/// Command to leave the shell
+struct ExitCommand;
+
+impl Command for ExitCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(ExitCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        EXIT_REQUESTED.store(true, Ordering::Relaxed);
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            exits the shell, saving the history, then waits for a key to start a new one
+         usage:           exit
+         example command: exit
+         example output:  [ INFO ] the shell has exited, press any key to start a new one"
+    }
+}
+
+/// Command to change the shape of the cursor
+struct CursorCommand {
+    shape: Option<CursorShape>,
+    parse_error: bool,
+}
+
+impl Command for CursorCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let (shape, parse_error) = match args.get(0).map(|arg| arg.trim()) {
+            Some("block") => (Some(CursorShape::Block), false),
+            Some("underline") => (Some(CursorShape::Underline), false),
+            Some("hidden") => (Some(CursorShape::Hidden), false),
+            Some("") | None => (None, false),
+            Some(_) => (None, true),
+        };
+
+        Box::new(CursorCommand { shape, parse_error })
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        match self.shape {
+            Some(shape) => {
+                // the cursor is hidden while commands run, so this is shown at the next prompt
+                set_cursor_shape(shape);
+                set_cursor_visible(false);
+            }
+            None => {
+                let shape = interrupts::without_interrupts(|| WRITER.lock().cursor_shape());
+                match shape {
+                    CursorShape::Block => println!("block"),
+                    CursorShape::Underline => println!("underline"),
+                    CursorShape::Hidden => println!("hidden"),
+                }
+            }
+        }
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            sets the shape of the cursor, or shows it if no shape is given
+         usage:           cursor [block|underline|hidden]
+         example command: cursor block
+         example output:  N/A"
+    }
+}
+
+/// Command to change how held keys repeat
+struct KeyRateCommand {
+    setting: Option<(u16, f64)>,
+    parse_error: bool,
+}
+
+impl Command for KeyRateCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let (setting, parse_error) = match args {
+            [] | [""] => (None, false),
+            [delay, rate] => match (delay.parse::<u16>(), rate.parse::<f64>()) {
+                (Ok(delay @ 250..=1000), Ok(rate)) if (2.0..=30.0).contains(&rate) => {
+                    (Some((delay, rate)), false)
+                }
+                _ => (None, true),
+            },
+            _ => (None, true),
+        };
+
+        Box::new(KeyRateCommand {
+            setting,
+            parse_error,
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        if let Some((delay, rate)) = self.setting {
+            if !crate::input::set_typematic(delay, rate) {
+                return ExitCode::TimeoutError;
+            }
+        }
+
+        // The keyboard only supports some settings, so show what was actually chosen
+        let (delay, rate) = crate::input::typematic();
+        println!("{}ms delay, {:.1} repeats per second", delay, rate);
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            sets how long a key is held before it repeats (250 to 1000ms) and how often it repeats
+                          (2 to 30 per second), or shows the current setting if none is given
+         usage:           keyrate [<delay> <rate>]
+         example command: keyrate 250 30
+         example output:  250ms delay, 30.0 repeats per second"
+    }
+}
+
+/// Command to turn the screensaver on or off
+struct ScreensaverCommand {
+    enabled: Option<bool>,
+    timeout: Option<usize>,
+    parse_error: bool,
+}
+
+impl Command for ScreensaverCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let (enabled, enabled_error) = match args.get(0).map(|arg| arg.trim()) {
+            Some("on") => (Some(true), false),
+            Some("off") => (Some(false), false),
+            Some("") | None => (None, false),
+            Some(_) => (None, true),
+        };
+        let (timeout, timeout_error) = match args.get(1).map(|arg| arg.parse::<usize>()) {
+            Some(Ok(timeout)) if timeout > 0 => (Some(timeout), false),
+            Some(_) => (None, true),
+            None => (None, false),
+        };
+
+        Box::new(ScreensaverCommand {
+            enabled,
+            timeout,
+            parse_error: enabled_error || timeout_error,
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        if let Some(enabled) = self.enabled {
+            let (_, timeout) = crate::screensaver::settings();
+            crate::screensaver::set(enabled, self.timeout.unwrap_or(timeout));
+        }
+
+        match crate::screensaver::settings() {
+            (true, timeout) => println!("on, starting after {} seconds without input", timeout),
+            (false, _) => println!("off"),
+        }
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            turns the screensaver on or off, or shows whether it is on, optionally setting how many
+                          seconds without input it waits for before starting (60 until changed)
+         usage:           screensaver [on|off] [seconds]
+         example command: screensaver on 120
+         example output:  on, starting after 120 seconds without input"
+    }
+}
+
+/// Command to show the raw scancode and decoded key for each keypress
+struct ScancodeCommand;
+
+impl Command for ScancodeCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(ScancodeCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        info("showing scancodes, press escape to stop\n");
+        STDIN.clear();
+        crate::input::set_scancode_mode(true);
+
+        loop {
+            if let InputEvent::Scancode(scancode, key) = STDIN.get_event() {
+                match key {
+                    Some(key) => println!("0x{:02x} -> {:?}", scancode, key),
+                    None => println!("0x{:02x}", scancode),
+                }
+                if let Some(DecodedKey::Unicode('\x1b') | DecodedKey::RawKey(KeyCode::Escape)) = key
+                {
+                    break;
+                }
+            }
+        }
+
+        crate::input::set_scancode_mode(false);
+        STDIN.clear();
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints the raw scancode and decoded key of each keypress until escape is pressed
+         usage:           scancode
+         example command: scancode
+         example output:  0x1e -> Unicode('a')"
+    }
+}
+
+/// Command to repeatedly run another command until a key is pressed
+struct WatchCommand {
+    interval: Option<f64>,
+    command: Vec<String>,
+}
+
+impl Command for WatchCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(WatchCommand {
+            interval: args
+                .get(0)
+                .and_then(|arg| arg.parse::<f64>().ok())
+                .filter(|interval| *interval > 0.0),
+            command: args.iter().skip(1).map(|arg| (*arg).to_owned()).collect(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let interval = match self.interval {
+            Some(interval) if !self.command.is_empty() => interval,
+            _ => return ExitCode::ParseError,
+        };
+
+        STDIN.clear();
+        loop {
+            ClearCommand::new(&[]).execute();
+            info(&format!(
+                "every {}s: {} (press any key to stop)\n\n",
+                interval,
+                self.command.join(" ")
+            ));
+
+            let command = create_command(self.command.iter().map(|arg| arg.as_str()).collect());
+            let status_code = command.execute();
+            if !matches!(status_code, ExitCode::Success) {
+                err(&status_code.to_string());
+            }
+
+            // Sleep in short steps so a keypress stops the command promptly
+            let end = crate::time::uptime() + interval;
+            while crate::time::uptime() < end {
+                if STDIN.try_get_event().is_some() {
+                    STDIN.clear();
+                    println!();
+                    return ExitCode::Success;
+                }
+                crate::time::sleep_ms(50);
+            }
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            runs a command every given number of seconds until a key is pressed
+         usage:           watch <seconds> <command>
+         example command: watch 1 time
+         example output:  [ INFO ] every 1s: time (press any key to stop)"
+    }
+}
+
+/// Command to run another command several times
+struct RepeatCommand {
+    count: Option<usize>,
+    command: Vec<String>,
+}
+
+impl Command for RepeatCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(RepeatCommand {
+            count: args
+                .get(0)
+                .and_then(|arg| arg.parse::<usize>().ok())
+                .filter(|count| *count <= MAX_REPEAT_COUNT),
+            command: args.iter().skip(1).map(|arg| (*arg).to_owned()).collect(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let count = match self.count {
+            Some(count) if !self.command.is_empty() => count,
+            _ => return ExitCode::ParseError,
+        };
+
+        for _ in 0..count {
+            let command = create_command(self.command.iter().map(|arg| arg.as_str()).collect());
+            let status_code = command.execute();
+            if !matches!(status_code, ExitCode::Success) {
+                return status_code;
+            }
+        }
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            runs a command the given number of times (at most 1000), stopping if it fails
+         usage:           repeat <count> <command>
+         example command: repeat 3 echo hi
+         example output:  hi"
+    }
+}
+
+/// Command to show a test pattern in the graphics mode
+struct GraphicsCommand;
+
+impl Command for GraphicsCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(GraphicsCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        use crate::graphics::{self, HEIGHT, WIDTH};
+
+        graphics::enter_graphics_mode();
+
+        // Every colour in a 16 by 16 grid, with a gradient underneath
+        let (cell_width, cell_height) = (WIDTH / 16, (HEIGHT - 8) / 16);
+        for colour in 0..=255_u8 {
+            let (col, row) = (colour as usize % 16, colour as usize / 16);
+            graphics::fill_rect(
+                col * cell_width,
+                row * cell_height,
+                cell_width,
+                cell_height,
+                colour,
+            );
+        }
+        for x in 0..WIDTH {
+            let red = (x * 255 / (WIDTH - 1)) as u8;
+            let colour = graphics::rgb(red, 0, 255 - red);
+            for y in HEIGHT - 8..HEIGHT {
+                graphics::put_pixel(x, y, colour);
+            }
+        }
+
+        STDIN.clear();
+        STDIN.get_event();
+        graphics::leave_graphics_mode();
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            shows a test pattern in the 320x200 256 colour graphics mode until a key is pressed
+         usage:           gfx
+         example command: gfx
+         example output:  N/A"
+    }
+}
+
+/// Command to play a tone on the pc speaker
+struct BeepCommand {
+    frequency: Option<u32>,
+    duration: Option<u64>,
+}
+
+impl Command for BeepCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(BeepCommand {
+            frequency: match args.get(0) {
+                Some(arg) => arg.parse::<u32>().ok(),
+                None => Some(440),
+            },
+            duration: match args.get(1) {
+                Some(arg) => arg.parse::<u64>().ok(),
+                None => Some(200),
+            },
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        match (self.frequency, self.duration) {
+            (Some(frequency), Some(duration)) => {
+                crate::speaker::beep(frequency, duration);
+                ExitCode::Success
+            }
+            _ => ExitCode::ParseError,
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            plays a tone on the pc speaker, at 440hz for 200ms unless given
+         usage:           beep [frequency in hz] [duration in ms]
+         example command: beep 880 500
+         example output:  N/A"
+    }
+}
+
+/// Command to play a file of notes on the pc speaker
+struct PlayCommand {
+    name: Option<String>,
+}
+
+impl Command for PlayCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(PlayCommand {
+            name: args.get(0).map(|arg| (*arg).to_owned()),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let name = match &self.name {
+            Some(name) => name,
+            None => return ExitCode::ParseError,
+        };
+
+        let (drive, path) = resolve_path(name);
+        let fs = crate::fs::FILESYSTEM.lock();
+        let file = match fs.get(drive) {
+            Some(filesystem) => filesystem.get_file(&path),
+            None => return ExitCode::NotMountedError,
+        };
+        drop(fs);
+
+        let bytes = match file.map(|file| file.read()) {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(error_code)) => return error_code,
+            None => return ExitCode::NotFoundError,
+        };
+
+        STDIN.clear();
+        info("playing, press any key to stop\n");
+        for (index, line) in String::from_utf8_lossy(&bytes).lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let note = parts.next().and_then(crate::speaker::parse_note);
+            let duration = parts.next().and_then(|arg| arg.parse::<u64>().ok());
+            match (note, duration, parts.next()) {
+                (Some(0), Some(duration), None) => crate::time::sleep_ms(duration),
+                (Some(frequency), Some(duration), None) => {
+                    crate::speaker::beep(frequency, duration)
+                }
+                _ => {
+                    warn(&format!("skipping line {}: `{}`\n", index + 1, line));
+                    continue;
+                }
+            }
+
+            if STDIN.try_get_event().is_some() {
+                STDIN.clear();
+                break;
+            }
+            // Leave a short gap so repeated notes can be told apart
+            crate::time::sleep_ms(30);
+        }
+
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            plays a file of `note ms` lines such as `A4 250`, with `R` for rests
+         usage:           play <path>
+         example command: play tune
+         example output:  [ INFO ] playing, press any key to stop"
+    }
+}
+
+/// Command to print the history of commands
+struct HistoryCommand;
+
+impl Command for HistoryCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(HistoryCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        for (index, command) in HISTORY.lock().iter().enumerate() {
+            println!("{:>4}  {}", index + 1, command);
+        }
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints previous commands, which can be run again with !<number>
+         usage:           history
+         example command: history
+         example output:     1  ls"
+    }
+}
+
+/// Command to get the current time
+struct TimeCommand;
+
+impl Command for TimeCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(TimeCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        println!("{}", DateTime::get().to_string());
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            gets the current time
+         usage:           time
+         example command: time
+         example output:  13:50, Sunday 7 March 2021"
+    }
+}
+
+/// Parses the two numbers given to an arithmetic command, or None if there aren't exactly two or either isn't one.
+fn parse_two_numbers(args: &[&str]) -> Option<(f64, f64)> {
+    match args {
+        [first, second] => first.parse().ok().zip(second.parse().ok()),
+        _ => None,
+    }
+}
+
+/// Operation done by an `ArithmeticCommand`.
+#[derive(Clone, Copy)]
+enum Operation {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+/// Command to add, subtract, multiply, divide or find the remainder of two numbers
+struct ArithmeticCommand {
+    numbers: Option<(f64, f64)>,
+    operation: Operation,
+}
+
+impl ArithmeticCommand {
+    fn new_with(args: &[&str], operation: Operation) -> Box<Self> {
+        Box::new(ArithmeticCommand {
+            numbers: parse_two_numbers(args),
+            operation,
+        })
+    }
+}
+
+impl Command for ArithmeticCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        ArithmeticCommand::new_with(args, Operation::Add)
+    }
+    fn execute(&self) -> ExitCode {
+        let (first, second) = match self.numbers {
+            Some(numbers) => numbers,
+            None => return ExitCode::ParseError,
+        };
+
+        let result = match self.operation {
+            Operation::Add => first + second,
+            Operation::Subtract => first - second,
+            Operation::Multiply => first * second,
+            Operation::Divide | Operation::Modulo if second == 0.0 => {
+                return err("cannot divide by zero")
+            }
+            Operation::Divide => first / second,
+            Operation::Modulo => first % second,
+        };
+        println!("{}", result);
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        match self.operation {
+            Operation::Add => {
+                "help:            adds two numbers
+         usage:           add <first number> <second number>
+         example command: add 7 8
+         example output:  15"
+            }
+            Operation::Subtract => {
+                "help:            subtracts the second number from the first
+         usage:           sub <first number> <second number>
+         example command: sub 7 8
+         example output:  -1"
+            }
+            Operation::Multiply => {
+                "help:            multiplies two numbers
+         usage:           mul <first number> <second number>
+         example command: mul 7 8
+         example output:  56"
+            }
+            Operation::Divide => {
+                "help:            divides the first number by the second
+         usage:           div <first number> <second number>
+         example command: div 7 2
+         example output:  3.5"
+            }
+            Operation::Modulo => {
+                "help:            finds the remainder of dividing the first number by the second, with the sign of
+                          the first
+         usage:           mod <first number> <second number>
+         example command: mod 7.5 2
+         example output:  1.5"
+            }
+        }
+    }
+}
+
+/// Command to evaluate an arithmetic expression
+struct CalcCommand {
+    expression: String,
+}
+
+impl Command for CalcCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(CalcCommand {
+            expression: args.join(" "),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        match crate::calc::evaluate(&self.expression) {
+            Ok(result) => {
+                println!("{}", result);
+                ExitCode::Success
+            }
+            Err(CalcError::DivideByZero) => err("cannot divide by zero"),
+            Err(CalcError::Syntax) => ExitCode::ParseError,
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            evaluates an expression with + - * / and brackets, where * and / are done first
+         usage:           calc <expression>
+         example command: calc 2 * (3 + 4.5) / -5
+         example output:  -3"
+    }
+}
+
+/// Command to list connected disks
+struct DiskInfoCommand;
+
+impl Command for DiskInfoCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(DiskInfoCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        // Drives are addressed by their block device index, which counts ATA drives then any others
+        for index in 0..crate::block::device_count() {
+            if let Some(description) = crate::block::with_device(index, |drive| drive.description())
+            {
+                info(&format!("{}: {}\n", index, description));
+            }
+        }
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints info about connected disks
+         usage:           disk
+         example command: disk
+         example output:  0: ATA bus 1 master MODEL 12345678 (32 MB)"
+    }
+}
+
+/// Command to recover an ATA bus which has stopped responding
+struct AtaCommand {
+    bus: Option<u8>,
+}
+
+impl Command for AtaCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let bus = match args {
+            ["reset", bus] => bus.parse::<u8>().ok(),
+            _ => None,
+        };
+
+        Box::new(AtaCommand { bus })
+    }
+    fn execute(&self) -> ExitCode {
+        let bus = match self.bus {
+            Some(bus) => bus,
+            None => return ExitCode::ParseError,
+        };
+
+        match crate::ata::reset_bus(bus) {
+            Some(found) => {
+                okay(&format!("reset bus {}, found {} drive(s)\n", bus, found));
+
+                // Drives after the bus may have been renumbered, or different drives found, so remount them all
+                crate::fs::remount();
+                *PATH.lock() = Vec::new();
+                let mounted = crate::fs::FILESYSTEM.lock().filesystems.len();
+                okay(&format!("remounted {} filesystem(s)\n", mounted))
+            }
+            None => ExitCode::NotFoundError,
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            resets an ATA bus and identifies its drives again, for when disk commands hang
+         usage:           ata reset <bus>
+         example command: ata reset 1
+         example output:  [ OKAY ] reset bus 1, found 1 drive(s)
+                          [ OKAY ] remounted 1 filesystem(s)"
+    }
+}
+
+/// Command to list files
+struct ListFilesCommand {
+    name: String,
+    mode: SortMode,
+    dirs_first: bool,
+    show_hidden: bool,
+    long: bool,
+    single_column: bool,
+    parse_error: bool,
+}
+
+impl Command for ListFilesCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let mut command = ListFilesCommand {
+            name: String::new(),
+            mode: SortMode::Name,
+            dirs_first: false,
+            show_hidden: false,
+            long: false,
+            single_column: false,
+            parse_error: false,
+        };
+
+        for arg in args {
+            match *arg {
+                "-l" => command.long = true,
+                "-1" => command.single_column = true,
+                "-r" => command.mode = SortMode::NameDesc,
+                "-s" => command.mode = SortMode::Size,
+                "-g" => command.dirs_first = true,
+                "-a" => command.show_hidden = true,
+                flag if flag.starts_with('-') => command.parse_error = true,
+                name => command.name = name.to_owned(),
+            }
+        }
+
+        Box::new(command)
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let (drive, path) = resolve_path(&self.name);
+        let fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get(drive) {
+            let files = if let Some(files) =
+                filesystem.list_files_sorted(&path, self.mode, self.dirs_first, self.show_hidden)
+            {
+                files
+            } else {
+                return ExitCode::NotFoundError;
+            };
+            if files.len() == 0 {
+                println!("no files in this directory");
+                return ExitCode::Success;
+            }
+            let file_colour = ColourCode::new(Colour::White, Colour::Black);
+            let dir_colour = ColourCode::new(Colour::LightBlue, Colour::Black);
+
+            // Work out each line before printing, so the disk isn't read with the writer locked
+            let entries: Vec<(String, String, ColourCode)> = files
+                .iter()
+                .map(|object| {
+                    let name = object.to_string();
+                    let colour = if object.is_dir() {
+                        dir_colour
+                    } else {
+                        file_colour
+                    };
+                    let prefix = if self.long {
+                        let (kind, size, modified) = match object {
+                            FileType::File(f) => ("-", format!("{} bytes", f.size()), f.modified()),
+                            FileType::Dir(d) => {
+                                let mut dir_path = path.clone();
+                                dir_path.push(d.name.clone());
+                                let entries = filesystem
+                                    .list_entries(&dir_path)
+                                    .map_or(0, |entries| entries.len());
+                                ("d", format!("{} entries", entries), None)
+                            }
+                        };
+                        let modified =
+                            modified.map_or_else(|| "-".to_string(), |time| time.to_short_string());
+                        format!(" {} {:>13}  {:<17}  ", kind, size, modified)
+                    } else {
+                        " - ".to_string()
+                    };
+                    (prefix, name, colour)
+                })
+                .collect();
+            drop(fs);
+
+            // Short listings are laid out in as many columns as fit, going down each column in turn,
+            // unless a name is too long for there to be more than one
+            let column_width = entries
+                .iter()
+                .map(|(_, name, _)| name.chars().count() + 2)
+                .max()
+                .unwrap_or(0);
+            let columns = (BUFFER_WIDTH - 1) / column_width;
+            let lines: Vec<Vec<(String, ColourCode)>> =
+                if self.long || self.single_column || columns <= 1 {
+                    entries
+                        .into_iter()
+                        .map(|(prefix, name, colour)| vec![(prefix, file_colour), (name, colour)])
+                        .collect()
+                } else {
+                    let rows = (entries.len() + columns - 1) / columns;
+                    (0..rows)
+                        .map(|row| {
+                            entries
+                                .iter()
+                                .skip(row)
+                                .step_by(rows)
+                                .map(|(_, name, colour)| {
+                                    (format!("{:<1$}", name, column_width), *colour)
+                                })
+                                .collect()
+                        })
+                        .collect()
+                };
+
+            let mut pager = Pager::new();
+            for line in lines {
+                if !pager.next_line() {
+                    break;
+                }
+                interrupts::without_interrupts(|| {
+                    let mut writer = WRITER.lock();
+                    for (text, colour) in line {
+                        writer.write_string_colour(&text, colour);
+                    }
+                    writer.new_line();
+                });
+            }
+            ExitCode::Success
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            lists the files in the given or current directory, a screenful at a time
+         usage:           ls|dir [-l long] [-1 one per line] [-r reverse] [-s sort by size] [-g directories first] [-a all] [path]
+         example command: ls -l
+         example output:  - 11 bytes  15 Oct 2026 12:30  notes"
+    }
+}
+
+/// Command to copy a file, which can be to another drive
+struct CopyCommand {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl Command for CopyCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(CopyCommand {
+            from: args.get(0).map(|arg| (*arg).to_owned()),
+            to: args.get(1).map(|arg| (*arg).to_owned()),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let (from, to) = match (&self.from, &self.to) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return ExitCode::ParseError,
+        };
+        let (from_drive, from) = resolve_path(from);
+        let (to_drive, to) = resolve_path(to);
+
+        match crate::fs::FILESYSTEM
+            .lock()
+            .copy_file(from_drive, &from, to_drive, &to)
+        {
+            ExitCode::Success => okay("successfully copied file\n"),
+            error_code => error_code,
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            copies a file, replacing the destination if it exists, or into it if it's a directory
+         usage:           cp <path> <new path>
+         example command: cp 0:/document 1:/backup
+         example output:  [ OKAY ] successfully copied file"
+    }
+}
+
+/// Command to rename a file or directory
+struct RenameCommand {
+    old_name: String,
+    new_name: String,
+}
+
+impl Command for RenameCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(RenameCommand {
+            old_name: args[0].to_owned(),
+            new_name: args[1].to_owned(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let (drive, path) = resolve_path(&self.old_name);
+        let mut fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get_mut(drive) {
+            filesystem.rename(&path, &self.new_name)
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            renames a file at the given path
+         usage:           rename <path> <new name>
+         example command: rename documrnt document
+         example output:  N/A"
+    }
+}
+
+/// Command to remove a file or directory from the disk
+struct RemoveCommand {
+    name: String,
+    recursive: bool,
+    soft: bool,
+    parse_error: bool,
+}
+
+impl Command for RemoveCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let mut command = RemoveCommand {
+            name: String::new(),
+            recursive: false,
+            soft: false,
+            parse_error: false,
+        };
+
+        for arg in args {
+            match *arg {
+                "-r" => command.recursive = true,
+                "-s" => command.soft = true,
+                flag if flag.starts_with('-') => command.parse_error = true,
+                name => command.name = name.to_owned(),
+            }
+        }
+
+        Box::new(command)
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let (drive, path) = resolve_path(&self.name);
+        if path.is_empty() {
+            return ExitCode::NotFoundError;
+        }
+        let is_dir = crate::fs::FILESYSTEM
+            .lock()
+            .get(drive)
+            .map_or(false, |filesystem| filesystem.get_dir(&path).is_some());
+
+        // Only files keep their data when soft deleted, so a directory would be gone for good
+        if self.soft && is_dir {
+            return err("only files can be soft deleted, remove directories without -s");
+        }
+
+        // Removing a directory with everything in it can't be undone, so check first
+        if self.recursive
+            && is_dir
+            && !confirm(&format!(
+                "{} and everything in it will be removed, continue?",
+                self.name
+            ))
+        {
+            return ExitCode::Success;
+        }
+
+        let mut fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get_mut(drive) {
+            if self.soft {
+                filesystem.soft_delete_file(&path)
+            } else {
+                filesystem.delete_object(&path, self.recursive)
+            }
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            removes a file or directory from the disk, use -r to remove a directory with everything in it
+                          and -s to keep a file's data so it can be restored with undelete until the space is needed
+                          (directories can't be soft deleted)
+         usage:           rm [-r] [-s] <path>
+         example command: rm -r documents
+         example output:  N/A"
+    }
+}
+
+/// Command to restore a file removed with `rm -s`
+struct UndeleteCommand {
+    name: Option<String>,
+}
+
+impl Command for UndeleteCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(UndeleteCommand {
+            name: args.get(0).map(|arg| (*arg).to_owned()),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let (drive, path) = resolve_path(self.name.as_deref().unwrap_or(""));
+        let mut fs = crate::fs::FILESYSTEM.lock();
+        let filesystem = match fs.get_mut(drive) {
+            Some(filesystem) => filesystem,
+            None => return ExitCode::NotMountedError,
+        };
+
+        // Without a name, list what can be restored in the current directory
+        if self.name.is_none() {
+            return match filesystem.list_deleted(&path) {
+                Some(files) => {
+                    for file in files {
+                        println!("{}", file.name);
+                    }
+                    ExitCode::Success
+                }
+                None => ExitCode::NotFoundError,
+            };
+        }
+
+        filesystem.undelete_file(&path)
+    }
+    fn usage(&self) -> &str {
+        "help:            restores a file removed with rm -s, or lists the files which can be restored
+         usage:           undelete [path]
+         example command: undelete notes.txt
+         example output:  N/A"
+    }
+}
+
+/// Command to write text to a file
+struct WriteCommand {
+    name: String,
+    text: String,
+    encrypt: bool,
+    compress: bool,
+    parse_error: bool,
+}
+
+impl Command for WriteCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let flag_count = args
+            .iter()
+            .take_while(|arg| **arg == "-e" || **arg == "-z")
+            .count();
+        let (flags, args) = args.split_at(flag_count);
+
+        Box::new(WriteCommand {
+            name: args.first().copied().unwrap_or_default().to_owned(),
+            text: args.get(1..).unwrap_or_default().join(" "),
+            encrypt: flags.contains(&"-e"),
+            compress: flags.contains(&"-z"),
+            // The flags may be all there is, leaving no path to write to
+            parse_error: args.is_empty(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let passphrase = if self.encrypt {
+            info("passphrase: ");
+            Some(STDIN.get_str_hidden(true))
+        } else {
+            None
+        };
+
+        let (drive, path) = resolve_path(&self.name);
+        let mut fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get_mut(drive) {
+            let bytes = self.text.as_bytes().to_vec();
+            let length = bytes.len();
+            match filesystem.write_file_with(&path, bytes, self.compress, passphrase.as_deref()) {
+                ExitCode::Success => {
+                    if self.compress {
+                        let stored = filesystem
+                            .get_file(&path)
+                            .map_or(0, |file| file.stored_size());
+                        info(&format!(
+                            "stored {} bytes as {} ({:.0}% of the original size)\n",
+                            length,
+                            stored,
+                            stored as f64 * 100.0 / length.max(1) as f64
+                        ));
+                    }
+                    okay("successfully written file\n")
+                }
+                error_code => error_code,
+            }
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            writes text to a file, compressed if -z is given and scrambled with a passphrase if -e is
+         usage:           wt [-z compress] [-e encrypt] <path> <text>
+         example command: wt document hello world
+         example output:  N/A"
+    }
+}
+
+/// Command to write bytes given in hex to a file
+struct WriteHexCommand {
+    name: String,
+    hex_bytes: String,
+}
+
+impl Command for WriteHexCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(WriteHexCommand {
+            name: args.get(0).map_or(String::new(), |arg| (*arg).to_owned()),
+            // The bytes may be split up with spaces to make them easier to read
+            hex_bytes: args.get(1..).map_or(String::new(), |args| args.concat()),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let mut bytes = vec![0_u8; self.hex_bytes.len() / 2];
+        if self.name.is_empty()
+            || self.hex_bytes.len() % 2 != 0
+            || hex::decode_to_slice(&self.hex_bytes, &mut bytes).is_err()
+        {
+            return ExitCode::ParseError;
+        }
+
+        let (drive, path) = resolve_path(&self.name);
+        let mut fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get_mut(drive) {
+            match filesystem.write_file(&path, bytes) {
+                ExitCode::Success => okay("successfully written file\n"),
+                error_code => error_code,
+            }
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            writes bytes given in hex to a file, which rt prints as hex again if they aren't UTF-8
+         usage:           wx <path> <hex>
+         example command: wx data 00ff 1234
+         example output:  N/A"
+    }
+}
+
+/// Command to read text from a file
+struct ReadCommand {
+    name: String,
+}
+
+impl Command for ReadCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(ReadCommand {
+            name: args[0].to_owned(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let (drive, path) = resolve_path(&self.name);
+        let fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get(drive) {
+            let file = filesystem.get_file(&path);
+            drop(fs);
+
+            if let Some(f) = file {
+                let file_bytes = if f.is_encrypted() {
+                    info("passphrase: ");
+                    f.read_with_passphrase(&STDIN.get_str_hidden(true))
+                } else {
+                    match f.read() {
+                        Ok(file_bytes) => file_bytes,
+                        Err(error_code) => return error_code,
+                    }
+                };
+                print_text_or_hex(&file_bytes);
+                ExitCode::Success
+            } else {
+                ExitCode::NotFoundError
+            }
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            prints text from a UTF-8 file, asking for the passphrase if it is encrypted
+         usage:           rt <path>
+         example command: rt document
+         example output:  hello world"
+    }
+}
+
+/// Command to print several files one after another
+struct ConcatenateCommand {
+    names: Vec<String>,
+}
+
+impl Command for ConcatenateCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(ConcatenateCommand {
+            names: args
+                .iter()
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_string())
+                .collect(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        if self.names.is_empty() {
+            return ExitCode::ParseError;
+        }
+
+        let mut bytes = Vec::new();
+        let mut found = 0;
+        for name in &self.names {
+            let (drive, paths) = resolve_glob(name);
+            if paths.is_empty() {
+                warn(&format!("no files match `{}`\n", name));
+            }
+
+            for path in paths {
+                let mounts = crate::fs::FILESYSTEM.lock();
+                let file = match mounts.get(drive) {
+                    Some(filesystem) => filesystem.get_file(&path),
+                    None => return ExitCode::NotMountedError,
+                };
+                drop(mounts);
+
+                // A missing or encrypted file shouldn't stop the rest from being printed
+                match file.map(|f| f.read()) {
+                    Some(Ok(file_bytes)) => {
+                        bytes.extend(file_bytes);
+                        found += 1;
+                    }
+                    Some(Err(error_code)) => warn(&format!(
+                        "`{}` can't be read, skipping it: {}\n",
+                        path.join("/"),
+                        error_code
+                    )),
+                    None => warn(&format!(
+                        "`{}` was not found, skipping it\n",
+                        path.join("/")
+                    )),
+                }
+            }
+        }
+
+        if found == 0 {
+            return ExitCode::NotFoundError;
+        }
+        print_text_or_hex(&bytes);
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints files one after another, where a pattern like *.log in the last part of a path
+                          matches every file it fits
+         usage:           cat <path> [path...]
+         example command: cat *.log
+         example output:  the first log
+                          the second log"
+    }
+}
+
+/// Command to count the lines, words and bytes in a file
+struct WordCountCommand {
+    name: String,
+}
+
+impl Command for WordCountCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(WordCountCommand {
+            name: args[0].to_owned(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let (drive, path) = resolve_path(&self.name);
+        let fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get(drive) {
+            let file = filesystem.get_file(&path);
+            drop(fs);
+
+            if let Some(f) = file {
+                let file_bytes = match f.read() {
+                    Ok(file_bytes) => file_bytes,
+                    Err(error_code) => return error_code,
+                };
+                // Lines and words only make sense for text, so detect the encoding like `rt` does
+                if let Ok(file_text) = core::str::from_utf8(&file_bytes) {
+                    println!(
+                        "{} lines, {} words, {} bytes",
+                        file_text.matches('\n').count(),
+                        file_text.split_whitespace().count(),
+                        file_bytes.len()
+                    );
+                } else {
+                    warn("cannot detect encoding, only counting bytes\n");
+                    println!("{} bytes", file_bytes.len());
+                }
+                ExitCode::Success
+            } else {
+                ExitCode::NotFoundError
+            }
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            counts the lines, words and bytes in a file, or only the bytes if it isn't UTF-8
+         usage:           wc <path>
+         example command: wc document
+         example output:  1 lines, 2 words, 12 bytes"
+    }
+}
+
+/// Command to print the lines of a file containing some text
+struct GrepCommand {
+    pattern: String,
+    name: String,
+    line_numbers: bool,
+    ignore_case: bool,
+    parse_error: bool,
+}
+
+impl Command for GrepCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let mut command = GrepCommand {
+            pattern: String::new(),
+            name: String::new(),
+            line_numbers: false,
+            ignore_case: false,
+            parse_error: false,
+        };
+
+        let mut positional = Vec::new();
+        for arg in args {
+            match *arg {
+                "-n" => command.line_numbers = true,
+                "-i" => command.ignore_case = true,
+                flag if flag.starts_with('-') => command.parse_error = true,
+                arg => positional.push(arg),
+            }
+        }
+
+        match positional[..] {
+            [pattern, name] => {
+                command.pattern = pattern.to_owned();
+                command.name = name.to_owned();
+            }
+            _ => command.parse_error = true,
+        }
+
+        Box::new(command)
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let (drive, path) = resolve_path(&self.name);
+        let fs = crate::fs::FILESYSTEM.lock();
+        let file = match fs.get(drive) {
+            Some(filesystem) => filesystem.get_file(&path),
+            None => return ExitCode::NotMountedError,
+        };
+        drop(fs);
+
+        let file_bytes = match file.map(|f| f.read()) {
+            Some(Ok(file_bytes)) => file_bytes,
+            Some(Err(error_code)) => return error_code,
+            None => return ExitCode::NotFoundError,
+        };
+        if core::str::from_utf8(&file_bytes).is_err() {
+            warn("cannot detect encoding, searching it as text anyway\n");
+        }
+
+        let pattern = if self.ignore_case {
+            self.pattern.to_lowercase()
+        } else {
+            self.pattern.clone()
+        };
+
+        let mut matches = 0;
+        let mut pager = Pager::new();
+        for (index, line) in String::from_utf8_lossy(&file_bytes).lines().enumerate() {
+            let found = if self.ignore_case {
+                crate::pattern::matches_anywhere(&pattern, &line.to_lowercase())
+            } else {
+                crate::pattern::matches_anywhere(&pattern, line)
+            };
+            if !found {
+                continue;
+            }
+
+            matches += 1;
+            if !pager.next_line() {
+                break;
+            }
+            if self.line_numbers {
+                println!("{:>4} {}", index + 1, line);
+            } else {
+                println!("{}", line);
+            }
+        }
+
+        if matches == 0 {
+            info("no matches\n");
+        }
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints the lines of a file matching a pattern, where * and ? match any characters, [abc]
+                          one of those characters, and ^ and $ the start and end of the line, use -n to number
+                          them and -i to ignore case
+         usage:           grep [-n] [-i] <pattern> <path>
+         example command: grep -n hello document
+         example output:     1 hello world"
+    }
+}
+
+/// Command to sort the lines of a file
+struct SortCommand {
+    name: String,
+    output: Option<String>,
+    reverse: bool,
+    parse_error: bool,
+}
+
+impl Command for SortCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let mut command = SortCommand {
+            name: String::new(),
+            output: None,
+            reverse: false,
+            parse_error: false,
+        };
+
+        let mut positional = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "-r" => command.reverse = true,
+                "-o" => match args.next() {
+                    Some(output) => command.output = Some(output.to_string()),
+                    None => command.parse_error = true,
+                },
+                flag if flag.starts_with('-') => command.parse_error = true,
+                arg => positional.push(arg),
+            }
+        }
+
+        match positional[..] {
+            [name] => command.name = name.to_owned(),
+            _ => command.parse_error = true,
+        }
+
+        Box::new(command)
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let (drive, path) = resolve_path(&self.name);
+        let fs = crate::fs::FILESYSTEM.lock();
+        let file = match fs.get(drive) {
+            Some(filesystem) => filesystem.get_file(&path),
+            None => return ExitCode::NotMountedError,
+        };
+        drop(fs);
+
+        let file_bytes = match file.map(|f| f.read()) {
+            Some(Ok(file_bytes)) => file_bytes,
+            Some(Err(error_code)) => return error_code,
+            None => return ExitCode::NotFoundError,
+        };
+        // Sorting bytes which aren't text would give something meaningless, so unlike `grep` don't try
+        let file_text = match core::str::from_utf8(&file_bytes) {
+            Ok(file_text) => file_text,
+            Err(_) => return err("cannot detect encoding, only UTF-8 text can be sorted"),
+        };
+
+        let mut lines: Vec<&str> = file_text.lines().collect();
+        lines.sort_unstable();
+        if self.reverse {
+            lines.reverse();
+        }
+
+        if let Some(output) = &self.output {
+            // The file was read in full and the writer only removes it once the output is written,
+            // so the output can be the file being sorted without risking it
+            let (drive, path) = resolve_path(output);
+            let mut writer = FileWriter::new(drive, path);
+            for line in lines {
+                writeln!(writer, "{}", line).ok();
+            }
+
+            match writer.flush() {
+                ExitCode::Success => okay("successfully written file\n"),
+                error_code => error_code,
+            }
+        } else {
+            let mut pager = Pager::new();
+            for line in lines {
+                if !pager.next_line() {
+                    break;
+                }
+                println!("{}", line);
+            }
+            ExitCode::Success
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            prints the lines of a UTF-8 file in order, use -r to reverse it and -o to write to a file instead
+         usage:           sort [-r] [-o <output path>] <path>
+         example command: sort -o sorted names
+         example output:  successfully written file"
+    }
+}
+
+/// Command to print the lines of a file with adjacent duplicates collapsed
+struct UniqCommand {
+    name: String,
+    counts: bool,
+    parse_error: bool,
+}
+
+impl UniqCommand {
+    /// Prints a line, prefixed by how many times it was repeated if counts were asked for.
+    /// Returns false if the user stopped the output.
+    fn print_line(&self, pager: &mut Pager, line: &[u8], count: usize) -> bool {
+        if !pager.next_line() {
+            return false;
+        }
+        let line = String::from_utf8_lossy(line);
+        if self.counts {
+            println!("{:>4} {}", count, line);
+        } else {
+            println!("{}", line);
+        }
+        true
+    }
+}
+
+impl Command for UniqCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let mut command = UniqCommand {
+            name: String::new(),
+            counts: false,
+            parse_error: false,
+        };
+
+        let mut positional = Vec::new();
+        for arg in args {
+            match *arg {
+                "-c" => command.counts = true,
+                flag if flag.starts_with('-') => command.parse_error = true,
+                arg => positional.push(arg),
+            }
+        }
+
+        match positional[..] {
+            [name] => command.name = name.to_owned(),
+            _ => command.parse_error = true,
+        }
+
+        Box::new(command)
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let lines = match FileLines::open(&self.name) {
+            Ok(lines) => lines,
+            Err(error_code) => return error_code,
+        };
+
+        let mut pager = Pager::new();
+        let mut previous: Option<(Vec<u8>, usize)> = None;
+        for line in lines {
+            match &mut previous {
+                Some((previous_line, count)) if *previous_line == line => *count += 1,
+                _ => {
+                    if let Some((previous_line, count)) = previous.replace((line, 1)) {
+                        if !self.print_line(&mut pager, &previous_line, count) {
+                            return ExitCode::Success;
+                        }
+                    }
+                }
+            }
+        }
+
+        match previous {
+            Some((previous_line, count)) => {
+                self.print_line(&mut pager, &previous_line, count);
+            }
+            None => info("the file is empty\n"),
+        }
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        "help:            prints the lines of a file with repeated lines next to each other shown once, use -c to count them
+         usage:           uniq [-c] <path>
+         example command: uniq -c sorted
+         example output:     2 hello world"
+    }
+}
+
+/// Command to print the first or last lines of a file
+struct HeadTailCommand {
+    name: String,
+    count: usize,
+    from_end: bool,
+    parse_error: bool,
+}
+
+impl HeadTailCommand {
+    /// Number of lines printed if no count is given.
+    const DEFAULT_COUNT: usize = 10;
+
+    fn new_with(args: &[&str], from_end: bool) -> Box<Self> {
+        let (count, parse_error) = match args.get(1).map(|arg| arg.parse::<usize>()) {
+            Some(Ok(count)) => (count, false),
+            Some(Err(_)) => (0, true),
+            None => (Self::DEFAULT_COUNT, false),
+        };
+
+        Box::new(HeadTailCommand {
+            name: args.get(0).map_or(String::new(), |arg| arg.to_string()),
+            count,
+            from_end,
+            parse_error: parse_error || args.is_empty() || args.len() > 2,
+        })
+    }
+}
+
+impl Command for HeadTailCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        HeadTailCommand::new_with(args, false)
+    }
+    fn execute(&self) -> ExitCode {
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let lines = match FileLines::open(&self.name) {
+            Ok(lines) => lines,
+            Err(error_code) => return error_code,
+        };
+
+        // `head` stops reading once it has enough lines, but `tail` has to keep the last lines it has seen
+        let mut selected: VecDeque<Vec<u8>> = VecDeque::new();
+        if self.from_end {
+            for line in lines {
+                if selected.len() == self.count {
+                    selected.pop_front();
+                }
+                if self.count > 0 {
+                    selected.push_back(line);
+                }
+            }
+        } else {
+            selected.extend(lines.take(self.count));
+        }
+
+        let text: Vec<u8> = selected.into_iter().collect::<Vec<_>>().join(&b'\n');
+        print_text_or_hex(&text);
+        ExitCode::Success
+    }
+    fn usage(&self) -> &str {
+        if self.from_end {
+            "help:            prints the last lines of a UTF-8 file, 10 unless a number is given
+         usage:           tail <path> [lines]
+         example command: tail log 1
+         example output:  the last line"
+        } else {
+            "help:            prints the first lines of a UTF-8 file, 10 unless a number is given
+         usage:           head <path> [lines]
+         example command: head log 1
+         example output:  the first line"
+        }
+    }
+}
+
+/// Create directory command
+struct CreateDirCommand {
+    name: String,
+}
+
+impl Command for CreateDirCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(CreateDirCommand {
+            name: args[0].to_owned(),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let (drive, path) = resolve_path(&self.name);
+        let mut fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get_mut(drive) {
+            filesystem.create_dir(&path)
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            creates a directory at the given path
+         usage:           mkdir <path>
+         example command: mkdir documents
+         example output:  N/A"
+    }
+}
+
+/// Command to show details about a file or directory
+struct StatCommand {
+    name: Option<String>,
+}
+
+impl Command for StatCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(StatCommand {
+            name: args.first().map(|arg| (*arg).to_owned()),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let name = match &self.name {
+            Some(name) => name,
+            None => return ExitCode::ParseError,
+        };
+        let (drive, path) = resolve_path(name);
+        let fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get(drive) {
+            if let Some(file) = filesystem.get_file(&path) {
+                println!("type:    file");
+                println!("drive:   {}", file.drive_index);
+                println!("entry:   sector {}", file.entry_addr);
+                println!("sectors: {}", file.sectors().len());
+                println!("size:    {} bytes", file.size());
+                if file.is_compressed() {
+                    println!("stored:  {} bytes", file.stored_size());
+                }
+                ExitCode::Success
+            } else if let Some(dir) = filesystem.get_dir(&path) {
+                println!("type:    directory");
+                println!("drive:   {}", dir.drive_index);
+                println!("entry:   sector {}", dir.entry_addr);
+                println!("sectors: {}", dir.sectors().len());
+                println!("entries: {}", filesystem.list_files(&path).unwrap().len());
+                ExitCode::Success
+            } else {
+                ExitCode::NotFoundError
+            }
+        } else {
+            ExitCode::NotMountedError
+        }
+    }
+    fn usage(&self) -> &str {
+        "help:            shows details about a file or directory
+         usage:           stat <path>
+         example command: stat document
+         example output:  type: file"
+    }
+}
+
+/// Command to set or clear the read-only and hidden flags of a file or directory
+struct AttribCommand {
+    name: String,
+    changes: Vec<(u8, bool)>,
+    parse_error: bool,
+}
+
+impl Command for AttribCommand {
     fn new(args: &[&str]) -> Box<Self> {
-        if args.len() != 2 {
-            return Box::new(AddCommand {
-                number1: 0_f64,
-                number2: 0_f64,
-                parse_error: true,
-            });
-        }
+        let mut changes: Vec<(u8, bool)> = Vec::new();
+        let mut parse_error = args.len() == 0;
 
-        let parsed_number1 = args[0].parse::<f64>();
-        let parsed_number2 = args[1].parse::<f64>();
-        if parsed_number1.is_ok() && parsed_number2.is_ok() {
-            Box::new(AddCommand {
-                number1: parsed_number1.unwrap(),
-                number2: parsed_number2.unwrap(),
-                parse_error: false,
-            })
-        } else {
-            Box::new(AddCommand {
-                number1: 0_f64,
-                number2: 0_f64,
-                parse_error: true,
-            })
+        for arg in args.iter().take(args.len().saturating_sub(1)) {
+            match *arg {
+                "+r" => changes.push((FLAG_READ_ONLY, true)),
+                "-r" => changes.push((FLAG_READ_ONLY, false)),
+                "+h" => changes.push((FLAG_HIDDEN, true)),
+                "-h" => changes.push((FLAG_HIDDEN, false)),
+                _ => parse_error = true,
+            }
         }
+
+        Box::new(AttribCommand {
+            name: args.last().map_or(String::new(), |s| s.to_string()),
+            changes,
+            parse_error,
+        })
     }
     fn execute(&self) -> ExitCode {
-        if !self.parse_error {
-            println!("{}", self.number1 + self.number2);
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        let (drive, path) = resolve_path(&self.name);
+        let mut fs = crate::fs::FILESYSTEM.lock();
+
+        if let Some(filesystem) = fs.get_mut(drive) {
+            for (flag, enabled) in &self.changes {
+                match filesystem.set_flags(&path, *flag, *enabled) {
+                    ExitCode::Success => (),
+                    error_code => return error_code,
+                }
+            }
+
+            let flags = if let Some(file) = filesystem.get_file(&path) {
+                file.flags
+            } else if let Some(dir) = filesystem.get_dir(&path) {
+                dir.flags
+            } else {
+                return ExitCode::NotFoundError;
+            };
+
+            let yes_no = |set: bool| if set { "yes" } else { "no" };
+            println!(
+                "read-only: {}, hidden: {}",
+                yes_no(flags & FLAG_READ_ONLY != 0),
+                yes_no(flags & FLAG_HIDDEN != 0)
+            );
             ExitCode::Success
         } else {
-            ExitCode::ParseError
+            ExitCode::NotMountedError
         }
     }
     fn usage(&self) -> &str {
-        "help:            adds two numbers
-         usage:           add <first number> <second number>
-         example command: add 7 8
-         example output:  15"
+        "help:            sets (+) or clears (-) the read-only (r) and hidden (h) flags
+         usage:           attrib [+r|-r] [+h|-h] <path>
+         example command: attrib +r document
+         example output:  read-only: yes, hidden: no"
     }
 }
 
-/// Command to list connected disks
-struct DiskInfoCommand;
+/// Command to mount a filesystem, or list mounted filesystems if no drive is given
+struct MountCommand {
+    drive_index: Option<u8>,
+    make_default: bool,
+    parse_error: bool,
+}
 
-impl Command for DiskInfoCommand {
-    fn new(_args: &[&str]) -> Box<Self> {
-        Box::new(DiskInfoCommand)
+impl Command for MountCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        let make_default = args.get(0) == Some(&"-d");
+        let args = if make_default { &args[1..] } else { args };
+        let (drive_index, parse_error) = match args
+            .get(0)
+            .map(|arg| arg.trim_end_matches(':').parse::<u8>())
+        {
+            Some(Ok(drive_index)) => (Some(drive_index), false),
+            Some(Err(_)) => (None, true),
+            // There's nothing to make the default without a drive
+            None => (None, make_default),
+        };
+
+        Box::new(MountCommand {
+            drive_index,
+            make_default,
+            parse_error,
+        })
     }
     fn execute(&self) -> ExitCode {
-        let drives = crate::ata::DRIVES.lock();
-        for drive in &*drives {
-            info(&format!(
-                "ATA {}: {} {} {} ({} MB)\n",
-                drive.bus_index,
-                drive.drive_index,
-                drive.model,
-                drive.serial,
-                drive.sectors / 2048
-            ));
+        if self.parse_error {
+            return ExitCode::ParseError;
+        }
+
+        if let Some(drive_index) = self.drive_index {
+            // A filesystem which is already mounted can be made the default without mounting it again
+            let mounted = crate::fs::FILESYSTEM
+                .lock()
+                .filesystems
+                .contains_key(&drive_index);
+            if !(mounted && self.make_default) {
+                match crate::fs::mount(drive_index) {
+                    ExitCode::Success => {
+                        okay(&format!("mounted filesystem on disk {}\n", drive_index));
+                    }
+                    error_code => return error_code,
+                }
+            }
+
+            if self.make_default {
+                match crate::fs::FILESYSTEM.lock().set_default(drive_index) {
+                    ExitCode::Success => PATH.lock().clear(),
+                    error_code => return error_code,
+                }
+                okay(&format!(
+                    "disk {} is now the default filesystem\n",
+                    drive_index
+                ))
+            } else {
+                ExitCode::Success
+            }
+        } else {
+            let mounts = crate::fs::FILESYSTEM.lock();
+            if mounts.filesystems.len() == 0 {
+                println!("no filesystems mounted");
+            }
+            for drive_index in mounts.filesystems.keys() {
+                if mounts.current == Some(*drive_index) {
+                    println!(" - {}: (current)", drive_index);
+                } else {
+                    println!(" - {}:", drive_index);
+                }
+            }
+            ExitCode::Success
         }
-        ExitCode::Success
     }
     fn usage(&self) -> &str {
-        "help:            prints info about connected disks
-         usage:           disk
-         example command: disk
-         example output:  ATA 0: 0 MODEL 12345678 (32 MB)"
+        "help:            mounts the filesystem on a drive, or lists mounted filesystems
+                          -d makes it the current filesystem, now and whenever pogostick boots
+         usage:           mount [-d] [drive]
+         example command: mount 1
+         example output:  mounted filesystem on disk 1"
     }
 }
 
-/// Command to list files
-struct ListFilesCommand;
+/// Command to unmount a filesystem
+struct UnmountCommand {
+    drive_index: Option<u8>,
+}
 
-impl Command for ListFilesCommand {
-    fn new(_args: &[&str]) -> Box<Self> {
-        Box::new(ListFilesCommand)
+impl Command for UnmountCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(UnmountCommand {
+            drive_index: args
+                .get(0)
+                .and_then(|arg| arg.trim_end_matches(':').parse::<u8>().ok()),
+        })
     }
     fn execute(&self) -> ExitCode {
-        let mut fs = crate::fs::FILESYSTEM.lock();
-        let path = PATH.lock().clone();
-        if let Some(filesystem) = fs.as_mut() {
-            let files = filesystem.list_files(&path).unwrap();
-            if files.len() == 0 {
-                println!("no files in this directory");
-                return ExitCode::Success;
-            }
-            for file in files {
-                println!(" - {}", file);
+        if let Some(drive_index) = self.drive_index {
+            let mut mounts = crate::fs::FILESYSTEM.lock();
+            let previous = mounts.current;
+            match mounts.unmount(drive_index) {
+                ExitCode::Success => {
+                    if mounts.current != previous {
+                        *PATH.lock() = Vec::new();
+                    }
+                    okay(&format!("unmounted filesystem on disk {}\n", drive_index))
+                }
+                error_code => error_code,
             }
-            ExitCode::Success
         } else {
-            ExitCode::NotMountedError
+            ExitCode::ParseError
         }
     }
     fn usage(&self) -> &str {
-        "help:            lists the files in the current directory
-         usage:           ls|dir
-         example command: ls
-         example output:  no files in this directory"
+        "help:            syncs then unmounts the filesystem on a drive
+         usage:           unmount <drive>
+         example command: unmount 1
+         example output:  unmounted filesystem on disk 1"
     }
 }
 
-/// Command to rename a file or directory
-struct RenameCommand {
-    old_name: String,
-    new_name: String,
+/// Command to create a new filesystem on a drive
+struct MakeFilesystemCommand {
+    drive_index: Option<u8>,
+    reserved_sectors: Option<u32>,
 }
 
-impl Command for RenameCommand {
+impl Command for MakeFilesystemCommand {
     fn new(args: &[&str]) -> Box<Self> {
-        Box::new(RenameCommand {
-            old_name: args[0].to_owned(),
-            new_name: args[1].to_owned(),
+        Box::new(MakeFilesystemCommand {
+            drive_index: args
+                .get(0)
+                .and_then(|arg| arg.trim_end_matches(':').parse::<u8>().ok()),
+            reserved_sectors: match args.get(1) {
+                Some(arg) => arg.parse::<u32>().ok(),
+                None => Some(crate::fs::DEFAULT_RESERVED_SECTORS),
+            },
         })
     }
     fn execute(&self) -> ExitCode {
-        let mut fs = crate::fs::FILESYSTEM.lock();
-        let mut path = PATH.lock().clone();
-        path.extend(self.old_name.split("/").map(|s| s.to_owned()));
+        if let (Some(drive_index), Some(reserved_sectors)) =
+            (self.drive_index, self.reserved_sectors)
+        {
+            if !confirm(&format!(
+                "disk {} will be overwritten, continue?",
+                drive_index
+            )) {
+                return ExitCode::Success;
+            }
 
-        if let Some(filesystem) = fs.as_mut() {
-            filesystem.rename(&path, &self.new_name)
+            match crate::fs::format_drive_with(drive_index as usize, reserved_sectors) {
+                ExitCode::Success => {
+                    if crate::fs::FILESYSTEM.lock().current == Some(drive_index) {
+                        *PATH.lock() = Vec::new();
+                    }
+                    okay(&format!("created filesystem on disk {}\n", drive_index))
+                }
+                error_code => error_code,
+            }
         } else {
-            ExitCode::NotMountedError
+            ExitCode::ParseError
         }
     }
     fn usage(&self) -> &str {
-        "help:            renames a file at the given path
-         usage:           rename <path> <new name>
-         example command: rename documrnt document
-         example output:  N/A"
+        "help:            erases a drive and creates an empty filesystem on it, then mounts it,
+                          reserving 8 sectors at the end for metadata unless another number is given
+         usage:           mkfs <drive> [reserved sectors]
+         example command: mkfs 1 16
+         example output:  created filesystem on disk 1"
     }
 }
 
-/// Command to remove a file from the disk
-struct RemoveFileCommand {
-    name: String,
+/// Command to print a raw sector as hex and ASCII
+struct DumpCommand {
+    drive_index: Option<usize>,
+    sector: Option<u32>,
 }
 
-impl Command for RemoveFileCommand {
+impl Command for DumpCommand {
     fn new(args: &[&str]) -> Box<Self> {
-        Box::new(RemoveFileCommand {
-            name: args[0].to_owned(),
+        Box::new(DumpCommand {
+            drive_index: args
+                .get(0)
+                .and_then(|arg| arg.trim_end_matches(':').parse::<usize>().ok()),
+            sector: args.get(1).and_then(|arg| arg.parse::<u32>().ok()),
         })
     }
     fn execute(&self) -> ExitCode {
-        let mut fs = crate::fs::FILESYSTEM.lock();
-        let mut path = PATH.lock().clone();
-        path.extend(self.name.split("/").map(|s| s.to_owned()));
+        let (drive_index, sector) = match (self.drive_index, self.sector) {
+            (Some(drive_index), Some(sector)) => (drive_index, sector),
+            _ => return ExitCode::ParseError,
+        };
 
-        if let Some(filesystem) = fs.as_mut() {
-            filesystem.delete_file(&path)
-        } else {
-            ExitCode::NotMountedError
+        let mut buf = [0_u8; 512];
+        let read = crate::block::with_device(drive_index, |drive| {
+            if sector < drive.sectors() {
+                drive.read(sector, &mut buf)
+            } else {
+                Err(ExitCode::NotFoundError)
+            }
+        });
+        match read {
+            Some(Ok(())) => (),
+            Some(Err(error_code)) => return error_code,
+            None => return ExitCode::NotFoundError,
+        }
+
+        // Highlight the signatures of directory sectors and superblocks
+        let mut highlighted = [false; 512];
+        if buf[508..512] == *b"POGO" {
+            highlighted[508..512].copy_from_slice(&[true; 4]);
+        }
+        if buf[0..4] == *b"PGFS" {
+            highlighted[0..4].copy_from_slice(&[true; 4]);
+        }
+
+        let normal_colour = ColourCode::new(Colour::White, Colour::Black);
+        let offset_colour = ColourCode::new(Colour::LightCyan, Colour::Black);
+        let highlight_colour = ColourCode::new(Colour::LightGreen, Colour::Black);
+
+        // Print half a sector at a time so nothing scrolls off the screen
+        for (half, rows) in buf.chunks(256).enumerate() {
+            if half > 0 {
+                info("press any key to show the rest of the sector\n");
+                STDIN.get_event();
+            }
+
+            interrupts::without_interrupts(|| {
+                let mut writer = WRITER.lock();
+                for (row, bytes) in rows.chunks(16).enumerate() {
+                    let offset = half * 256 + row * 16;
+                    writer.write_string_colour(&format!("{:04x}  ", offset), offset_colour);
+
+                    for (index, byte) in bytes.iter().enumerate() {
+                        let mut hex_buf = [0_u8; 2];
+                        hex::encode_to_slice(&[*byte], &mut hex_buf).unwrap();
+                        let colour = if highlighted[offset + index] {
+                            highlight_colour
+                        } else {
+                            normal_colour
+                        };
+                        writer.write_string_colour(core::str::from_utf8(&hex_buf).unwrap(), colour);
+                        writer.write_string(" ");
+                    }
+
+                    writer.write_string(" |");
+                    for (index, byte) in bytes.iter().enumerate() {
+                        let character = match byte {
+                            0x20..=0x7e => *byte,
+                            _ => b'.',
+                        };
+                        let colour = if highlighted[offset + index] {
+                            highlight_colour
+                        } else {
+                            normal_colour
+                        };
+                        writer.write_string_colour(
+                            core::str::from_utf8(&[character]).unwrap(),
+                            colour,
+                        );
+                    }
+                    writer.write_string("|\n");
+                }
+            });
         }
+
+        ExitCode::Success
     }
     fn usage(&self) -> &str {
-        "help:            removes a file from the disk
-         usage:           remove <path>
-         example command: remove document
-         example output:  N/A"
+        "help:            prints a raw sector of a drive as hex and ASCII
+         usage:           dump <drive> <sector>
+         example command: dump 0 65535
+         example output:  0000  50 47 46 53 00 01 02 00 ..."
     }
 }
 
-/// Command to remove a directory from the disk
-struct RemoveDirCommand {
-    name: String,
+/// Command to overwrite a raw sector with the given bytes
+struct WriteSectorCommand {
+    drive_index: Option<usize>,
+    sector: Option<u32>,
+    hex_bytes: String,
+    force: bool,
 }
 
-impl Command for RemoveDirCommand {
+impl Command for WriteSectorCommand {
     fn new(args: &[&str]) -> Box<Self> {
-        Box::new(RemoveDirCommand {
-            name: args[0].to_owned(),
+        Box::new(WriteSectorCommand {
+            drive_index: args
+                .get(0)
+                .and_then(|arg| arg.trim_end_matches(':').parse::<usize>().ok()),
+            sector: args.get(1).and_then(|arg| arg.parse::<u32>().ok()),
+            hex_bytes: args.get(2).map_or(String::new(), |arg| (*arg).to_owned()),
+            force: args.get(3) == Some(&"--force"),
         })
     }
     fn execute(&self) -> ExitCode {
-        let mut fs = crate::fs::FILESYSTEM.lock();
-        let mut path = PATH.lock().clone();
-        path.extend(self.name.split("/").map(|s| s.to_owned()));
+        let (drive_index, sector) = match (self.drive_index, self.sector) {
+            (Some(drive_index), Some(sector)) => (drive_index, sector),
+            _ => return ExitCode::ParseError,
+        };
 
-        if let Some(filesystem) = fs.as_mut() {
-            filesystem.delete_dir(&path)
-        } else {
-            ExitCode::NotMountedError
+        // Parse up to a sector of bytes, leaving the rest of the sector zeroed
+        let mut buf = [0_u8; 512];
+        let byte_count = self.hex_bytes.len() / 2;
+        if self.hex_bytes.is_empty()
+            || byte_count > buf.len()
+            || hex::decode_to_slice(&self.hex_bytes, &mut buf[..byte_count]).is_err()
+        {
+            return ExitCode::ParseError;
+        }
+
+        let sectors = match crate::block::with_device(drive_index, |drive| drive.sectors()) {
+            Some(sectors) if sector < sectors => sectors,
+            _ => return ExitCode::NotFoundError,
+        };
+        if sector == sectors - 1 && !self.force {
+            warn("this is the superblock sector, use --force to overwrite it anyway\n");
+            return ExitCode::PermissionError;
+        }
+
+        if !confirm(&format!(
+            "sector {} of disk {} will be overwritten, continue?",
+            sector, drive_index
+        )) {
+            return ExitCode::Success;
+        }
+
+        match crate::block::with_device(drive_index, |drive| drive.write(sector, &buf)) {
+            Some(Ok(())) => (),
+            Some(Err(error_code)) => return error_code,
+            None => return ExitCode::NotFoundError,
+        }
+
+        // Reload the filesystem in case its entry table was overwritten
+        if let Some(filesystem) = crate::fs::FILESYSTEM
+            .lock()
+            .get_mut(Some(drive_index as u8))
+        {
+            filesystem.sync();
         }
+
+        okay(&format!(
+            "wrote {} bytes to sector {}\n",
+            byte_count, sector
+        ))
     }
     fn usage(&self) -> &str {
-        "help:            removes a directory from the disk
-         usage:           remove <path>
-         example command: remove documents
-         example output:  N/A"
+        "help:            overwrites a raw sector of a drive with bytes given in hex
+         usage:           writesec <drive> <sector> <hex bytes> [--force]
+         example command: writesec 0 1000 706f676f
+         example output:  wrote 4 bytes to sector 1000"
     }
 }
 
-/// Command to write text to a file
-struct WriteCommand {
-    name: String,
-    text: String,
+/// Runs the given function on the block device with the given index, which is only locked while it runs.
+/// Returns `NotFoundError` if there is no such device.
+fn on_device(
+    drive_index: usize,
+    f: impl FnOnce(&dyn crate::block::BlockDevice) -> Result<(), ExitCode>,
+) -> Result<(), ExitCode> {
+    crate::block::with_device(drive_index, f).unwrap_or(Err(ExitCode::NotFoundError))
 }
 
-impl Command for WriteCommand {
+/// Command to measure how quickly a drive can write and read sectors
+struct DiskSpeedCommand {
+    drive_index: Option<usize>,
+    sector_count: Option<u32>,
+}
+
+impl Command for DiskSpeedCommand {
     fn new(args: &[&str]) -> Box<Self> {
-        Box::new(WriteCommand {
-            name: args[0].to_owned(),
-            text: args[1..].join(" "),
+        Box::new(DiskSpeedCommand {
+            drive_index: match args.get(0) {
+                Some(arg) => arg.trim_end_matches(':').parse::<usize>().ok(),
+                None => Some(crate::fs::FILESYSTEM.lock().current.unwrap_or(0) as usize),
+            },
+            sector_count: match args.get(1) {
+                Some(arg) => arg.parse::<u32>().ok().filter(|count| *count > 0),
+                None => Some(64),
+            },
         })
     }
     fn execute(&self) -> ExitCode {
-        let mut fs = crate::fs::FILESYSTEM.lock();
-        let mut path = PATH.lock().clone();
-        path.extend(self.name.split("/").map(|s| s.to_owned()));
-        if let Some(filesystem) = fs.as_mut() {
-            match filesystem.write_file(&path, self.text.as_bytes().to_vec()) {
-                ExitCode::Success => okay("successfully written file\n"),
-                error_code => error_code,
+        let (drive_index, sector_count) = match (self.drive_index, self.sector_count) {
+            (Some(drive_index), Some(sector_count)) => (drive_index, sector_count),
+            _ => return ExitCode::ParseError,
+        };
+
+        // Metadata sectors reserved by a filesystem may be empty, but must never be overwritten
+        let below = crate::fs::Superblock::load(drive_index)
+            .map_or(u32::MAX, |superblock| superblock.reserved_start());
+
+        // The drive is only locked for each operation, so the rest of the system can use it in between
+        let first_sector = match crate::block::with_device(drive_index, |drive| {
+            drive.find_available_run(below, sector_count)
+        }) {
+            Some(Some(first_sector)) => first_sector,
+            _ => return ExitCode::NotFoundError,
+        };
+        info(&format!(
+            "using sectors {} to {} of disk {}\n",
+            first_sector,
+            first_sector + sector_count - 1,
+            drive_index
+        ));
+
+        let pattern = |sector: u32| {
+            let mut buf = [0_u8; 512];
+            for (index, byte) in buf.iter_mut().enumerate() {
+                *byte = (sector as usize + index) as u8 | 1;
+            }
+            buf
+        };
+
+        // Show progress on the current line, across both the write and read passes
+        let show_progress = |pass: u32, sector: u32| {
+            let done = pass * sector_count + sector - first_sector + 1;
+            progress(BUFFER_HEIGHT - 1, done as f64 / (sector_count * 2) as f64);
+        };
+
+        // Stop at the first sector which can't be accessed, but still clear the sectors afterwards
+        let mut failure = None;
+        let write_start = crate::time::uptime();
+        for sector in first_sector..first_sector + sector_count {
+            if let Err(error_code) =
+                on_device(drive_index, |drive| drive.write(sector, &pattern(sector)))
+            {
+                failure = Some(error_code);
+                break;
             }
+            show_progress(0, sector);
+        }
+        crate::block::with_device(drive_index, |drive| drive.flush());
+        let write_time = crate::time::uptime() - write_start;
+
+        let mut mismatches = 0;
+        let read_start = crate::time::uptime();
+        for sector in first_sector..first_sector + sector_count {
+            if failure.is_some() {
+                break;
+            }
+            let mut buf = [0_u8; 512];
+            if let Err(error_code) = on_device(drive_index, |drive| drive.read(sector, &mut buf)) {
+                failure = Some(error_code);
+            } else if buf != pattern(sector) {
+                mismatches += 1;
+            }
+            show_progress(1, sector);
+        }
+        let read_time = crate::time::uptime() - read_start;
+        println!();
+
+        // Leave the sectors available again
+        for sector in first_sector..first_sector + sector_count {
+            on_device(drive_index, |drive| drive.write(sector, &[0_u8; 512])).ok();
+        }
+        crate::block::with_device(drive_index, |drive| drive.flush());
+
+        if let Some(error_code) = failure {
+            return error_code;
+        }
+
+        let megabytes = (sector_count as f64 * 512.0) / (1024.0 * 1024.0);
+        for (name, time) in [("write", write_time), ("read", read_time)].iter() {
+            println!(
+                "{}: {:.3} MB/s, {:.3} ms per sector",
+                name,
+                megabytes / time.max(0.001),
+                time * 1000.0 / sector_count as f64
+            );
+        }
+
+        if mismatches == 0 {
+            ExitCode::Success
         } else {
-            ExitCode::NotMountedError
+            err(&format!("{} sector(s) read back incorrectly", mismatches))
         }
     }
     fn usage(&self) -> &str {
-        "help:            writes text to a file
-         usage:           wt <path> <text>
-         example command: wt document hello world
-         example output:  N/A"
+        "help:            writes then reads free sectors to measure the speed of a drive
+         usage:           diskspeed [drive] [sectors]
+         example command: diskspeed 0 128
+         example output:  write: 0.512 MB/s, 0.954 ms per sector"
     }
 }
 
-/// Command to read text from a file
-struct ReadCommand {
-    name: String,
+/// Command to flush every mounted filesystem to disk
+struct SyncCommand;
+
+impl Command for SyncCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(SyncCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        if let ExitCode::PermissionError = save_history() {
+            warn("the history file is read-only so the history wasn't saved\n");
+        }
+
+        let mut mounts = crate::fs::FILESYSTEM.lock();
+        if mounts.filesystems.len() == 0 {
+            return ExitCode::NotMountedError;
+        }
+
+        for filesystem in mounts.filesystems.values_mut() {
+            filesystem.sync();
+        }
+
+        okay(&format!(
+            "synced {} filesystem(s)\n",
+            mounts.filesystems.len()
+        ))
+    }
+    fn usage(&self) -> &str {
+        "help:            saves the history then writes all cached data to the mounted disks
+         usage:           sync
+         example command: sync
+         example output:  synced 1 filesystem(s)"
+    }
 }
 
-impl Command for ReadCommand {
-    fn new(args: &[&str]) -> Box<Self> {
-        Box::new(ReadCommand {
-            name: args[0].to_owned(),
-        })
+/// Command to recover from the filesystem being left locked, by unlocking and remounting everything
+struct FsResetCommand;
+
+impl Command for FsResetCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(FsResetCommand)
     }
     fn execute(&self) -> ExitCode {
-        let mut fs = crate::fs::FILESYSTEM.lock();
-        let mut path = PATH.lock().clone();
-        path.extend(self.name.split("/").map(|s| s.to_owned()));
+        // Nothing else runs while a command is executing, so any lock still held has been leaked
+        unsafe { crate::fs::reset() };
+        *PATH.lock() = Vec::new();
 
-        if let Some(filesystem) = fs.as_mut() {
-            let file = filesystem.get_file(&path);
+        let mounted = crate::fs::FILESYSTEM.lock().filesystems.len();
+        okay(&format!("remounted {} filesystem(s)\n", mounted))
+    }
+    fn usage(&self) -> &str {
+        "help:            unlocks and remounts every filesystem if file commands have stopped responding
+         usage:           fsreset
+         example command: fsreset
+         example output:  remounted 1 filesystem(s)"
+    }
+}
 
-            if let Some(f) = file {
-                let file_bytes = f.read();
-                if let Ok(file_text) = core::str::from_utf8(&file_bytes) {
-                    println!("{}", file_text)
-                } else {
-                    let mut bytes: Vec<u8> = Vec::with_capacity(file_bytes.len() * 2);
-                    hex::encode_to_slice(file_bytes, &mut bytes).unwrap();
-                    warn("cannot detect encoding, printing as hex\n\n");
-                    println!("{}", core::str::from_utf8(&bytes).unwrap());
-                }
-                ExitCode::Success
-            } else {
-                ExitCode::NotFoundError
-            }
-        } else {
-            ExitCode::NotMountedError
+/// Command to edit a text file line by line
+struct EditCommand {
+    name: Option<String>,
+}
+
+impl Command for EditCommand {
+    fn new(args: &[&str]) -> Box<Self> {
+        Box::new(EditCommand {
+            name: args.first().map(|arg| (*arg).to_owned()),
+        })
+    }
+    fn execute(&self) -> ExitCode {
+        let name = match &self.name {
+            Some(name) => name,
+            None => return ExitCode::ParseError,
+        };
+        let (drive, path) = resolve_path(name);
+        match crate::editor::Editor::open(drive, path) {
+            Ok(mut editor) => editor.run(),
+            Err(error_code) => error_code,
         }
     }
     fn usage(&self) -> &str {
-        "help:            prints text from a UTF-8 file
-         usage:           rt <path>
-         example command: rt document
-         example output:  hello world"
+        "help:            edits a text file line by line, creating it if it doesn't exist
+         usage:           edit <path>
+         keys:            enter appends the typed line, or replaces the selected line
+                          up/down select a line to replace, escape deselects it
+                          delete removes the selected line
+                          ctrl+s saves the file, ctrl+q quits
+         example command: edit notes"
     }
 }
 
-/// Create directory command
-struct CreateDirCommand {
-    name: String,
+/// Kinds of fault `crashtest` can cause.
+const CRASH_KINDS: &[&str] = &[
+    "breakpoint",
+    "pagefault",
+    "gpfault",
+    "divide",
+    "stackoverflow",
+];
+
+/// Command to deliberately cause a CPU exception, to check the handlers for it
+struct CrashTestCommand {
+    kind: String,
 }
 
-impl Command for CreateDirCommand {
+impl Command for CrashTestCommand {
     fn new(args: &[&str]) -> Box<Self> {
-        Box::new(CreateDirCommand {
-            name: args[0].to_owned(),
+        Box::new(CrashTestCommand {
+            kind: args.first().copied().unwrap_or_default().to_owned(),
         })
     }
     fn execute(&self) -> ExitCode {
-        let mut fs = crate::fs::FILESYSTEM.lock();
-        let mut path = PATH.lock().clone();
-        path.push(self.name.clone());
+        if !CRASH_KINDS.contains(&self.kind.as_str()) {
+            return err(&format!("expected one of: {}\n", CRASH_KINDS.join(", ")));
+        }
 
-        if let Some(filesystem) = fs.as_mut() {
-            filesystem.create_dir(&path)
-        } else {
-            ExitCode::NotMountedError
+        warn(&format!("causing a {}\n", self.kind));
+        match self.kind.as_str() {
+            "breakpoint" => x86_64::instructions::interrupts::int3(),
+            "pagefault" => unsafe {
+                // Nothing is mapped at this address, as the kernel and heap are elsewhere
+                core::ptr::read_volatile(0xdead_beef as *const u8);
+            },
+            "gpfault" => unsafe {
+                // Addresses which aren't canonical cause a general protection fault rather than a page fault
+                core::ptr::read_volatile(0x8000_0000_0000_0000 as *const u8);
+            },
+            "divide" => unsafe {
+                // Dividing by zero in Rust panics before the CPU sees it, so `idiv` is used directly
+                core::arch::asm!("cqo", "idiv {0}", in(reg) 0_i64, inout("rax") 1_i64 => _, out("rdx") _);
+            },
+            _ => {
+                // Recurses until the guard page below the stack is hit, which has to be handled on another stack
+                #[allow(unconditional_recursion)]
+                fn recurse(depth: u64) -> u64 {
+                    core::hint::black_box(recurse(core::hint::black_box(depth + 1))) + 1
+                }
+                recurse(0);
+            }
         }
+
+        // Only the breakpoint handler returns
+        okay("the handler returned\n");
+        ExitCode::Success
     }
     fn usage(&self) -> &str {
-        "help:            creates a directory at the given path
-         usage:           mkdir <path>
-         example command: mkdir documents
-         example output:  N/A"
+        "help:            causes a cpu exception to check its handler, only in debug builds, where the kind is
+                          breakpoint, pagefault, gpfault, divide or stackoverflow
+         usage:           crashtest <kind>
+         example command: crashtest breakpoint
+         example output:  EXCEPTION: BREAKPOINT"
+    }
+}
+
+/// Command to run the kernel's smoke tests
+struct SelfTestCommand;
+
+impl Command for SelfTestCommand {
+    fn new(_args: &[&str]) -> Box<Self> {
+        Box::new(SelfTestCommand)
+    }
+    fn execute(&self) -> ExitCode {
+        crate::selftest::run()
+    }
+    fn usage(&self) -> &str {
+        "help:            runs the smoke tests then exits qemu with the result if possible
+         usage:           selftest
+         example command: selftest
+         example output:  [ OKAY ] all tests passed"
     }
 }
 
@@ -0,0 +1,85 @@
+// Processor identification
+// Wraps the `CPUID` instruction to report the vendor, brand and supported features.
+
+use alloc::string::String;
+use bit_field::BitField;
+use core::arch::x86_64::{__cpuid, CpuidResult};
+
+/// Represents a selection of features the processor may support.
+#[derive(Debug, Clone, Copy)]
+pub struct Features {
+    pub tsc: bool,           // time stamp counter
+    pub invariant_tsc: bool, // time stamp counter runs at a constant rate in every power state
+    pub apic: bool,          // on-chip advanced programmable interrupt controller
+    pub sse: bool,
+    pub sse2: bool,
+    pub sse3: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub avx: bool,
+}
+
+/// Runs `CPUID` with the given leaf.
+/// `__cpuid` is only marked safe on newer toolchains, so the `unsafe` block may be redundant.
+#[allow(unused_unsafe)]
+fn cpuid(leaf: u32) -> CpuidResult {
+    unsafe { __cpuid(leaf) }
+}
+
+/// Gets the highest extended leaf supported by the processor.
+fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000).eax
+}
+
+/// Appends the bytes of a register to a string as ASCII.
+fn push_register(string: &mut String, register: u32) {
+    for byte in register.to_le_bytes().iter() {
+        string.push(*byte as char);
+    }
+}
+
+/// Gets the processor's vendor string, such as `GenuineIntel` or `AuthenticAMD`.
+pub fn vendor() -> String {
+    let result = cpuid(0);
+    let mut vendor = String::new();
+    push_register(&mut vendor, result.ebx);
+    push_register(&mut vendor, result.edx);
+    push_register(&mut vendor, result.ecx);
+    vendor
+}
+
+/// Gets the processor's brand string, or None if the processor doesn't report one.
+pub fn brand() -> Option<String> {
+    if max_extended_leaf() < 0x8000_0004 {
+        return None;
+    }
+
+    let mut brand = String::new();
+    for leaf in 0x8000_0002..=0x8000_0004 {
+        let result = cpuid(leaf);
+        push_register(&mut brand, result.eax);
+        push_register(&mut brand, result.ebx);
+        push_register(&mut brand, result.ecx);
+        push_register(&mut brand, result.edx);
+    }
+
+    Some(brand.trim_end_matches('\0').trim().into())
+}
+
+/// Detects which of the features in `Features` the processor supports.
+pub fn features() -> Features {
+    let basic = cpuid(1);
+    let invariant_tsc = max_extended_leaf() >= 0x8000_0007 && cpuid(0x8000_0007).edx.get_bit(8);
+
+    Features {
+        tsc: basic.edx.get_bit(4),
+        invariant_tsc,
+        apic: basic.edx.get_bit(9),
+        sse: basic.edx.get_bit(25),
+        sse2: basic.edx.get_bit(26),
+        sse3: basic.ecx.get_bit(0),
+        sse4_1: basic.ecx.get_bit(19),
+        sse4_2: basic.ecx.get_bit(20),
+        avx: basic.ecx.get_bit(28),
+    }
+}
@@ -0,0 +1,267 @@
+// Line editor
+// A simple full-screen editor for text files, used by the `edit` command.
+//
+// Keybindings:
+//   typing + Enter  appends a line, or replaces the selected line
+//   Up / Down       selects a line to replace, loading it for editing
+//   Escape          deselects the line, going back to appending
+//   Delete          removes the selected line
+//   Ctrl+S          saves the file
+//   Ctrl+Q          quits, asking to press it again if there are unsaved changes
+
+use crate::input::{InputEvent, SpecialKey, STDIN};
+use crate::vga::{set_cursor_visible, Colour, ColourCode, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use crate::{fs, ExitCode};
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use x86_64::instructions::interrupts;
+
+/// Width of the line number column, including the separator.
+const NUMBER_WIDTH: usize = 6;
+
+/// Represents a file being edited.
+pub struct Editor {
+    drive: Option<u8>,
+    path: Vec<String>,
+    lines: Vec<String>,
+    selected: Option<usize>,
+    input: String,
+    status: String,
+    modified: bool,
+    confirming_quit: bool,
+}
+
+impl Editor {
+    /// Opens the file at the given path on the given drive (or the current drive if `None`) for editing.
+    /// If the file doesn't exist, the editor starts empty and creates it when saved.
+    pub fn open(drive: Option<u8>, path: Vec<String>) -> Result<Self, ExitCode> {
+        if path.is_empty() {
+            return Err(ExitCode::NotFoundError);
+        }
+
+        let mounts = fs::FILESYSTEM.lock();
+        let filesystem = mounts.get(drive).ok_or(ExitCode::NotMountedError)?;
+        let (lines, status) = match filesystem.get_file(&path) {
+            Some(file) => {
+                let bytes = file.read()?;
+                let text = String::from_utf8_lossy(&bytes);
+                let mut lines: Vec<String> = text.split('\n').map(|line| line.to_owned()).collect();
+                if lines.last().map_or(false, |line| line.is_empty()) {
+                    lines.pop();
+                }
+                let status = format!("opened {} line(s)", lines.len());
+                (lines, status)
+            }
+            None => (Vec::new(), "new file".to_string()),
+        };
+
+        Ok(Editor {
+            drive,
+            path,
+            lines,
+            selected: None,
+            input: String::new(),
+            status,
+            modified: false,
+            confirming_quit: false,
+        })
+    }
+
+    /// Runs the editor until the user quits.
+    pub fn run(&mut self) -> ExitCode {
+        set_cursor_visible(true);
+
+        loop {
+            self.draw();
+
+            let event = STDIN.get_event();
+            if event != InputEvent::Key(SpecialKey::Ctrl('q')) {
+                self.confirming_quit = false;
+            }
+
+            match event {
+                InputEvent::Char('\n') => self.commit_input(),
+                InputEvent::Char('\x08') => {
+                    self.input.pop();
+                }
+                InputEvent::Char(character) if !character.is_control() => {
+                    self.input.push(character)
+                }
+                InputEvent::Key(SpecialKey::Up) => {
+                    let line = match self.selected {
+                        Some(line) => line.saturating_sub(1),
+                        None => self.lines.len().saturating_sub(1),
+                    };
+                    self.select(Some(line));
+                }
+                InputEvent::Key(SpecialKey::Down) => match self.selected {
+                    Some(line) if line + 1 < self.lines.len() => self.select(Some(line + 1)),
+                    _ => self.select(None),
+                },
+                InputEvent::Key(SpecialKey::Escape) => self.select(None),
+                InputEvent::Key(SpecialKey::Delete) => {
+                    if let Some(line) = self.selected {
+                        self.lines.remove(line);
+                        self.modified = true;
+                        self.status = format!("removed line {}", line + 1);
+                        self.select(None);
+                    }
+                }
+                InputEvent::Key(SpecialKey::Ctrl('s')) => self.save(),
+                InputEvent::Key(SpecialKey::Ctrl('q')) => {
+                    if !self.modified || self.confirming_quit {
+                        break;
+                    }
+                    self.status =
+                        "there are unsaved changes, press Ctrl+Q again to quit anyway".to_string();
+                    self.confirming_quit = true;
+                }
+                _ => (),
+            }
+        }
+
+        interrupts::without_interrupts(|| {
+            for _ in 0..BUFFER_HEIGHT {
+                WRITER.lock().new_line();
+            }
+        });
+        ExitCode::Success
+    }
+
+    /// Selects a line to replace, loading it into the input, or deselects it if `None`.
+    fn select(&mut self, line: Option<usize>) {
+        self.selected = line.filter(|line| *line < self.lines.len());
+        self.input = match self.selected {
+            Some(line) => self.lines[line].clone(),
+            None => String::new(),
+        };
+    }
+
+    /// Replaces the selected line with the input, or appends it if no line is selected.
+    fn commit_input(&mut self) {
+        let input = core::mem::take(&mut self.input);
+        match self.selected {
+            Some(line) => {
+                self.lines[line] = input;
+                self.status = format!("replaced line {}", line + 1);
+            }
+            None => {
+                self.lines.push(input);
+                self.status = format!("appended line {}", self.lines.len());
+            }
+        }
+        self.selected = None;
+        self.modified = true;
+    }
+
+    /// Writes the lines back to the file, replacing it if it exists.
+    /// The old contents are only removed once the new ones are written, so a failed save loses nothing.
+    fn save(&mut self) {
+        let mut mounts = fs::FILESYSTEM.lock();
+        let filesystem = match mounts.get_mut(self.drive) {
+            Some(filesystem) => filesystem,
+            None => {
+                self.status = ExitCode::NotMountedError.to_string();
+                return;
+            }
+        };
+
+        let text = self.lines.join("\n");
+        self.status = match filesystem.replace_file(&self.path, text.into_bytes()) {
+            ExitCode::Success => {
+                self.modified = false;
+                format!("saved {} line(s)", self.lines.len())
+            }
+            error_code => error_code.to_string(),
+        };
+    }
+
+    /// Redraws the whole screen: the title, as many lines as fit around the selected one, the status and the input.
+    fn draw(&self) {
+        let title_colour = ColourCode::new(Colour::Black, Colour::LightGray);
+        let number_colour = ColourCode::new(Colour::LightCyan, Colour::Black);
+        let selected_colour = ColourCode::new(Colour::Black, Colour::LightCyan);
+        let status_colour = ColourCode::new(Colour::Yellow, Colour::Black);
+        let text_colour = ColourCode::new(Colour::White, Colour::Black);
+
+        // Show as many lines as fit on screen with the title, status and input rows (and the status bar if shown)
+        let visible_lines = interrupts::without_interrupts(|| WRITER.lock().scrolling_rows()) - 3;
+
+        // Keep the selected line (or the end of the file when appending) in view
+        let focus = self.selected.unwrap_or(self.lines.len());
+        let first_line = (focus + 1).saturating_sub(visible_lines);
+
+        let title = format!(
+            " edit {}{} - Ctrl+S save, Ctrl+Q quit",
+            self.path.join("/"),
+            if self.modified { " (modified)" } else { "" }
+        );
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            // Draw the whole screen before showing it, so it doesn't flicker
+            writer.set_buffered(true);
+            for _ in 0..BUFFER_HEIGHT {
+                writer.new_line();
+            }
+
+            writer.write_string_colour(&pad(&title, BUFFER_WIDTH - 1), title_colour);
+            for row in 0..visible_lines {
+                writer.new_line();
+                let line = first_line + row;
+                if let Some(text) = self.lines.get(line) {
+                    let number = format!("{:>4} ", line + 1);
+                    let text = truncate(text, BUFFER_WIDTH - NUMBER_WIDTH);
+                    if self.selected == Some(line) {
+                        writer.write_string_colour(&number, selected_colour);
+                    } else {
+                        writer.write_string_colour(&number, number_colour);
+                    }
+                    writer.write_string_colour(" ", text_colour);
+                    writer.write_string_colour(text, text_colour);
+                }
+            }
+
+            writer.new_line();
+            writer.write_string_colour(truncate(&self.status, BUFFER_WIDTH - 1), status_colour);
+            writer.new_line();
+
+            let prompt = match self.selected {
+                Some(line) => format!("{}> ", line + 1),
+                None => "+> ".to_string(),
+            };
+            writer.write_string_colour(&prompt, number_colour);
+            // Only show the end of the input if it's too long to fit
+            let width = BUFFER_WIDTH - prompt.len() - 1;
+            writer.write_string_colour(tail(&self.input, width), text_colour);
+            writer.set_buffered(false);
+        });
+    }
+}
+
+/// Truncates a string to fit in the given number of columns.
+fn truncate(text: &str, width: usize) -> &str {
+    match text.char_indices().nth(width) {
+        Some((index, _)) => &text[..index],
+        None => text,
+    }
+}
+
+/// Gets as much of the end of a string as fits in the given number of columns.
+fn tail(text: &str, width: usize) -> &str {
+    let skipped = text.chars().count().saturating_sub(width);
+    match text.char_indices().nth(skipped) {
+        Some((index, _)) => &text[index..],
+        None => text,
+    }
+}
+
+/// Pads a string with spaces to the given number of columns, truncating it if it's too long.
+fn pad(text: &str, width: usize) -> String {
+    let text = truncate(text, width);
+    format!("{}{}", text, " ".repeat(width - text.chars().count()))
+}
@@ -1,24 +1,263 @@
-use crate::ata::{self, Drive};
+use crate::ata;
+use crate::block::{self, BlockDevice};
 use crate::input::STDIN;
-use crate::vga::{info, okay, warn};
-use crate::{println, ExitCode};
-use alloc::{borrow::ToOwned, format, string::String, string::ToString, vec::Vec};
+use crate::vga::{err, info, okay, warn};
+use crate::{println, time::DateTime, ExitCode};
+use alloc::{
+    borrow::ToOwned,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    string::ToString,
+    vec,
+    vec::Vec,
+};
 use bit_field::BitField;
+use core::{cell::Cell, cmp::Reverse, fmt::Display};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+/// Type byte bit marking the object as a directory.
+pub const FLAG_DIR: u8 = 0x01;
+/// Type byte bit marking the object as read-only.
+pub const FLAG_READ_ONLY: u8 = 0x02;
+/// Type byte bit marking the object as hidden from listings.
+pub const FLAG_HIDDEN: u8 = 0x04;
+/// Type byte bit marking a file's data as scrambled with a passphrase.
+pub const FLAG_ENCRYPTED: u8 = 0x08;
+/// Type byte bit marking a file's data as run-length encoded.
+pub const FLAG_COMPRESSED: u8 = 0x10;
+/// Type byte bit marking a file as deleted, with its data kept so it can be restored until the space is needed.
+pub const FLAG_DELETED: u8 = 0x20;
+
+/// Signature found at the end of every directory sector.
+const FILESYSTEM_SIGNATURE: [u8; 4] = [b'P', b'O', b'G', b'O'];
+
 lazy_static! {
-    pub static ref FILESYSTEM: Mutex<Option<FileSystem>> = Mutex::new(None);
+    pub static ref FILESYSTEM: Mutex<Mounts> = Mutex::new(Mounts::new());
+}
+
+/// Struct representing every mounted filesystem, keyed by drive index.
+#[derive(Default)]
+pub struct Mounts {
+    pub filesystems: BTreeMap<u8, FileSystem>,
+    pub current: Option<u8>,
+}
+
+impl Mounts {
+    /// Create an empty set of mounts.
+    pub fn new() -> Self {
+        Mounts {
+            filesystems: BTreeMap::new(),
+            current: None,
+        }
+    }
+
+    /// Get the filesystem on the given drive, or the current filesystem if no drive is given.
+    pub fn get(&self, drive_index: Option<u8>) -> Option<&FileSystem> {
+        self.filesystems.get(&drive_index.or(self.current)?)
+    }
+
+    /// Get the filesystem on the given drive mutably, or the current filesystem if no drive is given.
+    pub fn get_mut(&mut self, drive_index: Option<u8>) -> Option<&mut FileSystem> {
+        self.filesystems.get_mut(&drive_index.or(self.current)?)
+    }
+
+    /// Add a filesystem to the mounts, replacing any already mounted from the same drive.
+    /// It becomes the current filesystem if there isn't one already.
+    pub fn mount(&mut self, filesystem: FileSystem) {
+        if self.current.is_none() {
+            self.current = Some(filesystem.drive_index);
+        }
+        self.filesystems.insert(filesystem.drive_index, filesystem);
+    }
+
+    /// Copy a file from the filesystem on one drive to the filesystem on another, or the same, drive.
+    /// As with `get`, the current filesystem is used if no drive is given.
+    /// If the destination is a directory, the file is copied into it with the same name.
+    pub fn copy_file(
+        &mut self,
+        from_drive: Option<u8>,
+        from: &Vec<String>,
+        to_drive: Option<u8>,
+        to: &Vec<String>,
+    ) -> ExitCode {
+        let (from_drive, to_drive) = match (from_drive.or(self.current), to_drive.or(self.current))
+        {
+            (Some(from_drive), Some(to_drive))
+                if self.filesystems.contains_key(&from_drive)
+                    && self.filesystems.contains_key(&to_drive) =>
+            {
+                (from_drive, to_drive)
+            }
+            _ => return ExitCode::NotMountedError,
+        };
+
+        if from_drive == to_drive {
+            return self
+                .filesystems
+                .get_mut(&from_drive)
+                .unwrap()
+                .copy_file(from, to);
+        }
+
+        match self.filesystems[&from_drive].get_file(from) {
+            Some(file) => self
+                .filesystems
+                .get_mut(&to_drive)
+                .unwrap()
+                .write_copy(&file, to),
+            None => ExitCode::NotFoundError,
+        }
+    }
+
+    /// Remove the filesystem on the given drive from the mounts.
+    /// If it was the current filesystem, the lowest remaining drive becomes current.
+    /// The filesystem is synced before it is detached.
+    pub fn unmount(&mut self, drive_index: u8) -> ExitCode {
+        if let Some(mut filesystem) = self.filesystems.remove(&drive_index) {
+            filesystem.sync();
+        } else {
+            return ExitCode::NotMountedError;
+        }
+
+        if self.current == Some(drive_index) {
+            self.current = self.filesystems.keys().next().cloned();
+        }
+
+        ExitCode::Success
+    }
+
+    /// Make the filesystem on the given drive the current one, and remember it in the superblocks of every mounted
+    /// filesystem so that it becomes the current one again after booting.
+    /// Returns `NotMountedError` if it isn't mounted, or `UnsupportedFilesystemError` if it is too old to have
+    /// a superblock to remember it in.
+    pub fn set_default(&mut self, drive_index: u8) -> ExitCode {
+        match self.filesystems.get(&drive_index) {
+            Some(filesystem) if filesystem.superblock.version == 0 => {
+                return ExitCode::UnsupportedFilesystemError
+            }
+            Some(_) => (),
+            None => return ExitCode::NotMountedError,
+        }
+
+        // Older filesystems without a superblock can't be the default, so they have nothing to clear
+        for (index, filesystem) in self.filesystems.iter_mut() {
+            let is_default = *index == drive_index;
+            if filesystem.superblock.version == 0 || filesystem.superblock.is_default == is_default
+            {
+                continue;
+            }
+
+            filesystem.superblock.is_default = is_default;
+            if let Err(error_code) = filesystem.superblock.update_physical_drive(*index as usize) {
+                return error_code;
+            }
+        }
+
+        self.current = Some(drive_index);
+        ExitCode::Success
+    }
 }
 
 /// Struct representing the filesystem.
 pub struct FileSystem {
     pub drive_index: u8,
+    pub superblock: Superblock,
     pub entry_sector: u32,
     pub entry_table: FileTableSector,
+    /// Number of free sectors, counted when first asked for after the filesystem changes.
+    free_sectors: Cell<Option<u32>>,
 }
 
 impl FileSystem {
+    /// Open the filesystem on the given drive without mounting it.
+    /// Fails with `NoFilesystemError` if the drive doesn't contain a filesystem,
+    /// or `UnsupportedFilesystemError` if it was created by a newer version of Pogostick.
+    /// Any table update which was interrupted after being recorded in the journal is finished first.
+    pub fn open(drive_index: u8) -> Result<Self, ExitCode> {
+        let superblock = Superblock::load(drive_index as usize)?;
+        if Journal::new(&superblock, drive_index as usize).replay()? {
+            warn(&format!(
+                "finished an interrupted update on disk {}\n",
+                drive_index
+            ));
+        }
+
+        let entry_sector = superblock.root_addr;
+
+        Ok(FileSystem {
+            drive_index,
+            superblock,
+            entry_sector,
+            entry_table: FileTableSector::load(entry_sector, drive_index as usize, None)?,
+            free_sectors: Cell::new(None),
+        })
+    }
+
+    /// Get the journal for recording table updates on this filesystem.
+    fn journal(&self) -> Journal {
+        Journal::new(&self.superblock, self.drive_index as usize)
+    }
+
+    /// Flush the drive's write cache and reload the entry table from the disk.
+    pub fn sync(&mut self) {
+        with_drive(self.drive_index as usize, |drive| drive.flush());
+        self.reload_entry_table();
+    }
+
+    /// Reload the entry table from the disk after it has changed, keeping the old copy if it can't be read.
+    /// The free space is counted again next time it is asked for, as the change may have used or freed sectors.
+    fn reload_entry_table(&mut self) {
+        self.free_sectors.set(None);
+        if let Ok(table) = FileTableSector::load(self.entry_sector, self.drive_index as usize, None)
+        {
+            self.entry_table = table;
+        }
+    }
+
+    /// Get the addresses of every sector used by the filesystem, including the reserved sectors and entry table.
+    /// Fails if a table can't be read, as the sectors it refers to would otherwise look unused.
+    pub fn used_sectors(&self) -> Result<Vec<u32>, ExitCode> {
+        let mut sectors: Vec<u32> =
+            (self.superblock.reserved_start()..self.superblock.total_sectors).collect();
+        let mut tables: Vec<u32> = vec![self.entry_sector];
+        let mut guard = ChainGuard::new(self.entry_sector);
+
+        while let Some(addr) = tables.pop() {
+            let table = FileTableSector::load(addr, self.drive_index as usize, None)?;
+            sectors.push(addr);
+
+            tables.extend(guard.follow(table.continuation_addr));
+            sectors.extend(table.deleted.iter().flat_map(|file| file.sectors()));
+
+            for object in table.files {
+                match object {
+                    FileType::File(f) => sectors.extend(f.sectors()),
+                    FileType::Dir(d) => tables.extend(guard.follow(Some(d.entry_addr))),
+                }
+            }
+        }
+
+        Ok(sectors)
+    }
+
+    /// Get the number of sectors which aren't used by the filesystem.
+    /// This is an upper bound, as sectors used outside of the filesystem (e.g. by the bootloader) aren't counted.
+    /// Counting needs every table to be read, so the count is kept until the filesystem changes.
+    /// Fails if a table can't be read, like `used_sectors`.
+    pub fn free_sectors(&self) -> Result<u32, ExitCode> {
+        if let Some(free_sectors) = self.free_sectors.get() {
+            return Ok(free_sectors);
+        }
+
+        // Sector 0 is never allocated, as it always holds the bootloader
+        let usable_sectors = self.superblock.total_sectors.saturating_sub(1);
+        let free_sectors = usable_sectors.saturating_sub(self.used_sectors()?.len() as u32);
+        self.free_sectors.set(Some(free_sectors));
+        Ok(free_sectors)
+    }
+
     /// Get a file at the given path from the filesystem, or None if not found
     pub fn get_file(&self, path: &Vec<String>) -> Option<File> {
         if let Some(table) = self.get_table_with_object(path) {
@@ -28,6 +267,17 @@ impl FileSystem {
         }
     }
 
+    /// Open the file at the given path for reading from any position.
+    /// Named `open_file` as `open` opens the filesystem itself.
+    /// Encrypted files can't be opened without the passphrase, so give `EncryptedError`.
+    pub fn open_file(&self, path: &Vec<String>) -> Result<FileHandle, ExitCode> {
+        match self.get_file(path) {
+            Some(file) if file.is_encrypted() => Err(ExitCode::EncryptedError),
+            Some(file) => Ok(FileHandle::new(file)),
+            None => Err(ExitCode::NotFoundError),
+        }
+    }
+
     /// Get a directory at the given path from the filesystem, or None if not found
     pub fn get_dir(&self, path: &Vec<String>) -> Option<Dir> {
         if let Some(table) = self.get_table_with_object(path) {
@@ -39,421 +289,1422 @@ impl FileSystem {
 
     /// Gets a file table sector containing the given file or directory.
     fn get_table_with_object(&self, path: &Vec<String>) -> Option<FileTableSector> {
-        let mut current_table = self.entry_table.clone();
-        let mut current_dir: Option<String> = None;
-
-        // Iterate over the objects of the path
-        for (index, obj) in path.iter().enumerate() {
-            // Iterate over the tables representing the dir
-            while !current_table.contains_object(obj) {
-                if let Some(new_addr) = current_table.continuation_addr {
-                    current_table = FileTableSector::load(
-                        new_addr,
-                        self.drive_index as usize,
-                        current_dir.clone(),
-                    );
-                } else {
-                    return None;
-                }
+        let (name, dir_path) = path.split_last()?;
+        self.dir_tables(dir_path)?
+            .find(|table| table.contains_object(name))
+    }
+
+    /// Iterate over every table sector of the directory at the given path, following continuations.
+    /// Returns `None` if the directory doesn't exist.
+    fn dir_tables(&self, path: &[String]) -> Option<DirTables> {
+        let mut entry_table = self.entry_table.clone();
+
+        for dir in path {
+            let d = DirTables::new(entry_table).find_map(|table| table.get_dir(dir))?;
+            entry_table =
+                FileTableSector::load(d.entry_addr, self.drive_index as usize, Some(d.name))
+                    .ok()?;
+        }
+
+        Some(DirTables::new(entry_table))
+    }
+
+    /// Iterate over every entry of the directory at the given path, in on-disk order.
+    /// Returns `None` if the directory doesn't exist.
+    pub fn dir_entries(&self, path: &[String]) -> Option<impl Iterator<Item = FileType>> {
+        Some(self.dir_tables(path)?.flat_map(|table| table.files))
+    }
+
+    /// Checks whether the directory at the given path has room for another entry without adding a table.
+    /// Returns `None` if the directory doesn't exist.
+    fn has_table_space(&self, path: &[String]) -> Option<bool> {
+        Some(self.dir_tables(path)?.any(|table| !table.is_full()))
+    }
+
+    /// Gets a table sector of the directory at the given path with room for another entry, as the first table returned.
+    /// If they are all full, a new table is added to the end of the directory in the given spare sector,
+    /// which callers should reserve when `has_table_space` is false, and the previous last table follows it
+    /// with its continuation pointing to the new one.
+    /// Nothing is written, so callers should write every table returned, in order, once they've added the entry.
+    /// Returns `NotFoundError` if the directory doesn't exist, or a new table is needed but there's no spare sector.
+    fn table_with_space(
+        &self,
+        path: &[String],
+        spare_sector: Option<u32>,
+    ) -> Result<Vec<FileTableSector>, ExitCode> {
+        let mut last_table = None;
+        for table in self.dir_tables(path).ok_or(ExitCode::NotFoundError)? {
+            if !table.is_full() {
+                return Ok(vec![table]);
+            }
+            last_table = Some(table);
+        }
+
+        let mut last_table = last_table.ok_or(ExitCode::NotFoundError)?;
+        let new_sector = spare_sector.ok_or(ExitCode::NotFoundError)?;
+        last_table.set_continuation(new_sector);
+
+        let new_table = FileTableSector::empty(
+            new_sector,
+            self.drive_index as usize,
+            last_table.directory_name.clone(),
+        );
+        Ok(vec![new_table, last_table])
+    }
+
+    /// Reserve enough free sectors for a new object in the directory at the given path, plus the given number
+    /// of sectors for the object itself. The first sectors are for the object, and the last is for a new table
+    /// if the directory needs one.
+    /// Free sectors are used where possible, and only once there aren't enough are deleted files purged to make room.
+    /// Nothing else is written, so if there isn't enough space, no sectors need to be freed again.
+    fn reserve_sectors(&self, path: &[String], count: usize) -> Result<Vec<u32>, ExitCode> {
+        let needs_table = !self.has_table_space(path).ok_or(ExitCode::NotFoundError)?;
+        let total = count + needs_table as usize;
+
+        let sectors = with_drive(self.drive_index as usize, |drive| {
+            drive.find_available_sectors(self.superblock.reserved_start(), total)
+        });
+
+        match sectors {
+            Some(sectors) => Ok(sectors),
+            // Purging may also free up room in the directory's tables, so check again from the start
+            None if self.purge_deleted()? => self.reserve_sectors(path, count),
+            None => Err(ExitCode::DiskFullError),
+        }
+    }
+
+    /// Permanently remove every deleted file on the filesystem, freeing their sectors.
+    /// Returns whether there were any.
+    fn purge_deleted(&self) -> Result<bool, ExitCode> {
+        let mut purged = false;
+        let mut tables: Vec<u32> = vec![self.entry_sector];
+        let mut guard = ChainGuard::new(self.entry_sector);
+
+        while let Some(addr) = tables.pop() {
+            let mut table = FileTableSector::load(addr, self.drive_index as usize, None)?;
+            tables.extend(guard.follow(table.continuation_addr));
+            tables.extend(
+                table
+                    .files
+                    .iter()
+                    .filter(|object| object.is_dir())
+                    .filter_map(|object| guard.follow(Some(object.entry_addr()))),
+            );
+
+            if table.deleted.is_empty() {
+                continue;
             }
 
-            if index != path.len() - 1 {
-                // If the found object is a directory, else if it is the final file return it
-                if let Some(new_dir) = current_table.get_dir(obj) {
-                    current_dir = Some(obj.clone());
-                    current_table = FileTableSector::load(
-                        new_dir.entry_addr,
-                        self.drive_index as usize,
-                        current_dir.clone(),
-                    );
-                } else {
-                    return Some(current_table);
-                }
-            } else {
-                return Some(current_table);
+            // Unlink the files before freeing their sectors, as with deleting them outright
+            let deleted = core::mem::take(&mut table.deleted);
+            table.update_physical_drive()?;
+            for addr in deleted.iter().flat_map(|file| file.sectors()) {
+                DataSector::load(addr, self.drive_index as usize).remove();
             }
+            purged = true;
+            self.free_sectors.set(None);
         }
 
-        None
+        Ok(purged)
     }
 
     /// Write a file to the given path containing the specified bytes.
+    /// If there isn't enough space for the whole file, returns `DiskFullError` without writing anything.
     pub fn write_file(&mut self, path: &Vec<String>, bytes: Vec<u8>) -> ExitCode {
-        let mut table_obj: FileTableSector;
-        let mut table = &mut self.entry_table;
-
-        for dir in &path[..path.len() - 1] {
-            // Iterate over the tables representing the dir
-            while table.get_dir(dir).is_none() {
-                if let Some(new_addr) = table.continuation_addr {
-                    table_obj = FileTableSector::load(
-                        new_addr,
-                        self.drive_index as usize,
-                        table.directory_name.clone(),
-                    );
-                    table = &mut table_obj;
-                } else {
-                    return ExitCode::NotFoundError;
-                }
+        if path.is_empty() {
+            return ExitCode::NotFoundError;
+        }
+
+        if let Some(existing) = self.get_file(path) {
+            if existing.is_read_only() {
+                return ExitCode::PermissionError;
             }
+        }
 
-            if let Some(d) = table.get_dir(dir) {
-                table_obj =
-                    FileTableSector::load(d.entry_addr, self.drive_index as usize, Some(d.name));
-                table = &mut table_obj;
-            } else {
-                return ExitCode::NotFoundError;
+        // The entry sector is written even if the file is empty, and has less room as it holds the timestamp
+        let (first_chunk, rest) = bytes.split_at(bytes.len().min(ENTRY_SECTOR_CAPACITY));
+        let parent = &path[..path.len() - 1];
+        let sector_count = 1 + rest.chunks(DATA_SECTOR_CAPACITY).count();
+        let mut sectors = match self.reserve_sectors(parent, sector_count) {
+            Ok(sectors) => sectors.into_iter(),
+            Err(error_code) => return error_code,
+        };
+
+        // Write the data before linking to it, so the entry never points at an incomplete file
+        let new_file_sector = sectors.next().unwrap();
+        if let Err(error_code) = self.write_data(new_file_sector, &mut sectors, first_chunk, rest) {
+            return error_code;
+        }
+
+        let linked = self
+            .table_with_space(parent, sectors.next())
+            .and_then(|mut tables| {
+                tables[0].add_file(&path[path.len() - 1], new_file_sector);
+                self.journal().write_tables(&tables)
+            });
+        self.reload_entry_table();
+
+        linked.map_or_else(|error_code| error_code, |_| ExitCode::Success)
+    }
+
+    /// Write the data sectors of a new file, starting with its entry sector, taking the rest from the given sectors.
+    /// Nothing links to them yet, so if a sector can't be written the file is just left unfinished.
+    fn write_data(
+        &self,
+        entry_sector: u32,
+        sectors: &mut impl Iterator<Item = u32>,
+        first_chunk: &[u8],
+        rest: &[u8],
+    ) -> Result<(), ExitCode> {
+        let drive_index = self.drive_index as usize;
+        let modified = DateTime::get().to_unix() as u32;
+        let mut current_sector =
+            DataSector::new_entry(entry_sector, drive_index, first_chunk.to_vec(), modified)?;
+
+        for chunk in rest.chunks(DATA_SECTOR_CAPACITY) {
+            let extension_file_sector = sectors.next().unwrap();
+            current_sector.continuation_addr = Some(extension_file_sector);
+            current_sector.update_physical_drive()?;
+            current_sector = DataSector::new(extension_file_sector, drive_index, chunk.to_vec())?;
+        }
+
+        Ok(())
+    }
+
+    /// Add bytes to the end of the file at the given path, creating it if it doesn't exist.
+    /// Compressed files are rewritten compressed, but encrypted files can't be appended to without the passphrase,
+    /// so give `EncryptedError`.
+    /// The file is rewritten, so if there isn't room for another copy of it, returns `DiskFullError` without
+    /// changing anything.
+    pub fn append_file(&mut self, path: &Vec<String>, bytes: Vec<u8>) -> ExitCode {
+        let existing = self.get_file(path);
+        let (mut contents, compress) = match &existing {
+            Some(file) if file.is_read_only() => return ExitCode::PermissionError,
+            Some(file) => match file.read() {
+                Ok(contents) => (contents, file.is_compressed()),
+                Err(error_code) => return error_code,
+            },
+            None => (Vec::new(), false),
+        };
+        contents.extend(bytes);
+
+        // Deleting the old copy can't be undone, so make sure the new one fits first, allowing for a new table
+        let data_bytes = contents.len().saturating_sub(ENTRY_SECTOR_CAPACITY);
+        let needed_sectors = 2 + (data_bytes + DATA_SECTOR_CAPACITY - 1) / DATA_SECTOR_CAPACITY;
+        match self.free_sectors() {
+            Ok(free_sectors) if needed_sectors > free_sectors as usize => {
+                return ExitCode::DiskFullError
             }
+            Ok(_) => (),
+            Err(error_code) => return error_code,
         }
 
-        let main_dir_name = table.directory_name.clone();
+        if existing.is_some() {
+            match self.delete_file(path) {
+                ExitCode::Success => (),
+                error_code => return error_code,
+            }
+        }
 
-        while table.files.len() == 8 {
-            if let Some(new_addr) = table.continuation_addr {
-                table_obj = FileTableSector::load(
-                    new_addr,
-                    self.drive_index as usize,
-                    main_dir_name.clone(),
-                );
-                table = &mut table_obj;
-            } else {
-                let drives = ata::DRIVES.lock();
-                let drive = &drives[self.drive_index as usize];
-                let new_sector = drive.find_available_sector().unwrap();
-                drop(drives);
+        self.write_file_with(path, contents, compress, None)
+    }
+
+    /// Replace the contents of the file at the given path, creating it if it doesn't exist.
+    /// The new contents are written under a temporary name and only swapped in once they're complete, so if they
+    /// can't be written the old file is left as it was.
+    /// Compressed files stay compressed and hidden files stay hidden, but encrypted files can't be rewritten without
+    /// the passphrase, so give `EncryptedError`.
+    pub fn replace_file(&mut self, path: &Vec<String>, bytes: Vec<u8>) -> ExitCode {
+        let (name, dir_path) = match path.split_last() {
+            Some(split) => split,
+            None => return ExitCode::NotFoundError,
+        };
+        let existing = match self.get_file(path) {
+            Some(file) if file.is_read_only() => return ExitCode::PermissionError,
+            Some(file) if file.is_encrypted() => return ExitCode::EncryptedError,
+            Some(file) => file,
+            None => return self.write_file(path, bytes),
+        };
 
-                table.set_continuation(new_sector);
-                table_obj = FileTableSector::new(
-                    new_sector,
-                    self.drive_index as usize,
-                    main_dir_name.clone(),
-                );
-                table = &mut table_obj;
+        let mut temp_path = dir_path.to_vec();
+        temp_path.push(
+            (0..)
+                .map(|i| format!("~replacing{}", i))
+                .find(|temp_name| {
+                    let mut candidate = dir_path.to_vec();
+                    candidate.push(temp_name.clone());
+                    self.get_file(&candidate).is_none() && self.get_dir(&candidate).is_none()
+                })
+                .unwrap(),
+        );
+
+        match self.write_file_with(&temp_path, bytes, existing.is_compressed(), None) {
+            ExitCode::Success => (),
+            error_code => {
+                self.delete_file(&temp_path);
+                return error_code;
+            }
+        }
+        match self.delete_file(path) {
+            ExitCode::Success => (),
+            error_code => {
+                self.delete_file(&temp_path);
+                return error_code;
             }
         }
 
-        let drives = ata::DRIVES.lock();
-        let new_file_sector = drives[self.drive_index as usize]
-            .find_available_sector()
-            .unwrap();
+        match self.rename(&temp_path, name) {
+            ExitCode::Success if existing.is_hidden() => self.set_flags(path, FLAG_HIDDEN, true),
+            exit_code => exit_code,
+        }
+    }
 
-        drop(drives);
+    /// Copy a file to another path on this filesystem.
+    /// If the destination is a directory, the file is copied into it with the same name.
+    pub fn copy_file(&mut self, from: &Vec<String>, to: &Vec<String>) -> ExitCode {
+        match self.get_file(from) {
+            Some(file) => self.write_copy(&file, to),
+            None => ExitCode::NotFoundError,
+        }
+    }
 
-        table.add_file(&path[path.len() - 1], new_file_sector);
+    /// Write a copy of a file, which may be on another drive, to the given path on this filesystem.
+    /// The data is copied as it is stored, so compressed and encrypted files stay that way.
+    /// Any file already at the path is replaced, unless it is read-only.
+    fn write_copy(&mut self, file: &File, to: &Vec<String>) -> ExitCode {
+        let mut to = to.clone();
+        if to.is_empty() || self.get_dir(&to).is_some() {
+            to.push(file.name.clone());
+        }
 
-        let drives = ata::DRIVES.lock();
-        let drive = &drives[self.drive_index as usize];
+        // Read the file before replacing anything, in case it is being copied onto itself
+        let bytes = file.read_stored();
+        if self.get_file(&to).is_some() {
+            match self.delete_file(&to) {
+                ExitCode::Success => (),
+                error_code => return error_code,
+            }
+        }
 
-        let mut bytes_to_write = bytes.clone();
-        bytes_to_write.truncate(506);
-        let mut written_bytes = bytes_to_write.len();
-        let mut current_sector = DataSector::new(new_file_sector, drive, bytes_to_write);
+        let flags = file.flags & (FLAG_ENCRYPTED | FLAG_COMPRESSED);
+        match self.write_file(&to, bytes) {
+            ExitCode::Success if flags != 0 => self.set_flags(&to, flags, true),
+            error_code => error_code,
+        }
+    }
 
-        while written_bytes < bytes.len() {
-            bytes_to_write = bytes.clone();
-            bytes_to_write.drain(..written_bytes);
-            bytes_to_write.truncate(506);
-            let extension_file_sector = drive.find_available_sector().unwrap();
-            current_sector.continuation_addr = Some(extension_file_sector);
-            current_sector.update_physical_drive(drive);
-            written_bytes += bytes_to_write.len();
-            current_sector = DataSector::new(extension_file_sector, drive, bytes_to_write);
+    /// Write a file to the given path with its data scrambled using the given passphrase.
+    /// This only stops the data being read by accident, as it isn't real encryption.
+    pub fn write_file_encrypted(
+        &mut self,
+        path: &Vec<String>,
+        bytes: Vec<u8>,
+        passphrase: &str,
+    ) -> ExitCode {
+        self.write_file_with(path, bytes, false, Some(passphrase))
+    }
+
+    /// Write a file to the given path with its data run-length encoded.
+    /// If that wouldn't make the data smaller, it is stored as it is instead.
+    pub fn write_file_compressed(&mut self, path: &Vec<String>, bytes: Vec<u8>) -> ExitCode {
+        self.write_file_with(path, bytes, true, None)
+    }
+
+    /// Write a file to the given path, transforming the data first.
+    /// If `compress` is set, the data is run-length encoded if that makes it smaller,
+    /// then if a passphrase is given, the data is scrambled with it.
+    /// The file's flags record which transforms were applied, so reading it can undo them.
+    pub fn write_file_with(
+        &mut self,
+        path: &Vec<String>,
+        mut bytes: Vec<u8>,
+        compress: bool,
+        passphrase: Option<&str>,
+    ) -> ExitCode {
+        let mut flags = 0;
+
+        if compress {
+            let compressed = rle_compress(&bytes);
+            if compressed.len() < bytes.len() {
+                bytes = compressed;
+                flags |= FLAG_COMPRESSED;
+            }
+        }
+        if let Some(passphrase) = passphrase {
+            xor_with_passphrase(&mut bytes, passphrase);
+            flags |= FLAG_ENCRYPTED;
         }
 
-        ExitCode::Success
+        match self.write_file(path, bytes) {
+            ExitCode::Success if flags != 0 => self.set_flags(path, flags, true),
+            error_code => error_code,
+        }
     }
 
     /// Create a directory at the given path.
+    /// If there isn't enough space, returns `DiskFullError` without writing anything.
     pub fn create_dir(&mut self, path: &Vec<String>) -> ExitCode {
-        let mut table_obj: FileTableSector;
-        let mut table = &mut self.entry_table;
-
-        for dir in &path[..path.len() - 1] {
-            // Iterate over the tables representing the dir
-            while table.get_dir(dir).is_none() {
-                if let Some(new_addr) = table.continuation_addr {
-                    table_obj = FileTableSector::load(
-                        new_addr,
-                        self.drive_index as usize,
-                        table.directory_name.clone(),
-                    );
-                    table = &mut table_obj;
-                } else {
-                    return ExitCode::NotFoundError;
-                }
-            }
+        if path.is_empty() {
+            return ExitCode::NotFoundError;
+        }
 
-            if let Some(d) = table.get_dir(dir) {
-                table_obj =
-                    FileTableSector::load(d.entry_addr, self.drive_index as usize, Some(d.name));
-                table = &mut table_obj;
-            } else {
-                return ExitCode::NotFoundError;
+        let parent = &path[..path.len() - 1];
+        let mut sectors = match self.reserve_sectors(parent, 1) {
+            Ok(sectors) => sectors.into_iter(),
+            Err(error_code) => return error_code,
+        };
+
+        // Write the directory's empty table before linking to it, so the entry never points at an unused sector
+        let name = &path[path.len() - 1];
+        let new_table = FileTableSector::empty(
+            sectors.next().unwrap(),
+            self.drive_index as usize,
+            Some(name.clone()),
+        );
+
+        let linked = self
+            .table_with_space(parent, sectors.next())
+            .and_then(|mut tables| {
+                tables[0].add_dir(name, new_table.addr);
+                tables.insert(0, new_table);
+                self.journal().write_tables(&tables)
+            });
+        self.reload_entry_table();
+
+        linked.map_or_else(|error_code| error_code, |_| ExitCode::Success)
+    }
+
+    /// List the files and directories at a given path in alphabetical order, including hidden ones.
+    pub fn list_entries(&self, path: &Vec<String>) -> Option<Vec<FileType>> {
+        self.list_files_sorted(path, SortMode::Name, false, true)
+    }
+
+    /// List the names of the files at a given path in alphabetical order, including hidden ones.
+    /// Directory names end with a `/`.
+    pub fn list_files(&self, path: &Vec<String>) -> Option<Vec<String>> {
+        Some(
+            self.list_entries(path)?
+                .iter()
+                .map(|object| object.to_string())
+                .collect(),
+        )
+    }
+
+    /// List the files and directories at a given path, sorted according to the given mode.
+    /// If `dirs_first` is set, directories are grouped before files.
+    /// Hidden files and directories are only included if `show_hidden` is set.
+    /// The sort is stable, so entries which compare equal keep their on-disk order.
+    pub fn list_files_sorted(
+        &self,
+        path: &Vec<String>,
+        mode: SortMode,
+        dirs_first: bool,
+        show_hidden: bool,
+    ) -> Option<Vec<FileType>> {
+        let mut objects = self.list_objects(path)?;
+
+        if !show_hidden {
+            objects.retain(|object| match object {
+                FileType::File(f) => !f.is_hidden(),
+                FileType::Dir(d) => !d.is_hidden(),
+            });
+        }
+
+        match mode {
+            SortMode::Name => objects.sort_by(|a, b| a.name().cmp(b.name())),
+            SortMode::NameDesc => objects.sort_by(|a, b| b.name().cmp(a.name())),
+            SortMode::Size => {
+                objects.sort_by(|a, b| a.name().cmp(b.name()));
+                objects.sort_by_cached_key(|object| match object {
+                    FileType::File(f) => Reverse(f.size()),
+                    FileType::Dir(_) => Reverse(0),
+                });
             }
         }
 
-        let main_dir_name = table.directory_name.clone();
+        if dirs_first {
+            objects.sort_by_key(|object| !object.is_dir());
+        }
 
-        while table.files.len() == 8 {
-            if let Some(new_addr) = table.continuation_addr {
-                table_obj = FileTableSector::load(
-                    new_addr,
-                    self.drive_index as usize,
-                    main_dir_name.clone(),
-                );
-                table = &mut table_obj;
-            } else {
-                let drives = ata::DRIVES.lock();
-                let drive = &drives[self.drive_index as usize];
-                let new_sector = drive.find_available_sector().unwrap();
-                drop(drives);
+        Some(objects)
+    }
+
+    /// List the objects at a given path in on-disk order.
+    fn list_objects(&self, path: &Vec<String>) -> Option<Vec<FileType>> {
+        Some(self.dir_entries(path)?.collect())
+    }
+
+    /// Permanently delete a file from the disk.
+    pub fn delete_file(&mut self, path: &Vec<String>) -> ExitCode {
+        if let Some(file) = self.get_file(path) {
+            if file.is_read_only() {
+                return ExitCode::PermissionError;
+            }
+
+            let sectors_to_remove = file.sectors();
+            let mut file_table_sector = self.get_table_with_object(path).unwrap();
+            let remove_index = file_table_sector
+                .files
+                .iter()
+                .position(|ft| !ft.is_dir() && ft.entry_addr() == file.entry_addr)
+                .unwrap();
+
+            // Unlink the file before freeing its sectors, so the table never points at a freed sector
+            file_table_sector.files.remove(remove_index);
+            let updated = self.journal().write_tables(&[file_table_sector]);
+            if updated.is_ok() {
+                for addr in sectors_to_remove {
+                    DataSector::load(addr, self.drive_index as usize).remove();
+                }
+            }
+
+            self.reload_entry_table();
+
+            updated.map_or_else(|error_code| error_code, |_| ExitCode::Success)
+        } else {
+            ExitCode::NotFoundError
+        }
+    }
 
-                table.set_continuation(new_sector);
-                table_obj = FileTableSector::new(
-                    new_sector,
-                    self.drive_index as usize,
-                    main_dir_name.clone(),
-                );
-                table = &mut table_obj;
+    /// Delete a file, keeping its data so it can be restored with `undelete_file` until the space is needed.
+    /// Older versions would list soft deleted files as normal ones, so older disks are marked with the latest
+    /// version first, except for disks without a superblock which give `UnsupportedFilesystemError`.
+    pub fn soft_delete_file(&mut self, path: &Vec<String>) -> ExitCode {
+        if let Some(file) = self.get_file(path) {
+            if file.is_read_only() {
+                return ExitCode::PermissionError;
+            }
+
+            if self.superblock.version == 0 {
+                return ExitCode::UnsupportedFilesystemError;
+            } else if self.superblock.version < FORMAT_VERSION {
+                self.superblock.version = FORMAT_VERSION;
+                if let Err(error_code) = self
+                    .superblock
+                    .update_physical_drive(self.drive_index as usize)
+                {
+                    return error_code;
+                }
             }
+
+            let mut file_table_sector = self.get_table_with_object(path).unwrap();
+            let remove_index = file_table_sector
+                .files
+                .iter()
+                .position(|ft| !ft.is_dir() && ft.entry_addr() == file.entry_addr)
+                .unwrap();
+
+            file_table_sector.files.remove(remove_index);
+            file_table_sector.deleted.push(File {
+                flags: file.flags | FLAG_DELETED,
+                ..file
+            });
+            let updated = self.journal().write_tables(&[file_table_sector]);
+
+            self.reload_entry_table();
+
+            updated.map_or_else(|error_code| error_code, |_| ExitCode::Success)
+        } else {
+            ExitCode::NotFoundError
         }
+    }
 
-        let drives = ata::DRIVES.lock();
-        let new_file_sector = drives[self.drive_index as usize]
-            .find_available_sector()
+    /// Restore a file deleted with `soft_delete_file`, if its sectors haven't been reused since.
+    /// If several deleted files had the same name, the most recently deleted is restored.
+    /// Returns `AlreadyExistsError` if something else now has the file's name.
+    pub fn undelete_file(&mut self, path: &Vec<String>) -> ExitCode {
+        let (name, dir_path) = match path.split_last() {
+            Some(split) => split,
+            None => return ExitCode::NotFoundError,
+        };
+        if self.get_file(path).is_some() || self.get_dir(path).is_some() {
+            return ExitCode::AlreadyExistsError;
+        }
+
+        let mut table = match self.dir_tables(dir_path).and_then(|tables| {
+            tables
+                .filter(|table| table.deleted.iter().any(|file| &file.name == name))
+                .last()
+        }) {
+            Some(table) => table,
+            None => return ExitCode::NotFoundError,
+        };
+
+        let index = table
+            .deleted
+            .iter()
+            .rposition(|file| &file.name == name)
             .unwrap();
+        let file = table.deleted.remove(index);
 
-        drop(drives);
+        // Purging frees the sectors along with the entry, but check they weren't written over some other way
+        let intact = file.sectors().iter().all(|addr| {
+            let mut buf = [0_u8; 512];
+            with_drive(self.drive_index as usize, |drive| {
+                drive.read(*addr, &mut buf)
+            })
+            .is_ok()
+                && buf.iter().any(|byte| *byte != 0)
+        });
+        if !intact {
+            return ExitCode::NotFoundError;
+        }
 
-        table.add_dir(&path[path.len() - 1], new_file_sector);
-        FileTableSector::new(new_file_sector, self.drive_index as usize, None);
+        table.files.push(FileType::File(File {
+            flags: file.flags & !FLAG_DELETED,
+            ..file
+        }));
+        let updated = self.journal().write_tables(&[table]);
 
-        ExitCode::Success
+        self.reload_entry_table();
+
+        updated.map_or_else(|error_code| error_code, |_| ExitCode::Success)
     }
 
-    /// List the files at a given path.
-    pub fn list_files(&self, path: &Vec<String>) -> Option<Vec<String>> {
-        let mut result: Vec<String> = Vec::new();
-        let mut table = self.entry_table.clone();
+    /// List the files deleted from the directory at the given path which can still be restored, in on-disk order.
+    pub fn list_deleted(&self, path: &[String]) -> Option<Vec<File>> {
+        Some(
+            self.dir_tables(path)?
+                .flat_map(|table| table.deleted)
+                .collect(),
+        )
+    }
 
-        for dir in path {
-            // Iterate over the tables representing the dir
-            while table.get_dir(dir).is_none() {
-                if let Some(new_addr) = table.continuation_addr {
-                    table = FileTableSector::load(
-                        new_addr,
-                        self.drive_index as usize,
-                        table.directory_name,
-                    );
-                } else {
-                    return None;
+    /// Permanently delete an empty directory from the disk.
+    pub fn delete_dir(&mut self, path: &Vec<String>) -> ExitCode {
+        if let Some(dir) = self.get_dir(path) {
+            if dir.is_read_only() {
+                return ExitCode::PermissionError;
+            }
+
+            // Check every table of the directory, not just the entry one, as earlier tables may have been emptied
+            if self
+                .list_objects(path)
+                .map_or(false, |objects| !objects.is_empty())
+            {
+                return ExitCode::NotEmptyError;
+            }
+
+            let mut file_table_sector = self.get_table_with_object(path).unwrap();
+            let remove_index = file_table_sector
+                .files
+                .iter()
+                .position(|ft| ft.is_dir() && ft.entry_addr() == dir.entry_addr)
+                .unwrap();
+
+            // Unlink the directory before clearing its tables, so the entry never points at a cleared table
+            file_table_sector.files.remove(remove_index);
+            // Files deleted from the directory can't be restored without it, so their sectors are freed too
+            let mut tables = vec![file_table_sector];
+            let mut deleted_sectors = Vec::new();
+            for addr in dir.sectors() {
+                let mut table = match FileTableSector::load(addr, self.drive_index as usize, None) {
+                    Ok(table) => table,
+                    Err(error_code) => return error_code,
+                };
+                deleted_sectors.extend(table.deleted.iter().flat_map(|file| file.sectors()));
+                table.remove();
+                tables.push(table);
+            }
+            let updated = self.journal().write_tables(&tables);
+            if updated.is_ok() {
+                for addr in deleted_sectors {
+                    DataSector::load(addr, self.drive_index as usize).remove();
                 }
             }
 
-            if let Some(d) = table.get_dir(dir) {
-                table =
-                    FileTableSector::load(d.entry_addr, self.drive_index as usize, Some(d.name));
+            self.reload_entry_table();
+
+            updated.map_or_else(|error_code| error_code, |_| ExitCode::Success)
+        } else {
+            ExitCode::NotFoundError
+        }
+    }
+
+    /// Permanently delete a file or directory from the disk.
+    /// Directories must be empty unless `recursive` is set, in which case everything inside them is deleted first.
+    pub fn delete_object(&mut self, path: &Vec<String>, recursive: bool) -> ExitCode {
+        if self.get_file(path).is_some() {
+            return self.delete_file(path);
+        }
+        if self.get_dir(path).is_none() {
+            return ExitCode::NotFoundError;
+        }
+
+        if recursive {
+            for object in self.list_objects(path).unwrap_or_default() {
+                let mut object_path = path.clone();
+                object_path.push(object.name().to_owned());
+
+                match self.delete_object(&object_path, true) {
+                    ExitCode::Success => (),
+                    error_code => return error_code,
+                }
+            }
+        }
+
+        self.delete_dir(path)
+    }
+
+    /// Renames a file or directory.
+    pub fn rename(&mut self, path: &Vec<String>, new_name: &str) -> ExitCode {
+        if path.is_empty() {
+            return ExitCode::NotFoundError;
+        }
+
+        let old_name = path[path.len() - 1].clone();
+        if let Some(mut table) = self.get_table_with_object(path) {
+            // Find the object
+            let object = table
+                .files
+                .iter_mut()
+                .find(|object| object.name() == old_name)
+                .unwrap();
+
+            let flags = match object {
+                FileType::File(f) => f.flags,
+                FileType::Dir(d) => d.flags,
+            };
+            if flags & FLAG_READ_ONLY != 0 {
+                return ExitCode::PermissionError;
+            }
+
+            // Rename the object
+            match object {
+                FileType::File(f) => f.name = new_name.to_string(),
+                FileType::Dir(d) => d.name = new_name.to_string(),
+            }
+
+            // Update physical disk with virtual changes
+            let updated = table.update_physical_drive();
+
+            // Update entry sector in case file was stored in it
+            self.reload_entry_table();
+
+            updated.map_or_else(|error_code| error_code, |_| ExitCode::Success)
+        } else {
+            ExitCode::NotFoundError
+        }
+    }
+
+    /// Sets or clears the given flags on a file or directory.
+    /// This is permitted on read-only objects so that the read-only flag can be cleared.
+    pub fn set_flags(&mut self, path: &Vec<String>, flags: u8, enabled: bool) -> ExitCode {
+        if path.is_empty() {
+            return ExitCode::NotFoundError;
+        }
+
+        let name = path[path.len() - 1].clone();
+        if let Some(mut table) = self.get_table_with_object(path) {
+            let object = table
+                .files
+                .iter_mut()
+                .find(|object| object.name() == name)
+                .unwrap();
+
+            let object_flags = match object {
+                FileType::File(f) => &mut f.flags,
+                FileType::Dir(d) => &mut d.flags,
+            };
+            if enabled {
+                *object_flags |= flags & !FLAG_DIR;
             } else {
-                return None;
+                *object_flags &= !flags;
             }
+
+            let updated = table.update_physical_drive();
+
+            self.reload_entry_table();
+
+            updated.map_or_else(|error_code| error_code, |_| ExitCode::Success)
+        } else {
+            ExitCode::NotFoundError
         }
+    }
+}
 
-        result.extend(table.files.iter().map(|f| match f {
-            FileType::File(f) => f.name.clone(),
-            FileType::Dir(d) => format!("{}/", d.name),
-        }));
+/// Magic number at the start of a superblock.
+const SUPERBLOCK_MAGIC: [u8; 4] = [b'P', b'G', b'F', b'S'];
+/// Latest version of the on-disk format which can be read.
+/// Version 0 is used for disks from before superblocks existed, version 1 for disks without reserved sectors,
+/// and version 2 for disks without soft deleted files.
+pub const FORMAT_VERSION: u16 = 3;
 
-        if table.continuation_addr.is_some() {
-            let mut next_addr = table.continuation_addr.unwrap();
-            loop {
-                let table = FileTableSector::load(
-                    next_addr,
-                    self.drive_index as usize,
-                    table.directory_name.clone(),
-                );
-                result.extend(table.files.iter().map(|f| match f {
-                    FileType::File(f) => f.name.clone(),
-                    FileType::Dir(d) => format!("{}/", d.name),
-                }));
-                if table.continuation_addr.is_some() {
-                    next_addr = table.continuation_addr.unwrap();
+/// Number of sectors reserved for metadata at the end of new filesystems, including the superblock.
+pub const DEFAULT_RESERVED_SECTORS: u32 = 8;
+
+/// Represents the superblock in the last sector of the disk, which describes the filesystem.
+#[derive(Clone, Debug)]
+pub struct Superblock {
+    pub version: u16,
+    pub sector_size: u16,
+    pub total_sectors: u32,
+    pub root_addr: u32,
+    /// Number of sectors at the end of the filesystem reserved for metadata, ending with the superblock.
+    /// These are never used for files or directories.
+    pub reserved_sectors: u32,
+    /// Whether the filesystem should be the current one after booting, when several are mounted.
+    pub is_default: bool,
+}
+
+impl Superblock {
+    /// Describe a new filesystem on a drive with the given number of sectors,
+    /// reserving the given number of sectors at the end for metadata.
+    /// The root directory is placed in the sector before the reserved sectors.
+    pub fn new(total_sectors: u32, reserved_sectors: u32) -> Self {
+        Superblock {
+            version: FORMAT_VERSION,
+            sector_size: block::SECTOR_SIZE as u16,
+            total_sectors,
+            root_addr: total_sectors - reserved_sectors - 1,
+            reserved_sectors,
+            is_default: false,
+        }
+    }
+
+    /// Get the first sector reserved for metadata, which every sector used for files and directories comes before.
+    pub fn reserved_start(&self) -> u32 {
+        self.total_sectors - self.reserved_sectors
+    }
+
+    /// Load the superblock from the last sector of the given drive.
+    /// Disks from before superblocks existed have their root directory in the last sector instead,
+    /// so they are described as version 0 with the root directory there.
+    pub fn load(drive_index: usize) -> Result<Self, ExitCode> {
+        let mut buf = [0_u8; 512];
+        let total_sectors = block::with_device(drive_index, |drive| {
+            drive.read(drive.sectors() - 1, &mut buf)?;
+            Ok(drive.sectors())
+        })
+        .ok_or(ExitCode::NotFoundError)??;
+
+        if buf[0..4] == SUPERBLOCK_MAGIC {
+            let version = (buf[4] as u16) << 8 | (buf[5] as u16);
+            let superblock = Superblock {
+                version,
+                sector_size: (buf[6] as u16) << 8 | (buf[7] as u16),
+                total_sectors: (buf[8] as u32) << 24
+                    | (buf[9] as u32) << 16
+                    | (buf[10] as u32) << 8
+                    | (buf[11] as u32),
+                root_addr: (buf[12] as u32) << 24
+                    | (buf[13] as u32) << 16
+                    | (buf[14] as u32) << 8
+                    | (buf[15] as u32),
+                // Version 1 only reserved the superblock itself
+                reserved_sectors: if version < 2 {
+                    1
                 } else {
-                    break;
-                }
+                    (buf[16] as u32) << 24
+                        | (buf[17] as u32) << 16
+                        | (buf[18] as u32) << 8
+                        | (buf[19] as u32)
+                },
+                // The flags byte was reserved, so always zero, in version 1
+                is_default: buf[20].get_bit(0),
+            };
+
+            if superblock.version > FORMAT_VERSION
+                || superblock.sector_size as usize != block::SECTOR_SIZE
+                || superblock.total_sectors > total_sectors
+                || superblock.reserved_sectors == 0
+                || superblock.reserved_sectors >= superblock.total_sectors
+            {
+                return Err(ExitCode::UnsupportedFilesystemError);
             }
-        };
 
-        Some(result)
+            Ok(superblock)
+        } else if buf[508..512] == FILESYSTEM_SIGNATURE {
+            Ok(Superblock {
+                version: 0,
+                sector_size: block::SECTOR_SIZE as u16,
+                total_sectors,
+                root_addr: total_sectors - 1,
+                reserved_sectors: 0,
+                is_default: false,
+            })
+        } else {
+            Err(ExitCode::NoFilesystemError)
+        }
     }
 
-    /// Permanently delete a file from the disk.
-    pub fn delete_file(&mut self, path: &Vec<String>) -> ExitCode {
-        if let Some(file) = self.get_file(path) {
-            let drives = ata::DRIVES.lock();
-            let drive = &drives[self.drive_index as usize];
-            let mut current_sector = DataSector::load(file.entry_addr, drive);
-            let mut sectors_to_remove: Vec<DataSector> = Vec::new();
-
-            loop {
-                sectors_to_remove.push(current_sector.clone());
-                if let Some(new_addr) = current_sector.continuation_addr {
-                    current_sector = DataSector::load(new_addr, drive);
-                } else {
-                    break;
-                }
+    /// Write the superblock to the last sector of the given drive, reading it back to check it was stored.
+    /// This is the drive's last sector rather than the filesystem's, as that is where `load` looks for it.
+    /// Returns `VerifyError` if it wasn't.
+    pub fn update_physical_drive(&self, drive_index: usize) -> Result<(), ExitCode> {
+        let mut buf = [0_u8; 512];
+
+        buf[0..4].copy_from_slice(&SUPERBLOCK_MAGIC);
+        buf[4] = self.version.get_bits(8..16) as u8;
+        buf[5] = self.version.get_bits(0..8) as u8;
+        buf[6] = self.sector_size.get_bits(8..16) as u8;
+        buf[7] = self.sector_size.get_bits(0..8) as u8;
+        buf[8] = self.total_sectors.get_bits(24..32) as u8;
+        buf[9] = self.total_sectors.get_bits(16..24) as u8;
+        buf[10] = self.total_sectors.get_bits(8..16) as u8;
+        buf[11] = self.total_sectors.get_bits(0..8) as u8;
+        buf[12] = self.root_addr.get_bits(24..32) as u8;
+        buf[13] = self.root_addr.get_bits(16..24) as u8;
+        buf[14] = self.root_addr.get_bits(8..16) as u8;
+        buf[15] = self.root_addr.get_bits(0..8) as u8;
+        buf[16] = self.reserved_sectors.get_bits(24..32) as u8;
+        buf[17] = self.reserved_sectors.get_bits(16..24) as u8;
+        buf[18] = self.reserved_sectors.get_bits(8..16) as u8;
+        buf[19] = self.reserved_sectors.get_bits(0..8) as u8;
+        buf[20].set_bit(0, self.is_default);
+
+        with_drive(drive_index, |drive| {
+            drive.write_verified(drive.sectors() - 1, &buf)
+        })
+    }
+}
+
+/// Magic number at the start of a journal header.
+const JOURNAL_MAGIC: [u8; 4] = [b'P', b'J', b'N', b'L'];
+/// Most tables a journal can record, as each one's address takes four bytes of the header.
+const MAX_JOURNAL_TABLES: usize = 126;
+
+/// Represents the journal in the reserved sectors, which records table updates before they are made
+/// so that an update interrupted by a reset or crash can be finished when the filesystem is next mounted.
+/// The first reserved sector holds the header, listing where each table goes, and the sectors after it hold
+/// copies of the tables. Filesystems with fewer than three reserved sectors have no room for a journal.
+pub struct Journal {
+    drive_index: usize,
+    header_addr: u32,
+    capacity: usize,
+}
+
+impl Journal {
+    /// Get the journal of the filesystem described by the given superblock.
+    pub fn new(superblock: &Superblock, drive_index: usize) -> Self {
+        Journal {
+            drive_index,
+            header_addr: superblock.reserved_start(),
+            // The header and superblock take up two of the reserved sectors
+            capacity: (superblock.reserved_sectors.saturating_sub(2) as usize)
+                .min(MAX_JOURNAL_TABLES),
+        }
+    }
+
+    /// Write the given tables in order, recording them in the journal first so that either all of them are
+    /// written or, if interrupted before the journal was complete, none of them are.
+    /// If there are more tables than the journal can hold, they're written directly instead.
+    /// Returns `VerifyError` if a sector couldn't be written.
+    pub fn write_tables(&self, tables: &[FileTableSector]) -> Result<(), ExitCode> {
+        if tables.len() > self.capacity {
+            return tables
+                .iter()
+                .try_for_each(|table| table.update_physical_drive());
+        }
+
+        self.record(tables)?;
+        tables
+            .iter()
+            .try_for_each(|table| table.update_physical_drive())?;
+        self.clear()
+    }
+
+    /// Record the given tables in the journal without writing them to where they belong.
+    /// The header is written last, so the record only counts once every table has been copied.
+    pub fn record(&self, tables: &[FileTableSector]) -> Result<(), ExitCode> {
+        assert!(tables.len() <= self.capacity);
+        let mut header = [0_u8; 512];
+        header[0..4].copy_from_slice(&JOURNAL_MAGIC);
+        header[4] = (tables.len() as u16).get_bits(8..16) as u8;
+        header[5] = (tables.len() as u16).get_bits(0..8) as u8;
+
+        for (i, table) in tables.iter().enumerate() {
+            with_drive(self.drive_index, |drive| {
+                drive.write_verified(self.header_addr + 1 + i as u32, &table.to_bytes())
+            })?;
+
+            let index = 6 + i * 4;
+            header[index] = table.addr.get_bits(24..32) as u8;
+            header[index + 1] = table.addr.get_bits(16..24) as u8;
+            header[index + 2] = table.addr.get_bits(8..16) as u8;
+            header[index + 3] = table.addr.get_bits(0..8) as u8;
+        }
+
+        let checksum = crc16(&header[0..510]);
+        header[510] = checksum.get_bits(8..16) as u8;
+        header[511] = checksum.get_bits(0..8) as u8;
+
+        with_drive(self.drive_index, |drive| {
+            drive.write_verified(self.header_addr, &header)
+        })
+    }
+
+    /// Finish any update recorded in the journal by writing its tables again, then clear the journal.
+    /// A header which is incomplete (e.g. interrupted while being written) is discarded, as none of its tables
+    /// were written yet. Returns whether an update was finished.
+    pub fn replay(&self) -> Result<bool, ExitCode> {
+        if self.capacity == 0 {
+            return Ok(false);
+        }
+
+        let mut header = [0_u8; 512];
+        with_drive(self.drive_index, |drive| {
+            drive.read(self.header_addr, &mut header)
+        })?;
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(false);
+        }
+
+        let count = ((header[4] as u16) << 8 | (header[5] as u16)) as usize;
+        let complete = header[0..4] == JOURNAL_MAGIC
+            && count <= self.capacity
+            && crc16(&header[0..510]) == (header[510] as u16) << 8 | (header[511] as u16);
+
+        if complete {
+            for i in 0..count {
+                let index = 6 + i * 4;
+                let addr = (header[index] as u32) << 24
+                    | (header[index + 1] as u32) << 16
+                    | (header[index + 2] as u32) << 8
+                    | (header[index + 3] as u32);
+
+                let mut buf = [0_u8; 512];
+                with_drive(self.drive_index, |drive| {
+                    drive.read(self.header_addr + 1 + i as u32, &mut buf)?;
+                    drive.write_verified(addr, &buf)
+                })?;
+            }
+        }
+
+        self.clear()?;
+        Ok(complete)
+    }
+
+    /// Clear the journal's header, so that nothing is replayed.
+    fn clear(&self) -> Result<(), ExitCode> {
+        with_drive(self.drive_index, |drive| {
+            drive.write_verified(self.header_addr, &[0_u8; 512])
+        })
+    }
+}
+
+/// Abstract struct representing a file, not connected in any way to disk
+#[derive(Clone, Debug)]
+pub struct File {
+    pub name: String,
+    pub drive_index: usize,
+    pub entry_addr: u32,
+    pub flags: u8,
+}
+
+impl File {
+    /// Checks if the file is read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.flags & FLAG_READ_ONLY != 0
+    }
+
+    /// Checks if the file is hidden.
+    pub fn is_hidden(&self) -> bool {
+        self.flags & FLAG_HIDDEN != 0
+    }
+
+    /// Checks if the file's data is scrambled with a passphrase.
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// Checks if the file's data is run-length encoded.
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    /// Read bytes from the file, unscrambling them with the given passphrase if the file is encrypted.
+    /// A wrong passphrase isn't detected, it just gives the wrong bytes.
+    pub fn read_with_passphrase(&self, passphrase: &str) -> Vec<u8> {
+        let mut bytes = self.read_stored();
+        if self.is_encrypted() {
+            xor_with_passphrase(&mut bytes, passphrase);
+        }
+        self.decompress(bytes)
+    }
+
+    /// Read bytes from the file, decompressing them if the file is compressed.
+    /// Encrypted files need the passphrase, so give `EncryptedError` rather than scrambled bytes,
+    /// and should be read with `read_with_passphrase` instead.
+    /// Warns about any sectors which appear to be corrupted.
+    pub fn read(&self) -> Result<Vec<u8>, ExitCode> {
+        if self.is_encrypted() {
+            return Err(ExitCode::EncryptedError);
+        }
+        Ok(self.decompress(self.read_stored()))
+    }
+
+    /// Decompresses bytes read from the file if it is compressed, otherwise returns them unchanged.
+    fn decompress(&self, bytes: Vec<u8>) -> Vec<u8> {
+        if self.is_compressed() {
+            rle_decompress(&bytes)
+        } else {
+            bytes
+        }
+    }
+
+    /// Read the bytes stored on disk for the file, following the linked list.
+    /// Warns about any sectors which appear to be corrupted.
+    fn read_stored(&self) -> Vec<u8> {
+        let mut output_bytes: Vec<u8> = Vec::new();
+        let mut current_addr = self.entry_addr;
+        let mut current_sector = DataSector::load(current_addr, self.drive_index);
+        let mut guard = ChainGuard::new(current_addr);
+
+        loop {
+            if current_sector.is_corrupted {
+                warn(&format!(
+                    "sector {} of `{}` appears to be corrupted\n",
+                    current_sector.addr, self.name
+                ));
+            }
+            output_bytes.extend(
+                current_sector.data[0..(current_sector.size as usize).min(506)]
+                    .iter()
+                    .cloned(),
+            );
+            if let Some(next_sector) = guard.follow(current_sector.continuation_addr) {
+                current_addr = next_sector;
+                current_sector = DataSector::load(current_addr, self.drive_index);
+            } else {
+                break;
+            }
+        }
+
+        output_bytes
+    }
+
+    /// Get the addresses of every sector in the file, following the linked list.
+    pub fn sectors(&self) -> Vec<u32> {
+        let mut sectors: Vec<u32> = Vec::new();
+        let mut current_sector = DataSector::load(self.entry_addr, self.drive_index);
+        let mut guard = ChainGuard::new(self.entry_addr);
+
+        loop {
+            sectors.push(current_sector.addr);
+            if let Some(next_sector) = guard.follow(current_sector.continuation_addr) {
+                current_sector = DataSector::load(next_sector, self.drive_index);
+            } else {
+                break;
             }
+        }
+
+        sectors
+    }
+
+    /// Get when the file was last written.
+    /// Returns `None` for files written before timestamps were recorded.
+    pub fn modified(&self) -> Option<DateTime> {
+        DataSector::load(self.entry_addr, self.drive_index)
+            .modified
+            .map(|timestamp| DateTime::from_unix(timestamp as u64))
+    }
+
+    /// Get the size of the file in bytes once it is read, so compressed files give their decompressed size.
+    /// Encrypted files can't be decompressed without the passphrase, so give the size they are stored as.
+    pub fn size(&self) -> usize {
+        if self.is_compressed() && !self.is_encrypted() {
+            // Each pair of stored bytes is a count followed by the byte it repeats
+            self.read_stored()
+                .chunks_exact(2)
+                .map(|pair| pair[0] as usize)
+                .sum()
+        } else {
+            self.stored_size()
+        }
+    }
+
+    /// Get the number of bytes stored on disk for the file, following the linked list.
+    pub fn stored_size(&self) -> usize {
+        let mut size = 0;
+        let mut current_sector = DataSector::load(self.entry_addr, self.drive_index);
+        let mut guard = ChainGuard::new(self.entry_addr);
 
-            for mut sector in sectors_to_remove {
-                sector.remove(drive);
+        loop {
+            size += current_sector.size as usize;
+            if let Some(next_sector) = guard.follow(current_sector.continuation_addr) {
+                current_sector = DataSector::load(next_sector, self.drive_index);
+            } else {
+                break;
             }
+        }
+
+        size
+    }
+}
+
+/// Handle to an open file, which reads from a position that can be moved with `seek`.
+/// The sector containing the position is cached, so reading sequentially only loads each sector once,
+/// and seeking forwards continues along the linked list rather than starting again from the entry sector.
+/// Positions in compressed files don't line up with sectors, so they are decoded all at once instead.
+pub struct FileHandle {
+    file: File,
+    decoded: Option<Vec<u8>>,
+    position: usize,
+    sector: DataSector,
+    sector_start: usize,
+    guard: ChainGuard,
+}
+
+impl FileHandle {
+    fn new(file: File) -> Self {
+        Self {
+            sector: DataSector::load(file.entry_addr, file.drive_index),
+            sector_start: 0,
+            guard: ChainGuard::new(file.entry_addr),
+            decoded: Some(&file)
+                .filter(|file| file.is_compressed())
+                .map(|file| rle_decompress(&file.read_stored())),
+            position: 0,
+            file,
+        }
+    }
+
+    /// Get the file this handle refers to.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Get the position the next read will start from.
+    pub fn position(&self) -> usize {
+        self.position
+    }
 
-            drop(drives);
+    /// Move the position the next read will start from.
+    /// Seeking past the end of the file is allowed, but nothing can be read from there.
+    pub fn seek(&mut self, position: usize) {
+        self.position = position;
+    }
 
-            let mut file_table_sector = self.get_table_with_object(path).unwrap();
-            let remove_index = file_table_sector
-                .files
-                .iter()
-                .position(|ft| match ft {
-                    FileType::File(f) => f.entry_addr == file.entry_addr,
-                    FileType::Dir(_) => false,
-                })
-                .unwrap();
+    /// Read bytes from the current position into the buffer, advancing the position.
+    /// Returns the number of bytes read, which is only less than the buffer's length at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        if let Some(decoded) = &self.decoded {
+            let available = decoded.get(self.position..).unwrap_or(&[]);
+            let count = available.len().min(buf.len());
+            buf[..count].copy_from_slice(&available[..count]);
+            self.position += count;
+            return count;
+        }
 
-            file_table_sector.files.remove(remove_index);
-            file_table_sector.update_physical_drive();
+        let mut read_bytes = 0;
 
-            self.entry_table =
-                FileTableSector::load(self.entry_sector, self.drive_index as usize, None);
+        while read_bytes < buf.len() && self.load_sector_at(self.position) {
+            let offset = self.position - self.sector_start;
+            let available = &self.sector.data[offset..self.sector.size as usize];
+            let count = available.len().min(buf.len() - read_bytes);
 
-            ExitCode::Success
-        } else {
-            ExitCode::NotFoundError
+            buf[read_bytes..read_bytes + count].copy_from_slice(&available[..count]);
+            read_bytes += count;
+            self.position += count;
         }
-    }
 
-    /// Permanently delete an empty directory from the disk.
-    pub fn delete_dir(&mut self, path: &Vec<String>) -> ExitCode {
-        if let Some(dir) = self.get_dir(path) {
-            let mut current_sector =
-                FileTableSector::load(dir.entry_addr, self.drive_index as usize, None);
-            let mut sectors_to_remove: Vec<FileTableSector> = Vec::new();
+        read_bytes
+    }
 
-            if current_sector.files.len() != 0 {
-                return ExitCode::NotEmptyError;
-            }
+    /// Make the cached sector the one containing the given position, following the linked list from the
+    /// cached sector, or from the entry sector if the position is before it.
+    /// Returns false if the position is past the end of the file.
+    fn load_sector_at(&mut self, position: usize) -> bool {
+        if position < self.sector_start {
+            self.sector = DataSector::load(self.file.entry_addr, self.file.drive_index);
+            self.sector_start = 0;
+            self.guard = ChainGuard::new(self.file.entry_addr);
+        }
 
-            loop {
-                sectors_to_remove.push(current_sector.clone());
-                if let Some(new_addr) = current_sector.continuation_addr {
-                    current_sector =
-                        FileTableSector::load(new_addr, self.drive_index as usize, None);
-                } else {
-                    break;
+        while position >= self.sector_start + self.sector.size as usize {
+            match self.guard.follow(self.sector.continuation_addr) {
+                Some(next_addr) => {
+                    self.sector_start += self.sector.size as usize;
+                    self.sector = DataSector::load(next_addr, self.file.drive_index);
                 }
+                None => return false,
             }
+        }
 
-            for mut sector in sectors_to_remove {
-                sector.remove();
-            }
-
-            let mut file_table_sector = self.get_table_with_object(path).unwrap();
-            let remove_index = file_table_sector
-                .files
-                .iter()
-                .position(|ft| match ft {
-                    FileType::Dir(d) => d.entry_addr == dir.entry_addr,
-                    FileType::File(_) => false,
-                })
-                .unwrap();
+        true
+    }
+}
 
-            file_table_sector.files.remove(remove_index);
-            file_table_sector.update_physical_drive();
+/// Writer for formatted output to a file, so `write!` can be used like it is for the console.
+/// Output is buffered until the writer is flushed or dropped, and then written to the file on the given drive,
+/// or the current filesystem if no drive is given.
+/// The filesystem is locked while flushing, so it must not already be locked when a writer is dropped.
+pub struct FileWriter {
+    drive_index: Option<u8>,
+    path: Vec<String>,
+    buffer: Vec<u8>,
+    appending: bool,
+    dirty: bool,
+}
 
-            self.entry_table =
-                FileTableSector::load(self.entry_sector, self.drive_index as usize, None);
+impl FileWriter {
+    /// Create a writer which replaces the file at the given path, even if nothing is written to it.
+    pub fn new(drive_index: Option<u8>, path: Vec<String>) -> Self {
+        Self {
+            drive_index,
+            path,
+            buffer: Vec::new(),
+            appending: false,
+            dirty: true,
+        }
+    }
 
-            ExitCode::Success
-        } else {
-            ExitCode::NotFoundError
+    /// Create a writer which adds to the end of the file at the given path, creating it if it doesn't exist.
+    pub fn append(drive_index: Option<u8>, path: Vec<String>) -> Self {
+        Self {
+            drive_index,
+            path,
+            buffer: Vec::new(),
+            appending: true,
+            dirty: false,
         }
     }
 
-    /// Renames a file or directory.
-    pub fn rename(&mut self, path: &Vec<String>, new_name: &str) -> ExitCode {
-        let old_name = path[path.len() - 1].clone();
-        if let Some(mut table) = self.get_table_with_object(path) {
-            // Find the object
-            let object = table
-                .files
-                .iter_mut()
-                .find(|f| match f {
-                    FileType::File(f) => f.name == old_name,
-                    FileType::Dir(d) => d.name == old_name,
-                })
-                .unwrap();
+    /// Write the buffered output to the file.
+    /// A file being replaced is only removed once the output is written, so a failed flush leaves it as it was.
+    /// Later output is added to the end of the file, so flushing part way through doesn't lose anything.
+    pub fn flush(&mut self) -> ExitCode {
+        if !self.dirty {
+            return ExitCode::Success;
+        }
 
-            // Rename the object
-            match object {
-                FileType::File(f) => f.name = new_name.to_string(),
-                FileType::Dir(d) => d.name = new_name.to_string(),
-            }
+        let mut mounts = FILESYSTEM.lock();
+        let filesystem = match mounts.get_mut(self.drive_index) {
+            Some(filesystem) => filesystem,
+            None => return ExitCode::NotMountedError,
+        };
 
-            // Update physical disk with virtual changes
-            table.update_physical_drive();
+        let bytes = core::mem::take(&mut self.buffer);
+        let exit_code = if self.appending {
+            filesystem.append_file(&self.path, bytes)
+        } else {
+            filesystem.replace_file(&self.path, bytes)
+        };
 
-            // Update entry sector in case file was stored in it
-            self.entry_table =
-                FileTableSector::load(self.entry_sector, self.drive_index as usize, None);
+        self.appending = true;
+        self.dirty = false;
+        exit_code
+    }
+}
 
-            ExitCode::Success
-        } else {
-            ExitCode::NotFoundError
-        }
+impl core::fmt::Write for FileWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.buffer.extend_from_slice(s.as_bytes());
+        self.dirty = true;
+        Ok(())
     }
 }
 
-/// Abstract struct representing a file, not connected in any way to disk
+impl Drop for FileWriter {
+    /// Flush anything still buffered. Errors can't be reported from here, so call `flush` to check for them.
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Abstract struct representing a directory, not connected in any way to disk.
 #[derive(Clone, Debug)]
-pub struct File {
+pub struct Dir {
     pub name: String,
     pub drive_index: usize,
     pub entry_addr: u32,
+    pub flags: u8,
 }
 
-impl File {
-    /// Read bytes from the file, following the linked list.
-    pub fn read(&self) -> Vec<u8> {
-        let drives = ata::DRIVES.lock();
-        let drive: &Drive = &drives[self.drive_index];
+impl Dir {
+    /// Checks if the directory is read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.flags & FLAG_READ_ONLY != 0
+    }
 
-        let mut output_bytes: Vec<u8> = Vec::new();
-        let mut current_addr = self.entry_addr;
-        let mut current_sector = DataSector::load(current_addr, drive);
+    /// Checks if the directory is hidden.
+    pub fn is_hidden(&self) -> bool {
+        self.flags & FLAG_HIDDEN != 0
+    }
 
-        loop {
-            output_bytes.extend(
-                current_sector.data[0..current_sector.size as usize]
-                    .iter()
-                    .cloned(),
-            );
-            if let Some(next_sector) = current_sector.continuation_addr {
-                current_addr = next_sector;
-                current_sector = DataSector::load(current_addr, drive);
-            } else {
-                break;
-            }
+    /// Get the addresses of every file table sector in the directory, following the linked list.
+    /// Stops at the first table which can't be read, as its continuation isn't known.
+    pub fn sectors(&self) -> Vec<u32> {
+        match FileTableSector::load(self.entry_addr, self.drive_index, None) {
+            Ok(entry_table) => DirTables::new(entry_table)
+                .map(|table| table.addr)
+                .collect(),
+            Err(_) => Vec::new(),
         }
+    }
+}
 
-        output_bytes
+/// Tracks the sectors visited while following a chain of continuation addresses,
+/// so a corrupted disk where a sector links back to an earlier one can't cause an infinite loop.
+struct ChainGuard {
+    visited: BTreeSet<u32>,
+}
+
+impl ChainGuard {
+    /// Start tracking a chain from the given sector.
+    fn new(start: u32) -> Self {
+        let mut visited = BTreeSet::new();
+        visited.insert(start);
+        Self { visited }
+    }
+
+    /// Follow a link to the next sector, returning it if it hasn't been visited yet.
+    /// If it has, warns that the filesystem is corrupted and returns `None` to end the chain.
+    fn follow(&mut self, next: Option<u32>) -> Option<u32> {
+        let addr = next?;
+        if self.visited.insert(addr) {
+            Some(addr)
+        } else {
+            warn(&format!(
+                "sector {} is linked to more than once, the filesystem may be corrupted\n",
+                addr
+            ));
+            None
+        }
     }
 }
 
-/// Abstract struct representing a directory, not connected in any way to disk.
-#[derive(Clone, Debug)]
-pub struct Dir {
-    pub name: String,
-    pub drive_index: usize,
-    pub entry_addr: u32,
+/// Iterator over the table sectors of a directory, starting from its entry table and following continuations.
+/// A table which can't be read ends the iteration with a warning, like a chain which links back on itself.
+struct DirTables {
+    next: Option<FileTableSector>,
+    guard: ChainGuard,
+}
+
+impl DirTables {
+    fn new(entry_table: FileTableSector) -> Self {
+        Self {
+            guard: ChainGuard::new(entry_table.addr),
+            next: Some(entry_table),
+        }
+    }
+}
+
+impl Iterator for DirTables {
+    type Item = FileTableSector;
+
+    fn next(&mut self) -> Option<FileTableSector> {
+        let table = self.next.take()?;
+        self.next =
+            self.guard.follow(table.continuation_addr).and_then(
+                |addr| match FileTableSector::load(
+                    addr,
+                    table.drive_index,
+                    table.directory_name.clone(),
+                ) {
+                    Ok(next) => Some(next),
+                    Err(error_code) => {
+                        warn(&format!(
+                            "couldn't read table sector {}: {}\n",
+                            addr, error_code
+                        ));
+                        None
+                    }
+                },
+            );
+        Some(table)
+    }
 }
 
 /// Represents a file type, either a file or directory.
@@ -463,6 +1714,47 @@ pub enum FileType {
     Dir(Dir),   // Directory object
 }
 
+impl FileType {
+    /// Gets the name of the file or directory.
+    pub fn name(&self) -> &str {
+        match self {
+            FileType::File(f) => &f.name,
+            FileType::Dir(d) => &d.name,
+        }
+    }
+
+    /// Gets the address of the file or directory's entry sector.
+    pub fn entry_addr(&self) -> u32 {
+        match self {
+            FileType::File(f) => f.entry_addr,
+            FileType::Dir(d) => d.entry_addr,
+        }
+    }
+
+    /// Checks whether this is a directory rather than a file.
+    pub fn is_dir(&self) -> bool {
+        matches!(self, FileType::Dir(_))
+    }
+}
+
+impl Display for FileType {
+    /// Formats the name, with a `/` on the end for directories.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FileType::File(file) => write!(f, "{}", file.name),
+            FileType::Dir(dir) => write!(f, "{}/", dir.name),
+        }
+    }
+}
+
+/// Represents the order in which directory listings are sorted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortMode {
+    Name,     // Alphabetical order
+    NameDesc, // Reverse alphabetical order
+    Size,     // Largest first, with directories counting as empty
+}
+
 /// Represents a sector of the disk containing a file table.
 #[derive(Clone)]
 pub struct FileTableSector {
@@ -470,19 +1762,22 @@ pub struct FileTableSector {
     pub directory_name: Option<String>,
     pub continuation_addr: Option<u32>,
     pub files: Vec<FileType>,
+    /// Files which have been deleted but can still be restored, which take up space in the table until purged.
+    pub deleted: Vec<File>,
     pub drive_index: usize,
     pub is_deleted: bool,
 }
 
 impl FileTableSector {
     //// Load a `FileTableSector` object from its address
-    pub fn load(addr: u32, drive_index: usize, directory_name: Option<String>) -> Self {
-        let drive: &Drive = &ata::DRIVES.lock()[drive_index];
-
+    /// Fails if the sector can't be read, rather than giving an empty table which could be written back over it.
+    pub fn load(
+        addr: u32,
+        drive_index: usize,
+        directory_name: Option<String>,
+    ) -> Result<Self, ExitCode> {
         let mut buf = [0_u8; 512];
-        drive.read(addr, &mut buf);
-
-        drop(drive);
+        with_drive(drive_index, |drive| drive.read(addr, &mut buf))?;
 
         // Parse the continuation address from the first four bytes
         let continuation_addr =
@@ -495,6 +1790,7 @@ impl FileTableSector {
 
         // Parse the actual filenames and file addresses information
         let mut files: Vec<FileType> = Vec::new();
+        let mut deleted: Vec<File> = Vec::new();
 
         let data_bytes = &buf[4..508]; // bytes 508 - 511 are ignored as they contain "POGO"
         for i in 0_usize..8 {
@@ -516,65 +1812,88 @@ impl FileTableSector {
                         break;
                     }
                 }
-                if *file_type_byte == 0 {
+                let flags = *file_type_byte & !FLAG_DIR;
+                if *file_type_byte & FLAG_DIR == 0 && flags & FLAG_DELETED != 0 {
+                    deleted.push(File {
+                        name: file_name,
+                        entry_addr: file_addr,
+                        drive_index,
+                        flags,
+                    });
+                } else if *file_type_byte & FLAG_DIR == 0 {
                     files.push(FileType::File(File {
                         name: file_name,
                         entry_addr: file_addr,
                         drive_index,
+                        flags,
                     }));
                 } else {
                     files.push(FileType::Dir(Dir {
                         name: file_name,
                         entry_addr: file_addr,
                         drive_index,
+                        flags,
                     }));
                 }
             }
         }
 
-        FileTableSector {
+        Ok(FileTableSector {
             addr,
             directory_name,
             continuation_addr: continuation_option,
             files,
+            deleted,
             drive_index,
             is_deleted: false,
-        }
+        })
     }
 
     /// Initialise a brand new sector on the disk, then return a virtual instance of it.
-    pub fn new(new_addr: u32, drive_index: usize, directory_name: Option<String>) -> Self {
-        let drive: &Drive = &ata::DRIVES.lock()[drive_index];
-
-        let mut init_buf = [0_u8; 512];
-        init_buf[508] = b'P';
-        init_buf[509] = b'O';
-        init_buf[510] = b'G';
-        init_buf[511] = b'O';
-
-        drive.write(new_addr, &init_buf);
+    pub fn new(
+        new_addr: u32,
+        drive_index: usize,
+        directory_name: Option<String>,
+    ) -> Result<Self, ExitCode> {
+        let table = FileTableSector::empty(new_addr, drive_index, directory_name);
+        with_drive(drive_index, |drive| {
+            drive.write(new_addr, &table.to_bytes())
+        })?;
+        Ok(table)
+    }
 
+    /// Create a virtual instance of an empty table without writing it to the disk.
+    pub fn empty(addr: u32, drive_index: usize, directory_name: Option<String>) -> Self {
         FileTableSector {
-            addr: new_addr,
+            addr,
             directory_name,
             continuation_addr: None,
             files: Vec::new(),
+            deleted: Vec::new(),
             drive_index,
             is_deleted: false,
         }
     }
 
-    /// Remove the sector from the disk.
+    /// Mark the sector as removed, so that it is cleared when it is next written.
     pub fn remove(&mut self) {
         self.continuation_addr = None;
         self.files = Vec::new();
+        self.deleted = Vec::new();
         self.is_deleted = true;
-        self.update_physical_drive();
     }
 
-    /// Update the virtual parameters onto the disk.
-    pub fn update_physical_drive(&self) {
-        let drive: &Drive = &ata::DRIVES.lock()[self.drive_index];
+    /// Update the virtual parameters onto the disk, reading them back to check they were stored.
+    /// Returns `VerifyError` if they weren't.
+    pub fn update_physical_drive(&self) -> Result<(), ExitCode> {
+        let buf = self.to_bytes();
+        with_drive(self.drive_index, |drive| {
+            drive.write_verified(self.addr, &buf)
+        })
+    }
+
+    /// Get the bytes of the sector as they are stored on the disk.
+    pub fn to_bytes(&self) -> [u8; 512] {
         let mut buf = [0_u8; 512];
 
         if let Some(continuation) = self.continuation_addr {
@@ -589,32 +1908,32 @@ impl FileTableSector {
             buf[3] = 0;
         }
 
-        let mut index = 4;
-        for file_type in &self.files {
-            match file_type {
-                FileType::File(file) => {
-                    for (current_index, byte) in file.name.bytes().enumerate() {
-                        buf[index + current_index] = byte;
-                    }
-
-                    buf[index + 59] = file.entry_addr.get_bits(24..32) as u8;
-                    buf[index + 60] = file.entry_addr.get_bits(16..24) as u8;
-                    buf[index + 61] = file.entry_addr.get_bits(8..16) as u8;
-                    buf[index + 62] = file.entry_addr.get_bits(0..8) as u8;
-                }
-                FileType::Dir(dir) => {
-                    for (current_index, byte) in dir.name.bytes().enumerate() {
-                        buf[index + current_index] = byte;
-                    }
+        // Deleted files are stored after the others, and keep their flag so they're loaded as deleted again
+        let entries = self
+            .files
+            .iter()
+            .map(|object| match object {
+                FileType::File(file) => (&file.name, file.flags, file.entry_addr),
+                FileType::Dir(dir) => (&dir.name, dir.flags | FLAG_DIR, dir.entry_addr),
+            })
+            .chain(
+                self.deleted
+                    .iter()
+                    .map(|file| (&file.name, file.flags, file.entry_addr)),
+            );
 
-                    buf[index + 58] = 0x01;
-                    buf[index + 59] = dir.entry_addr.get_bits(24..32) as u8;
-                    buf[index + 60] = dir.entry_addr.get_bits(16..24) as u8;
-                    buf[index + 61] = dir.entry_addr.get_bits(8..16) as u8;
-                    buf[index + 62] = dir.entry_addr.get_bits(0..8) as u8;
-                }
+        let mut index = 4;
+        for (name, type_byte, entry_addr) in entries {
+            for (current_index, byte) in name.bytes().enumerate() {
+                buf[index + current_index] = byte;
             }
 
+            buf[index + 58] = type_byte;
+            buf[index + 59] = entry_addr.get_bits(24..32) as u8;
+            buf[index + 60] = entry_addr.get_bits(16..24) as u8;
+            buf[index + 61] = entry_addr.get_bits(8..16) as u8;
+            buf[index + 62] = entry_addr.get_bits(0..8) as u8;
+
             index += 63;
         }
 
@@ -625,39 +1944,39 @@ impl FileTableSector {
             buf[511] = b'O';
         }
 
-        drive.write(self.addr, &buf);
+        buf
     }
 
-    /// Set the continuation address on disk
+    /// Set the continuation address.
+    /// WARNING: This does not update the physical drive.
     pub fn set_continuation(&mut self, sector: u32) {
         self.continuation_addr = Some(sector);
-        self.update_physical_drive();
     }
 
-    /// Add a file to the table and update the physical drive.
+    /// Add a file to the table.
     /// WARNING: This does not add the file to the disk, only a reference to the file on the table.
-    /// WARNING: This does not create a new table if the current one is full.
+    /// WARNING: This does not update the physical drive or create a new table if the current one is full.
     pub fn add_file(&mut self, name: &str, addr: u32) {
-        assert!(self.files.len() < 8);
+        assert!(!self.is_full());
         self.files.push(FileType::File(File {
             name: name.to_owned(),
             drive_index: self.drive_index,
             entry_addr: addr,
+            flags: 0,
         }));
-        self.update_physical_drive();
     }
 
-    /// Add a directory to the table and update the physical drive.
+    /// Add a directory to the table.
     /// WARNING: This does not add the directory to the disk, only a reference to the directory on the table.
-    /// WARNING: This does not create a new table if the current one is full.
+    /// WARNING: This does not update the physical drive or create a new table if the current one is full.
     pub fn add_dir(&mut self, name: &str, addr: u32) {
-        assert!(self.files.len() < 8);
+        assert!(!self.is_full());
         self.files.push(FileType::Dir(Dir {
             name: name.to_owned(),
             drive_index: self.drive_index,
             entry_addr: addr,
+            flags: 0,
         }));
-        self.update_physical_drive();
     }
 
     /// Gets a specified file from the sector.
@@ -684,19 +2003,28 @@ impl FileTableSector {
         }
     }
 
+    /// Checks whether every entry of the table is taken, including by deleted files.
+    pub fn is_full(&self) -> bool {
+        self.files.len() + self.deleted.len() >= 8
+    }
+
     /// Checks if sector contains a file or directory with the given name.
     /// Does not return the found object.
     pub fn contains_object(&self, name: &str) -> bool {
-        self.files
-            .iter()
-            .find(|el| match el {
-                FileType::File(f) => f.name == name,
-                FileType::Dir(d) => d.name == name,
-            })
-            .is_some()
+        self.files.iter().any(|object| object.name() == name)
     }
 }
 
+/// Number of data bytes in a data sector which ends with a checksum.
+pub const DATA_SECTOR_CAPACITY: usize = 504;
+/// Number of data bytes in a file's entry sector, which also holds when the file was written.
+pub const ENTRY_SECTOR_CAPACITY: usize = DATA_SECTOR_CAPACITY - 4;
+/// Bit of a data sector's size field which shows that the sector ends with a checksum.
+/// Sectors without it were written before checksums existed and hold up to 506 bytes.
+const CHECKSUM_FLAG: u16 = 0x8000;
+/// Bit of a data sector's size field which shows that the four bytes before the checksum hold a timestamp.
+const TIMESTAMP_FLAG: u16 = 0x4000;
+
 /// Represents a sector of the disk containing data
 #[derive(Clone)]
 pub struct DataSector {
@@ -705,13 +2033,23 @@ pub struct DataSector {
     pub size: u16,
     pub data: [u8; 506],
     pub drive_index: usize,
+    pub has_checksum: bool,
+    pub is_corrupted: bool,
+    /// Unix timestamp of when the file was last written, only stored in entry sectors.
+    pub modified: Option<u32>,
 }
 
 impl DataSector {
-    /// Loads a new `DataSector` object from its address
-    pub fn load(addr: u32, drive: &Drive) -> Self {
+    /// Loads a new `DataSector` object from its address.
+    /// If the sector has a checksum which doesn't match its contents, or a size larger than it can hold,
+    /// it is flagged as corrupted and the size is clamped to the sector's capacity.
+    /// A sector which can't be read is flagged as corrupted too, and loaded as empty.
+    pub fn load(addr: u32, drive_index: usize) -> Self {
         let mut buf = [0_u8; 512];
-        drive.read(addr, &mut buf);
+        let unreadable = with_drive(drive_index, |drive| drive.read(addr, &mut buf)).is_err();
+        if unreadable {
+            buf = [0_u8; 512];
+        }
 
         let continuation_addr =
             (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | (buf[3] as u32);
@@ -722,92 +2060,243 @@ impl DataSector {
             None
         };
 
-        let size = (buf[4] as u16) << 8 | (buf[5] as u16);
+        let size_field = (buf[4] as u16) << 8 | (buf[5] as u16);
+        let has_checksum = size_field & CHECKSUM_FLAG != 0;
+        let has_timestamp = size_field & TIMESTAMP_FLAG != 0;
+        let checksum_failed =
+            has_checksum && crc16(&buf[0..510]) != (buf[510] as u16) << 8 | (buf[511] as u16);
+
+        // Clamp the size so a corrupted size field can't make readers overrun the data
+        let capacity = if has_timestamp {
+            ENTRY_SECTOR_CAPACITY
+        } else if has_checksum {
+            DATA_SECTOR_CAPACITY
+        } else {
+            506
+        };
+        let size = size_field & !(CHECKSUM_FLAG | TIMESTAMP_FLAG);
+        let size_invalid = size as usize > capacity;
+
         let mut data = [0_u8; 506];
         data.clone_from_slice(&buf[6..512]);
 
+        let modified = if has_timestamp {
+            Some(
+                (buf[506] as u32) << 24
+                    | (buf[507] as u32) << 16
+                    | (buf[508] as u32) << 8
+                    | (buf[509] as u32),
+            )
+        } else {
+            None
+        };
+
         DataSector {
             addr,
             continuation_addr: continuation_addr_option,
-            size,
+            size: size.min(capacity as u16),
             data,
-            drive_index: drive.drive_index as usize,
+            drive_index,
+            has_checksum,
+            is_corrupted: unreadable || checksum_failed || size_invalid,
+            modified,
         }
     }
 
-    /// Initialise a brand new `DataSector` object on disk, then return a virtual instance
-    pub fn new(addr: u32, drive: &Drive, bytes: Vec<u8>) -> Self {
-        let mut buf = [0_u8; 512];
-        let size = bytes.len() as u16;
-        buf[4] = size.get_bits(8..16) as u8;
-        buf[5] = size.get_bits(0..8) as u8;
+    /// Initialise a brand new `DataSector` object on disk, then return a virtual instance.
+    /// At most `DATA_SECTOR_CAPACITY` bytes can be stored.
+    pub fn new(addr: u32, drive_index: usize, bytes: Vec<u8>) -> Result<Self, ExitCode> {
+        assert!(bytes.len() <= DATA_SECTOR_CAPACITY);
 
-        let index = 6;
-        for (current_index, byte) in bytes.iter().enumerate() {
-            buf[index + current_index] = *byte;
-        }
+        let mut data = [0_u8; 506];
+        data[..bytes.len()].copy_from_slice(&bytes);
+
+        let sector = DataSector {
+            addr,
+            continuation_addr: None,
+            size: bytes.len() as u16,
+            data,
+            drive_index,
+            has_checksum: true,
+            is_corrupted: false,
+            modified: None,
+        };
+
+        sector.update_physical_drive()?;
+        Ok(sector)
+    }
+
+    /// Initialise the entry sector of a file on disk, recording when it was written, then return a virtual instance.
+    /// At most `ENTRY_SECTOR_CAPACITY` bytes can be stored.
+    pub fn new_entry(
+        addr: u32,
+        drive_index: usize,
+        bytes: Vec<u8>,
+        modified: u32,
+    ) -> Result<Self, ExitCode> {
+        assert!(bytes.len() <= ENTRY_SECTOR_CAPACITY);
+
+        let mut data = [0_u8; 506];
+        data[..bytes.len()].copy_from_slice(&bytes);
 
-        drive.write(addr, &buf);
-        return Self::load(addr, drive);
+        let sector = DataSector {
+            addr,
+            continuation_addr: None,
+            size: bytes.len() as u16,
+            data,
+            drive_index,
+            has_checksum: true,
+            is_corrupted: false,
+            modified: Some(modified),
+        };
+
+        sector.update_physical_drive()?;
+        Ok(sector)
     }
 
     /// Removes the sector from the disk.
-    pub fn remove(&mut self, drive: &Drive) {
+    /// If it can't be written, it keeps its old contents, so it is never mistaken for an available sector.
+    pub fn remove(&mut self) {
         self.continuation_addr = None;
         self.data = [0_u8; 506];
         self.size = 0;
-        self.update_physical_drive(drive);
+        self.has_checksum = false;
+        self.modified = None;
+        self.update_physical_drive().ok();
     }
 
     /// Updates the physical disk with the contents of the virtual sector.
-    pub fn update_physical_drive(&self, drive: &Drive) {
+    /// The checksum is recalculated if the sector has one.
+    pub fn update_physical_drive(&self) -> Result<(), ExitCode> {
         let mut buf = [0_u8; 512];
-        drive.read(self.addr, &mut buf);
 
         if let Some(continuation) = self.continuation_addr {
             buf[0] = continuation.get_bits(24..32) as u8;
             buf[1] = continuation.get_bits(16..24) as u8;
             buf[2] = continuation.get_bits(8..16) as u8;
             buf[3] = continuation.get_bits(0..8) as u8;
+        }
+
+        let mut size_field = if self.has_checksum {
+            self.size | CHECKSUM_FLAG
         } else {
-            buf[0] = 0;
-            buf[1] = 0;
-            buf[2] = 0;
-            buf[3] = 0;
+            self.size
+        };
+        if self.modified.is_some() {
+            size_field |= TIMESTAMP_FLAG;
         }
+        buf[4] = size_field.get_bits(8..16) as u8;
+        buf[5] = size_field.get_bits(0..8) as u8;
 
-        buf[4] = self.size.get_bits(8..16) as u8;
-        buf[5] = self.size.get_bits(0..8) as u8;
+        buf[6..512].copy_from_slice(&self.data);
+
+        if let Some(modified) = self.modified {
+            buf[506] = modified.get_bits(24..32) as u8;
+            buf[507] = modified.get_bits(16..24) as u8;
+            buf[508] = modified.get_bits(8..16) as u8;
+            buf[509] = modified.get_bits(0..8) as u8;
+        }
 
-        for index in 6_usize..512 {
-            buf[index] = self.data[index - 6];
+        if self.has_checksum {
+            let checksum = crc16(&buf[0..510]);
+            buf[510] = checksum.get_bits(8..16) as u8;
+            buf[511] = checksum.get_bits(0..8) as u8;
         }
 
-        drive.write(self.addr, &buf);
+        with_drive(self.drive_index, |drive| drive.write(self.addr, &buf))
+    }
+}
+
+/// Run-length encodes bytes as pairs of a count (from 1 to 255) followed by the byte to repeat.
+/// Data without long runs can become up to twice as long, so callers should check it's actually smaller.
+pub fn rle_compress(bytes: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = bytes[index];
+        let run = bytes[index..]
+            .iter()
+            .take(255)
+            .take_while(|other| **other == byte)
+            .count();
+        output.push(run as u8);
+        output.push(byte);
+        index += run;
+    }
+
+    output
+}
+
+/// Reverses `rle_compress`. A trailing count without a byte is ignored.
+pub fn rle_decompress(bytes: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+
+    for pair in bytes.chunks_exact(2) {
+        output.extend(core::iter::repeat(pair[1]).take(pair[0] as usize));
+    }
+
+    output
+}
+
+/// Scrambles or unscrambles bytes by XORing them with a keystream derived from the passphrase.
+/// The passphrase is hashed with FNV-1a to seed an xorshift generator, so every byte of the key depends on all of it.
+fn xor_with_passphrase(bytes: &mut [u8], passphrase: &str) {
+    let mut state = passphrase
+        .bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+        .max(1);
+
+    for byte in bytes {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        *byte ^= state as u8;
+    }
+}
+
+/// Calculates the CRC-16 (CCITT) checksum of the given bytes.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
     }
+
+    crc
+}
+
+/// Runs the given function on the block device with the given index.
+/// Panics if there is no such device.
+fn with_drive<T>(drive_index: usize, f: impl FnOnce(&dyn BlockDevice) -> T) -> T {
+    block::with_device(drive_index, f).expect("no block device with the given index")
 }
 
 /// Create the basic filesystem on a drive specified by the user.
-/// Allows the user to cancel at several points.
-fn create_fs() {
-    let drives = ata::DRIVES.lock();
-    let mut filesystem = FILESYSTEM.lock();
-
-    info(&format!("detected {} drive(s):\n", drives.len()));
-    for drive in &*drives {
-        println!(
-            "         {}: {} {} ({} MB)",
-            drive.drive_index,
-            drive.model,
-            drive.serial,
-            drive.sectors / 2048
-        );
+/// Allows the user to cancel at several points, asking with `confirm` before overwriting the drive.
+fn create_fs(confirm: impl Fn(&str) -> bool) {
+    let drive_count = block::device_count();
+
+    info(&format!("detected {} drive(s):\n", drive_count));
+    for index in 0..drive_count {
+        if let Some(description) = block::with_device(index, |drive| drive.description()) {
+            println!("         {}: {}", index, description);
+        }
     }
 
     println!();
     let mut drive_index = -1_i8;
 
-    while drive_index < 0 || drive_index >= drives.len() as i8 {
+    while drive_index < 0 || drive_index >= drive_count as i8 {
         info("select a drive or type x to exit: ");
         let mut char_buf = [0_u8; 1];
         STDIN.get_char().encode_utf8(&mut char_buf);
@@ -819,79 +2308,147 @@ fn create_fs() {
         }
     }
 
-    warn("this disk will be overwritten, continue? (y/n): ");
-    let confirmation = STDIN.get_char();
-    println!();
-    if confirmation != 'y' {
+    if !confirm("this disk will be overwritten, continue?") {
         return warn("running in diskless mode, some features will be unavailable\n");
     }
 
     info(&format!("creating filesystem on disk {}\n", drive_index));
 
-    let mut init_buf = [0_u8; 512];
-    init_buf[508] = b'P';
-    init_buf[509] = b'O';
-    init_buf[510] = b'G';
-    init_buf[511] = b'O';
+    match format_drive(drive_index as usize) {
+        ExitCode::Success => okay("filesystem successfully created\n"),
+        error_code => err(&error_code.to_string()),
+    };
+}
 
-    let drive = &drives[drive_index as usize];
-    drive.write(drive.sectors - 1, &init_buf);
+/// Create an empty filesystem on the given drive and mount it, with the default number of reserved sectors.
+/// Any filesystem already on the drive is unmounted first, and the sectors it used are cleared so they can be reused.
+/// If the drive was the current drive, it stays current.
+pub fn format_drive(drive_index: usize) -> ExitCode {
+    format_drive_with(drive_index, DEFAULT_RESERVED_SECTORS)
+}
 
-    let sectors = drive.sectors;
-    drop(drives);
+/// Create an empty filesystem on the given drive and mount it, reserving the given number of sectors at the end
+/// for metadata (including the superblock), like `format_drive`.
+/// Returns `ParseError` if there would be no superblock or the reserved sectors would take up over half the drive.
+pub fn format_drive_with(drive_index: usize, reserved_sectors: u32) -> ExitCode {
+    let superblock = match block::with_device(drive_index, |drive| drive.sectors()) {
+        Some(total_sectors) if reserved_sectors == 0 || reserved_sectors > total_sectors / 2 => {
+            return ExitCode::ParseError
+        }
+        Some(total_sectors) => Superblock::new(total_sectors, reserved_sectors),
+        None => return ExitCode::NotFoundError,
+    };
+
+    let mut mounts = FILESYSTEM.lock();
+    let was_current = mounts.current == Some(drive_index as u8);
+    mounts.unmount(drive_index as u8);
+    drop(mounts);
+
+    // Sectors of a filesystem which can't be fully read are left as they are, so they won't be reused
+    if let Ok(used_sectors) = FileSystem::open(drive_index as u8).and_then(|fs| fs.used_sectors()) {
+        let empty_buf = [0_u8; 512];
+        for addr in used_sectors {
+            if let Err(error_code) = with_drive(drive_index, |drive| drive.write(addr, &empty_buf))
+            {
+                return error_code;
+            }
+        }
+    }
 
-    *filesystem = Some(FileSystem {
-        drive_index: drive_index as u8,
-        entry_sector: sectors - 1,
-        entry_table: FileTableSector::load(sectors - 1, drive_index as usize, None),
-    });
+    if let Err(error_code) = FileTableSector::new(superblock.root_addr, drive_index, None) {
+        return error_code;
+    }
+    if let Err(error_code) = superblock.update_physical_drive(drive_index) {
+        return error_code;
+    }
 
-    okay("filesystem successfully created\n");
+    let exit_code = mount(drive_index as u8);
+    if was_current {
+        FILESYSTEM.lock().current = Some(drive_index as u8);
+    }
+    exit_code
 }
 
-/// Try to detect a filesystem on any drive.
-/// Gives the option to create one if none is found.
-pub fn detect_fs() {
-    {
-        let drives = ata::DRIVES.lock();
-        let mut filesystem = FILESYSTEM.lock();
-        let filesystem_signature: [u8; 4] = [b'P', b'O', b'G', b'O'];
-
-        for drive in &*drives {
-            let mut buf = [0_u8; 512];
-            let entry_sector = drive.sectors - 1;
-            drive.read(entry_sector, &mut buf);
-            if &buf[508..512] == &filesystem_signature {
-                let drive_index = drive.drive_index;
-                drop(drives);
-                *filesystem = Some(FileSystem {
-                    drive_index: drive_index,
-                    entry_sector,
-                    entry_table: FileTableSector::load(entry_sector, drive_index as usize, None),
-                });
-                break;
-            }
+/// Mount the filesystem on the given drive.
+/// Returns `NoFilesystemError` if the drive doesn't contain a filesystem.
+pub fn mount(drive_index: u8) -> ExitCode {
+    match FileSystem::open(drive_index) {
+        Ok(filesystem) => {
+            FILESYSTEM.lock().mount(filesystem);
+            ExitCode::Success
         }
+        Err(code) => code,
     }
+}
 
-    let filesystem = FILESYSTEM.lock();
+/// Try to detect a filesystem on every drive, mounting all that are found.
+/// If none is found, asks with `confirm` whether to create one, which can always refuse if nobody can answer.
+pub fn detect_fs(confirm: impl Fn(&str) -> bool) {
+    mount_all();
 
-    if let Some(fs) = &*filesystem {
-        okay(&format!("filesystem detected on disk {}\n", fs.drive_index));
-    } else {
-        warn("no filesystem detected, initialise one now? (y/n): ");
-        let char_input = STDIN.get_char();
-        println!();
-        if char_input == 'y' {
-            drop(filesystem);
-            create_fs();
+    if !is_mounted() {
+        if confirm("no filesystem detected, initialise one now?") {
+            create_fs(confirm);
         } else {
             warn("running in diskless mode, some features will be unavailable\n");
         }
     }
 }
 
-/// Checks if the filesystem is mounted.
+/// Discard every mount and detect the filesystems again, without syncing them first.
+/// This is for recovering when `FILESYSTEM` or the drive locks were left locked, such as by a command which
+/// panicked, so it forcibly unlocks them. It must not be called while anything is actually using them.
+pub unsafe fn reset() {
+    ata::BUSES.force_unlock();
+    block::DEVICES.force_unlock();
+    FILESYSTEM.force_unlock();
+
+    remount();
+}
+
+/// Discard every mount and detect the filesystems again, without syncing them first.
+/// This is for when drives may have been renumbered or replaced, such as after an ATA bus is reset, so the mounts
+/// could refer to the wrong drives.
+pub fn remount() {
+    *FILESYSTEM.lock() = Mounts::new();
+    mount_all();
+}
+
+/// Try to detect a filesystem on every drive, mounting all that are found.
+fn mount_all() {
+    let drive_count = block::device_count();
+
+    for drive_index in 0..drive_count as u8 {
+        match mount(drive_index) {
+            ExitCode::Success => {
+                okay(&format!("filesystem detected on disk {}\n", drive_index));
+            }
+            ExitCode::UnsupportedFilesystemError => warn(&format!(
+                "filesystem on disk {} is from a newer version and won't be mounted\n",
+                drive_index
+            )),
+            _ => (),
+        }
+    }
+
+    // Mounting makes the first filesystem current, but one can be chosen with `mount -d` instead
+    let mut mounts = FILESYSTEM.lock();
+    let default = mounts
+        .filesystems
+        .values()
+        .find(|filesystem| filesystem.superblock.is_default)
+        .map(|filesystem| filesystem.drive_index);
+    if let Some(drive_index) = default {
+        mounts.current = Some(drive_index);
+        drop(mounts);
+        info(&format!(
+            "using disk {} as the default filesystem\n",
+            drive_index
+        ));
+    }
+}
+
+/// Checks if any filesystem is mounted.
 pub fn is_mounted() -> bool {
-    FILESYSTEM.lock().is_some()
+    FILESYSTEM.lock().current.is_some()
 }
@@ -0,0 +1,308 @@
+// Graphics
+// Switches the VGA card between the 80x25 text mode and the 320x200 256 colour graphics mode (mode 13h),
+// by programming its registers directly as there's no BIOS to ask once the kernel is running.
+
+use crate::vga::WRITER;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 200;
+
+const FRAMEBUFFER_ADDR: u64 = 0xA0000; // physical address of the graphics memory window
+const FONT_SIZE: usize = 256 * 32; // every character has 32 bytes in plane 2, of which 16 are used
+
+/// Values of the miscellaneous output, sequencer, CRTC, graphics controller and attribute controller
+/// registers for a mode, in that order (from the tables in Chris Giese's `modes.c`).
+struct ModeRegisters {
+    misc: u8,
+    sequencer: [u8; 5],
+    crtc: [u8; 25],
+    graphics: [u8; 9],
+    attribute: [u8; 21],
+}
+
+const TEXT_MODE: ModeRegisters = ModeRegisters {
+    misc: 0x67,
+    sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+    crtc: [
+        0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F, 0x00, 0x4F, 0x0D, 0x0E, 0x00, 0x00, 0x00,
+        0x50, 0x9C, 0x0E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0E, 0x00, 0xFF],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E,
+        0x3F, 0x0C, 0x00, 0x0F, 0x08, 0x00,
+    ],
+};
+
+const GRAPHICS_MODE: ModeRegisters = ModeRegisters {
+    misc: 0x63,
+    sequencer: [0x03, 0x01, 0x0F, 0x00, 0x0E],
+    crtc: [
+        0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x9C, 0x0E, 0x8F, 0x28, 0x40, 0x96, 0xB9, 0xA3, 0xFF,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0F, 0xFF],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F, 0x41, 0x00, 0x0F, 0x00, 0x00,
+    ],
+};
+
+/// Offset at which the bootloader maps all of physical memory, as the graphics memory isn't identity mapped.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Everything the graphics mode overwrites which the text mode needs back.
+/// The text itself is drawn again from the writer's back buffer.
+struct SavedText {
+    font: Vec<u8>,
+    palette: Vec<u8>,
+}
+
+lazy_static! {
+    /// What was on the screen before switching to the graphics mode, or `None` when in the text mode.
+    static ref SAVED_TEXT: Mutex<Option<SavedText>> = Mutex::new(None);
+}
+
+/// Remembers where physical memory is mapped, so the graphics memory can be found.
+pub fn init(physical_memory_offset: u64) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+}
+
+/// Checks whether the screen is in the graphics mode.
+pub fn is_graphics_mode() -> bool {
+    SAVED_TEXT.lock().is_some()
+}
+
+/// Gets the colour closest to the given red, green and blue values.
+/// The graphics mode uses a palette with 3 bits of red, 3 of green and 2 of blue, so that every colour can be made.
+pub fn rgb(r: u8, g: u8, b: u8) -> u8 {
+    (r & 0xE0) | (g & 0xE0) >> 3 | b >> 6
+}
+
+/// Switches to the graphics mode with a black screen, saving the font and palette to restore afterwards.
+/// Does nothing if already in the graphics mode.
+pub fn enter_graphics_mode() {
+    let mut saved_text = SAVED_TEXT.lock();
+    if saved_text.is_some() {
+        return;
+    }
+
+    // Hold the writer so nothing is printed half way through switching
+    interrupts::without_interrupts(|| {
+        let _writer = WRITER.lock();
+        let font = unsafe { with_plane_2(|plane| plane[..FONT_SIZE].to_vec()) };
+        let palette = unsafe { read_palette() };
+        *saved_text = Some(SavedText { font, palette });
+
+        unsafe {
+            write_registers(&GRAPHICS_MODE);
+            write_rgb_palette();
+        }
+    });
+    drop(saved_text);
+
+    clear(0);
+}
+
+/// Switches back to the text mode, restoring what was on the screen before.
+/// Does nothing if already in the text mode.
+pub fn leave_graphics_mode() {
+    let saved_text = match SAVED_TEXT.lock().take() {
+        Some(saved_text) => saved_text,
+        None => return,
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        unsafe {
+            write_registers(&TEXT_MODE);
+            with_plane_2(|plane| plane[..FONT_SIZE].copy_from_slice(&saved_text.font));
+            write_palette(&saved_text.palette);
+        }
+
+        // The text mode's registers have the default cursor, so put back the writer's
+        writer.present();
+        writer.show_cursor();
+    });
+}
+
+/// Sets the pixel at the given position to the given colour.
+/// Pixels off the screen, or drawn in the text mode, are ignored.
+pub fn put_pixel(x: usize, y: usize, colour: u8) {
+    fill_rect(x, y, 1, 1, colour);
+}
+
+/// Fills the rectangle with its top left corner at the given position with the given colour.
+/// The rectangle is clipped to the screen, and nothing is drawn in the text mode.
+pub fn fill_rect(x: usize, y: usize, width: usize, height: usize, colour: u8) {
+    if !is_graphics_mode() {
+        return;
+    }
+
+    let right = x.saturating_add(width).min(WIDTH);
+    let bottom = y.saturating_add(height).min(HEIGHT);
+    for row in y..bottom {
+        for col in x..right {
+            unsafe {
+                memory_ptr(FRAMEBUFFER_ADDR)
+                    .add(row * WIDTH + col)
+                    .write_volatile(colour)
+            };
+        }
+    }
+}
+
+/// Fills the whole screen with the given colour.
+pub fn clear(colour: u8) {
+    fill_rect(0, 0, WIDTH, HEIGHT, colour);
+}
+
+/// Gets a pointer to the given physical address through the bootloader's mapping of physical memory.
+fn memory_ptr(physical_addr: u64) -> *mut u8 {
+    (PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + physical_addr) as *mut u8
+}
+
+/// Writes the registers for the given mode, switching the card to it.
+unsafe fn write_registers(mode: &ModeRegisters) {
+    let mut misc_port: Port<u8> = Port::new(0x3C2);
+    let mut sequencer_index: Port<u8> = Port::new(0x3C4);
+    let mut sequencer_data: Port<u8> = Port::new(0x3C5);
+    let mut crtc_index: Port<u8> = Port::new(0x3D4);
+    let mut crtc_data: Port<u8> = Port::new(0x3D5);
+    let mut graphics_index: Port<u8> = Port::new(0x3CE);
+    let mut graphics_data: Port<u8> = Port::new(0x3CF);
+    let mut attribute_port: Port<u8> = Port::new(0x3C0);
+    let mut input_status: Port<u8> = Port::new(0x3DA);
+
+    misc_port.write(mode.misc);
+    for (i, value) in mode.sequencer.iter().enumerate() {
+        sequencer_index.write(i as u8);
+        sequencer_data.write(*value);
+    }
+
+    // The first CRTC registers are write protected unless bit 7 of register 0x11 is clear,
+    // and bit 7 of register 0x03 must be set to keep the light pen registers out of the way
+    let mut crtc = mode.crtc;
+    crtc[0x03] |= 0x80;
+    crtc[0x11] &= !0x80;
+    crtc_index.write(0x03);
+    let value = crtc_data.read();
+    crtc_data.write(value | 0x80);
+    crtc_index.write(0x11);
+    let value = crtc_data.read();
+    crtc_data.write(value & !0x80);
+    for (i, value) in crtc.iter().enumerate() {
+        crtc_index.write(i as u8);
+        crtc_data.write(*value);
+    }
+
+    for (i, value) in mode.graphics.iter().enumerate() {
+        graphics_index.write(i as u8);
+        graphics_data.write(*value);
+    }
+
+    // Reading the input status register resets the attribute controller to expect an index next
+    for (i, value) in mode.attribute.iter().enumerate() {
+        input_status.read();
+        attribute_port.write(i as u8);
+        attribute_port.write(*value);
+    }
+
+    // Setting bit 5 of the index gives the palette back to the display, which unblanks it
+    input_status.read();
+    attribute_port.write(0x20);
+}
+
+/// Runs the given function on the first 64 KB of plane 2, where the text mode keeps its font.
+/// The registers are set up for reading and writing the plane on its own, then put back afterwards.
+unsafe fn with_plane_2<T>(f: impl FnOnce(&mut [u8]) -> T) -> T {
+    let mut sequencer_index: Port<u8> = Port::new(0x3C4);
+    let mut sequencer_data: Port<u8> = Port::new(0x3C5);
+    let mut graphics_index: Port<u8> = Port::new(0x3CE);
+    let mut graphics_data: Port<u8> = Port::new(0x3CF);
+
+    let read_register = |index: &mut Port<u8>, data: &mut Port<u8>, register: u8| {
+        index.write(register);
+        data.read()
+    };
+    let map_mask = read_register(&mut sequencer_index, &mut sequencer_data, 0x02);
+    let memory_mode = read_register(&mut sequencer_index, &mut sequencer_data, 0x04);
+    let read_map = read_register(&mut graphics_index, &mut graphics_data, 0x04);
+    let graphics_mode = read_register(&mut graphics_index, &mut graphics_data, 0x05);
+    let misc = read_register(&mut graphics_index, &mut graphics_data, 0x06);
+
+    let write_register = |index: &mut Port<u8>, data: &mut Port<u8>, register: u8, value: u8| {
+        index.write(register);
+        data.write(value);
+    };
+    // Write to and read from plane 2 only, without odd/even addressing, through the 64 KB window at 0xA0000
+    write_register(&mut sequencer_index, &mut sequencer_data, 0x02, 0x04);
+    write_register(
+        &mut sequencer_index,
+        &mut sequencer_data,
+        0x04,
+        memory_mode | 0x04,
+    );
+    write_register(&mut graphics_index, &mut graphics_data, 0x04, 0x02);
+    write_register(
+        &mut graphics_index,
+        &mut graphics_data,
+        0x05,
+        graphics_mode & !0x10,
+    );
+    write_register(
+        &mut graphics_index,
+        &mut graphics_data,
+        0x06,
+        (misc & !0x0E) | 0x04,
+    );
+
+    let plane = core::slice::from_raw_parts_mut(memory_ptr(FRAMEBUFFER_ADDR), 0x10000);
+    let result = f(plane);
+
+    write_register(&mut sequencer_index, &mut sequencer_data, 0x02, map_mask);
+    write_register(&mut sequencer_index, &mut sequencer_data, 0x04, memory_mode);
+    write_register(&mut graphics_index, &mut graphics_data, 0x04, read_map);
+    write_register(&mut graphics_index, &mut graphics_data, 0x05, graphics_mode);
+    write_register(&mut graphics_index, &mut graphics_data, 0x06, misc);
+    result
+}
+
+/// Reads all 256 colours of the palette, as 6-bit red, green and blue values.
+unsafe fn read_palette() -> Vec<u8> {
+    let mut read_index: Port<u8> = Port::new(0x3C7);
+    let mut data_port: Port<u8> = Port::new(0x3C9);
+
+    // The index moves on by itself after every third value
+    read_index.write(0);
+    (0..256 * 3).map(|_| data_port.read()).collect()
+}
+
+/// Writes all 256 colours of the palette, as 6-bit red, green and blue values.
+unsafe fn write_palette(palette: &[u8]) {
+    let mut write_index: Port<u8> = Port::new(0x3C8);
+    let mut data_port: Port<u8> = Port::new(0x3C9);
+
+    write_index.write(0);
+    for value in palette {
+        data_port.write(*value);
+    }
+}
+
+/// Writes the palette used by `rgb`, with 3 bits of red, 3 of green and 2 of blue in each colour.
+unsafe fn write_rgb_palette() {
+    let palette: Vec<u8> = (0..=255_u8)
+        .flat_map(|colour| {
+            let r = (colour >> 5) as u32 * 63 / 7;
+            let g = (colour >> 2 & 0x07) as u32 * 63 / 7;
+            let b = (colour & 0x03) as u32 * 63 / 3;
+            [r as u8, g as u8, b as u8]
+        })
+        .collect();
+    write_palette(&palette);
+}
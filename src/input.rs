@@ -4,56 +4,199 @@
 use crate::interrupts::{InterruptIndex, PICS};
 use crate::print;
 use crate::vga::WRITER;
-use alloc::{string::String, vec::Vec};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use bit_field::BitField;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
 use spin::Mutex;
 use x86_64::instructions::{interrupts, port::Port};
 use x86_64::structures::idt::InterruptStackFrame;
 
+/// Character sent to `Stdin` when Ctrl+Backspace or Ctrl+W is pressed, to delete the previous word.
+const DELETE_WORD: char = '\x17';
+/// Character sent to `Stdin` when Ctrl+L is pressed, to clear the screen.
+const CLEAR_SCREEN: char = '\x0c';
+/// Character sent to `Stdin` when Ctrl+U is pressed, to clear the line.
+const CLEAR_LINE: char = '\x15';
+/// Character sent to `Stdin` when Tab is pressed, to complete the line.
+const COMPLETE: char = '\t';
+
+/// Whether either control, shift or alt key is currently held down.
+static CTRL_HELD: AtomicBool = AtomicBool::new(false);
+static SHIFT_HELD: AtomicBool = AtomicBool::new(false);
+static ALT_HELD: AtomicBool = AtomicBool::new(false);
+
+/// Lock key states, mirroring the ones `pc_keyboard` tracks internally (which start with Num Lock on).
+static CAPS_LOCK: AtomicBool = AtomicBool::new(false);
+static NUM_LOCK: AtomicBool = AtomicBool::new(true);
+static SCROLL_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Whether every scancode is sent to `Stdin` as it is, for debugging the keyboard layout.
+static SCANCODE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// The typematic byte last sent to the keyboard, starting with the default after a reset (500ms, 10.9 per second).
+/// The BIOS may have set something else, but there's no way to read it back from the keyboard.
+static TYPEMATIC: AtomicU8 = AtomicU8::new(0x2B);
+
+/// Keyboard controller command to set the LEDs, followed by a bitmask of which are on.
+const SET_LEDS_COMMAND: u8 = 0xED;
+/// Keyboard command to set how keys repeat when held, followed by the typematic byte.
+const SET_TYPEMATIC_COMMAND: u8 = 0xF3;
+/// Byte sent by the keyboard to acknowledge a command.
+const KEYBOARD_ACK: u8 = 0xFA;
+/// Byte sent by the keyboard to ask for the last command to be sent again.
+const KEYBOARD_RESEND: u8 = 0xFE;
+/// Number of times to poll the keyboard controller before giving up.
+const KEYBOARD_TIMEOUT: usize = 100_000;
+
+/// State of the modifier and lock keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// Gets which modifier keys are held down and which lock keys are on.
+pub fn modifiers() -> Modifiers {
+    Modifiers {
+        shift: SHIFT_HELD.load(Ordering::Relaxed),
+        ctrl: CTRL_HELD.load(Ordering::Relaxed),
+        alt: ALT_HELD.load(Ordering::Relaxed),
+        caps_lock: CAPS_LOCK.load(Ordering::Relaxed),
+        num_lock: NUM_LOCK.load(Ordering::Relaxed),
+        scroll_lock: SCROLL_LOCK.load(Ordering::Relaxed),
+    }
+}
+
+/// Maximum number of characters which can be typed ahead before further keypresses are ignored.
+const INPUT_BUFFER_SIZE: usize = 1024;
+
+/// A key which doesn't produce a character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    Escape,
+    /// A function key, numbered from 1 to 12.
+    Function(u8),
+    /// A letter pressed with Ctrl, such as `Ctrl('s')` for Ctrl+S.
+    /// Shortcuts which line input handles itself (e.g. Ctrl+W) are sent as characters instead.
+    Ctrl(char),
+}
+
+/// A single piece of keyboard input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Char(char),
+    Key(SpecialKey),
+    /// A scancode straight from the keyboard and what it was decoded as, if anything.
+    /// Only sent in scancode mode, which replaces all other input.
+    Scancode(u8, Option<DecodedKey>),
+}
+
+/// What pressing Tab does to the line being typed, as worked out by a `Completer`.
+pub enum Completion {
+    /// Text to add to the end of the line.
+    Append(String),
+    /// Several ways the line could go on, which are listed below it.
+    Candidates(Vec<String>),
+    /// Nothing, as the line can't be completed.
+    None,
+}
+
+/// Completes lines read by `Stdin::get_str_completing` when Tab is pressed.
+pub trait Completer {
+    /// Work out how the line typed so far could be completed.
+    fn complete(&self, line: &str) -> Completion;
+
+    /// Show whatever comes before the line again, after candidates have been listed below it.
+    fn redraw_prompt(&self);
+}
+
+/// Represents the keyboard input, queued in the order it was typed.
+/// Input typed before it is requested (including pasted lines) is kept until it is read.
 pub struct Stdin {
-    chars: Mutex<Vec<char>>,
-    requesting: Mutex<bool>,
+    events: Mutex<VecDeque<InputEvent>>,
 }
 
 impl Stdin {
-    /// Clear the standard input stream
+    /// Clear the standard input stream, discarding anything typed ahead
     pub fn clear(&self) {
-        let mut chars = self.chars.lock();
-        *chars = Vec::new();
+        interrupts::without_interrupts(|| self.events.lock().clear());
     }
 
-    /// Get a character input (blocking)
-    pub fn get_char(&self) -> char {
-        let chars = self.chars.lock();
-        let mut requesting = self.requesting.lock();
-        let chars_len = chars.len();
-        *requesting = true;
-        drop(requesting);
-        drop(chars);
-
-        loop {
-            let chars = self.chars.lock();
-            let new_len = chars.len();
+    /// Queue characters as if they had been typed.
+    /// Characters past the end of the input buffer are discarded.
+    pub fn feed(&self, text: &str) {
+        for character in text.chars() {
+            self.push(InputEvent::Char(character));
+        }
+    }
 
-            drop(chars);
+    /// Queue an event, unless the input buffer is full.
+    fn push(&self, event: InputEvent) {
+        interrupts::without_interrupts(|| {
+            let mut events = self.events.lock();
+            if events.len() < INPUT_BUFFER_SIZE {
+                events.push_back(event);
+            }
+        });
+    }
 
-            if new_len != chars_len {
-                break;
+    /// Get the next input event (blocking), without echoing it
+    pub fn get_event(&self) -> InputEvent {
+        loop {
+            if let Some(event) = self.try_get_event() {
+                return event;
             }
             crate::idle();
         }
+    }
 
-        let chars = self.chars.lock();
-        let mut requesting = self.requesting.lock();
-        *requesting = false;
+    /// Get the next input event if there is one (non-blocking), without echoing it
+    pub fn try_get_event(&self) -> Option<InputEvent> {
+        interrupts::without_interrupts(|| self.events.lock().pop_front())
+    }
 
-        chars[chars.len() - 1]
+    /// Get a character input (blocking), echoing it to the console.
+    /// Special keys pressed in the meantime are discarded.
+    pub fn get_char(&self) -> char {
+        loop {
+            if let InputEvent::Char(character) = self.get_event() {
+                if !character.is_control() || character == '\n' {
+                    print!("{}", character);
+                }
+                return character;
+            }
+        }
     }
 
-    /// Get a string input (blocking)
+    /// Get a line of input (blocking), without the trailing newline.
+    /// If several lines were typed ahead or pasted, each call returns the next one.
     pub fn get_str(&self) -> String {
-        self.clear();
+        self.read_line(None)
+    }
+
+    /// Get a line of input (blocking) like `get_str`, completing it with the completer when Tab is pressed.
+    pub fn get_str_completing(&self, completer: &dyn Completer) -> String {
+        self.read_line(Some(completer))
+    }
+
+    /// Read a line for `get_str` or `get_str_completing`, where Tab is ignored without a completer.
+    fn read_line(&self, completer: Option<&dyn Completer>) -> String {
         let mut result = String::new();
         let mut new_char = self.get_char();
 
@@ -65,44 +208,185 @@ impl Stdin {
                         writer.overwrite_char(0x20);
                     });
                 }
+            } else if new_char == DELETE_WORD {
+                // Delete any trailing spaces, then the word before them
+                let word_start = result
+                    .trim_end_matches(' ')
+                    .rfind(' ')
+                    .map_or(0, |index| index + 1);
+                let erased_chars = result[word_start..].chars().count();
+                result.truncate(word_start);
+
+                interrupts::without_interrupts(|| {
+                    let mut writer = WRITER.lock();
+                    for _ in 0..erased_chars {
+                        writer.overwrite_char(0x20);
+                    }
+                });
+            } else if new_char == CLEAR_LINE {
+                let erased_chars = result.chars().count();
+                result.clear();
+
+                interrupts::without_interrupts(|| {
+                    let mut writer = WRITER.lock();
+                    for _ in 0..erased_chars {
+                        writer.overwrite_char(0x20);
+                    }
+                });
+            } else if new_char == CLEAR_SCREEN {
+                // The prompt and line being typed are on the last row, so they stay on screen
+                interrupts::without_interrupts(|| WRITER.lock().clear_above());
+            } else if new_char == COMPLETE {
+                if let Some(completer) = completer {
+                    match completer.complete(&result) {
+                        Completion::Append(text) => {
+                            print!("{}", text);
+                            result.push_str(&text);
+                        }
+                        Completion::Candidates(candidates) => {
+                            print!("\n{}\n", candidates.join("  "));
+                            completer.redraw_prompt();
+                            print!("{}", result);
+                        }
+                        Completion::None => (),
+                    }
+                }
             } else {
                 result.push(new_char);
             }
             new_char = self.get_char();
         }
 
-        self.clear();
+        result
+    }
+
+    /// Get a line of input (blocking) without showing what is typed, such as a password.
+    /// If `masked` is set, each character is shown as a `*`, otherwise nothing is shown at all,
+    /// so not even the length of the input is revealed.
+    pub fn get_str_hidden(&self, masked: bool) -> String {
+        let mut result = String::new();
+
+        // Erases the given number of characters from the screen, if they were shown
+        let erase = |count: usize| {
+            if masked {
+                interrupts::without_interrupts(|| {
+                    let mut writer = WRITER.lock();
+                    for _ in 0..count {
+                        writer.overwrite_char(0x20);
+                    }
+                });
+            }
+        };
 
+        loop {
+            let new_char = match self.get_event() {
+                InputEvent::Char(character) => character,
+                _ => continue,
+            };
+
+            if new_char == '\n' {
+                break;
+            } else if new_char == '\x08' {
+                if result.pop().is_some() {
+                    erase(1);
+                }
+            } else if new_char == DELETE_WORD {
+                let word_start = result
+                    .trim_end_matches(' ')
+                    .rfind(' ')
+                    .map_or(0, |index| index + 1);
+                erase(result[word_start..].chars().count());
+                result.truncate(word_start);
+            } else if new_char == CLEAR_LINE {
+                erase(result.chars().count());
+                result.clear();
+            } else if new_char == CLEAR_SCREEN {
+                interrupts::without_interrupts(|| WRITER.lock().clear_above());
+            } else if !new_char.is_control() {
+                if masked {
+                    print!("*");
+                }
+                result.push(new_char);
+            }
+        }
+
+        print!("\n");
         result
     }
 }
 
 lazy_static! {
     pub static ref STDIN: Stdin = Stdin {
-        chars: Mutex::new(Vec::new()),
-        requesting: Mutex::new(false),
+        events: Mutex::new(VecDeque::with_capacity(INPUT_BUFFER_SIZE)),
     };
 }
 
 /// Keyboard interrupt handler, manages keyboard input
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptStackFrame) {
+    crate::interrupts::count(InterruptIndex::Keyboard.as_u8());
+
     lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Uk105Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Uk105Key, ScancodeSet1, HandleControl::Ignore)
-        );
+        static ref KEYBOARD: Mutex<Keyboard<layouts::Uk105Key, ScancodeSet1>> =
+            Mutex::new(Keyboard::new(
+                layouts::Uk105Key,
+                ScancodeSet1,
+                HandleControl::MapLettersToUnicode
+            ));
     }
 
     let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60); // keyboard data port
     let scancode: u8 = unsafe { port.read() }; // get scancode
 
+    // Responses to commands are handled by `send_keyboard_command`, so they aren't keypresses
+    if scancode == KEYBOARD_ACK || scancode == KEYBOARD_RESEND {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        }
+        return;
+    }
+
+    // A keypress which stops the screensaver is still tracked, but isn't used as input
+    let woke_screensaver = crate::screensaver::handle_input();
+
+    let mut decoded_key = None;
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(new_character) => handle_raw_char_input(new_character),
-                DecodedKey::RawKey(key) => handle_raw_key_input(key),
+        let held = match key_event.code {
+            KeyCode::ControlLeft | KeyCode::ControlRight => Some(&CTRL_HELD),
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => Some(&SHIFT_HELD),
+            KeyCode::AltLeft | KeyCode::AltRight => Some(&ALT_HELD),
+            _ => None,
+        };
+        if let Some(held) = held {
+            held.store(key_event.state == KeyState::Down, Ordering::Relaxed);
+        }
+
+        if key_event.state == KeyState::Down {
+            let lock = match key_event.code {
+                KeyCode::CapsLock => Some(&CAPS_LOCK),
+                KeyCode::NumpadLock => Some(&NUM_LOCK),
+                KeyCode::ScrollLock => Some(&SCROLL_LOCK),
+                _ => None,
+            };
+            if let Some(lock) = lock {
+                lock.fetch_xor(true, Ordering::Relaxed);
+                update_keyboard_leds();
             }
         }
+
+        decoded_key = keyboard.process_keyevent(key_event);
+    }
+
+    if woke_screensaver {
+        // Nothing more to do
+    } else if SCANCODE_MODE.load(Ordering::Relaxed) {
+        STDIN.push(InputEvent::Scancode(scancode, decoded_key));
+    } else if let Some(key) = decoded_key {
+        match key {
+            DecodedKey::Unicode(new_character) => handle_raw_char_input(new_character),
+            DecodedKey::RawKey(key) => handle_raw_key_input(key),
+        }
     }
 
     unsafe {
@@ -111,26 +395,172 @@ pub extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptStackFrame)
     }
 }
 
-fn handle_raw_char_input(character: char) {
-    if *STDIN.requesting.lock() {
-        let mut chars = STDIN.chars.lock();
+/// Turn scancode mode on or off.
+/// While it is on, every byte from the keyboard is sent as an `InputEvent::Scancode` instead of being handled as input.
+pub fn set_scancode_mode(enabled: bool) {
+    SCANCODE_MODE.store(enabled, Ordering::Relaxed);
+}
+
+/// Set the keyboard's Caps Lock, Num Lock and Scroll Lock LEDs.
+/// Returns whether the keyboard acknowledged the change.
+pub fn set_keyboard_leds(caps: bool, num: bool, scroll: bool) -> bool {
+    let mask = (caps as u8) << 2 | (num as u8) << 1 | scroll as u8;
 
-        let allowed_chars = ['\n', ' ', '/', '.', '"'];
+    // Interrupts are disabled so the keyboard interrupt handler can't consume the acknowledgements
+    interrupts::without_interrupts(|| {
+        send_keyboard_command(SET_LEDS_COMMAND) && send_keyboard_command(mask)
+    })
+}
 
-        if character.is_alphanumeric() || allowed_chars.contains(&character) {
-            chars.push(character);
-            print!("{}", character);
-        } else {
-            // NON PRINTABLE CHARACTER HANDLING
+/// Set the keyboard LEDs to match the current lock key states.
+pub fn update_keyboard_leds() -> bool {
+    set_keyboard_leds(
+        CAPS_LOCK.load(Ordering::Relaxed),
+        NUM_LOCK.load(Ordering::Relaxed),
+        SCROLL_LOCK.load(Ordering::Relaxed),
+    )
+}
 
-            if character == '\x08' {
-                // Handle backspace
-                chars.push(character);
-            }
+/// Encode a delay in milliseconds before a held key repeats and a rate in repeats per second into a typematic byte,
+/// using the nearest delay (250, 500, 750 or 1000ms) and rate (2 to 30 per second) the keyboard supports.
+pub fn typematic_byte(delay_ms: u16, rate: f64) -> u8 {
+    let delay = (delay_ms.clamp(250, 1000) + 125) / 250 - 1;
+
+    // Rates aren't evenly spaced, so find the nearest by trying all of them
+    let rate_bits = (0..32)
+        .min_by(|a, b| {
+            let distance = |bits| {
+                let difference = typematic_settings(bits).1 - rate;
+                difference * difference
+            };
+            distance(*a)
+                .partial_cmp(&distance(*b))
+                .unwrap_or(core::cmp::Ordering::Equal)
+        })
+        .unwrap();
+
+    (delay as u8) << 5 | rate_bits
+}
+
+/// Decode a typematic byte into the delay in milliseconds before a held key repeats and the repeats per second.
+pub fn typematic_settings(byte: u8) -> (u16, f64) {
+    let delay_ms = (byte.get_bits(5..7) as u16 + 1) * 250;
+    // The repeat period is (8 + A) * 2^B * 4.17ms, where A is the low three bits and B the next two
+    let period_ms = (8 + byte.get_bits(0..3)) as f64 * (1 << byte.get_bits(3..5)) as f64 * 4.17;
+    (delay_ms, 1000.0 / period_ms)
+}
+
+/// Get the delay in milliseconds before a held key repeats and the repeats per second, as last set.
+pub fn typematic() -> (u16, f64) {
+    typematic_settings(TYPEMATIC.load(Ordering::Relaxed))
+}
+
+/// Set how long a key has to be held before it repeats and how often it then repeats, rounding them like
+/// `typematic_byte`. Returns whether the keyboard acknowledged the change.
+pub fn set_typematic(delay_ms: u16, rate: f64) -> bool {
+    let byte = typematic_byte(delay_ms, rate);
+
+    // Interrupts are disabled so the keyboard interrupt handler can't consume the acknowledgements
+    let acknowledged = interrupts::without_interrupts(|| {
+        send_keyboard_command(SET_TYPEMATIC_COMMAND) && send_keyboard_command(byte)
+    });
+    if acknowledged {
+        TYPEMATIC.store(byte, Ordering::Relaxed);
+    }
+    acknowledged
+}
+
+/// Send a byte to the keyboard and wait for it to be acknowledged, resending it if asked to.
+/// Gives up and returns false if the keyboard doesn't respond in time.
+fn send_keyboard_command(byte: u8) -> bool {
+    let mut status_port: Port<u8> = Port::new(0x64); // keyboard controller status port
+    let mut data_port: Port<u8> = Port::new(0x60); // keyboard data port
+
+    for _ in 0..3 {
+        // Wait for the controller's input buffer to be empty (bit 1 clear) before writing
+        if !(0..KEYBOARD_TIMEOUT).any(|_| unsafe { status_port.read() } & 0b10 == 0) {
+            return false;
+        }
+        unsafe { data_port.write(byte) };
+
+        // Wait for the controller's output buffer to be full (bit 0 set) then read the response
+        if !(0..KEYBOARD_TIMEOUT).any(|_| unsafe { status_port.read() } & 0b1 != 0) {
+            return false;
+        }
+        match unsafe { data_port.read() } {
+            KEYBOARD_ACK => return true,
+            KEYBOARD_RESEND => continue,
+            _ => return false,
         }
     }
+
+    false
 }
 
-fn handle_raw_key_input(_key: KeyCode) {
-    /* TODO */
+fn handle_raw_char_input(character: char) {
+    // Any printable ASCII can be typed, such as the dashes of flags
+    let allowed_chars = ['\n', ' '];
+
+    if character.is_alphanumeric()
+        || character.is_ascii_graphic()
+        || allowed_chars.contains(&character)
+    {
+        STDIN.push(InputEvent::Char(character));
+    } else {
+        // NON PRINTABLE CHARACTER HANDLING
+
+        if character == '\x08' && CTRL_HELD.load(Ordering::Relaxed) {
+            // Handle Ctrl+Backspace
+            STDIN.push(InputEvent::Char(DELETE_WORD));
+        } else if character == '\x08' {
+            // Handle backspace
+            STDIN.push(InputEvent::Char(character));
+        } else if [DELETE_WORD, CLEAR_SCREEN, CLEAR_LINE, COMPLETE].contains(&character) {
+            // Handle Ctrl+W, Ctrl+L, Ctrl+U and Tab, which are decoded as control characters
+            STDIN.push(InputEvent::Char(character));
+        } else if ('\x01'..='\x1a').contains(&character) && CTRL_HELD.load(Ordering::Relaxed) {
+            // Handle other Ctrl+letter shortcuts, where Ctrl+A is decoded as 0x01 and so on
+            let letter = (character as u8 - 1 + b'a') as char;
+            STDIN.push(InputEvent::Key(SpecialKey::Ctrl(letter)));
+        } else if character == '\x7f' {
+            // The delete key is decoded as the DEL character
+            STDIN.push(InputEvent::Key(SpecialKey::Delete));
+        } else if character == '\x1b' {
+            // As is the escape key
+            STDIN.push(InputEvent::Key(SpecialKey::Escape));
+        }
+    }
+}
+
+/// Dispatches keys which don't produce a character into the input queue as `SpecialKey`s.
+/// Keys without a `SpecialKey` (e.g. modifiers) are ignored.
+fn handle_raw_key_input(key: KeyCode) {
+    let special_key = match key {
+        KeyCode::ArrowUp => SpecialKey::Up,
+        KeyCode::ArrowDown => SpecialKey::Down,
+        KeyCode::ArrowLeft => SpecialKey::Left,
+        KeyCode::ArrowRight => SpecialKey::Right,
+        KeyCode::Home => SpecialKey::Home,
+        KeyCode::End => SpecialKey::End,
+        KeyCode::PageUp => SpecialKey::PageUp,
+        KeyCode::PageDown => SpecialKey::PageDown,
+        KeyCode::Insert => SpecialKey::Insert,
+        KeyCode::Delete => SpecialKey::Delete,
+        KeyCode::Escape => SpecialKey::Escape,
+        KeyCode::F1 => SpecialKey::Function(1),
+        KeyCode::F2 => SpecialKey::Function(2),
+        KeyCode::F3 => SpecialKey::Function(3),
+        KeyCode::F4 => SpecialKey::Function(4),
+        KeyCode::F5 => SpecialKey::Function(5),
+        KeyCode::F6 => SpecialKey::Function(6),
+        KeyCode::F7 => SpecialKey::Function(7),
+        KeyCode::F8 => SpecialKey::Function(8),
+        KeyCode::F9 => SpecialKey::Function(9),
+        KeyCode::F10 => SpecialKey::Function(10),
+        KeyCode::F11 => SpecialKey::Function(11),
+        KeyCode::F12 => SpecialKey::Function(12),
+        _ => return,
+    };
+
+    STDIN.push(InputEvent::Key(special_key));
 }
@@ -1,10 +1,13 @@
 // Handles interrupts.
 // Double faults are handled with a delightful message.
 
-use crate::{gdt, println};
+use crate::{gdt, interrupt_print};
+use bit_field::BitField;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use x86_64::instructions::{interrupts, port::Port};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -15,6 +18,10 @@ pub const PIC_2_OFFSET: u8 = 40;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ 7, which the primary PIC raises when an interrupt goes away before it can be delivered.
+    PrimarySpurious = PIC_1_OFFSET + 7,
+    /// IRQ 15, which the secondary PIC raises in the same way.
+    SecondarySpurious = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
@@ -27,6 +34,52 @@ impl InterruptIndex {
     }
 }
 
+/// Vectors of the CPU exceptions which have handlers.
+const DIVIDE_ERROR_VECTOR: u8 = 0;
+const BREAKPOINT_VECTOR: u8 = 3;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+
+/// Every vector with a handler and what it is called, in the order `counts` lists them.
+const HANDLED_VECTORS: [(u8, &str); 7] = [
+    (DIVIDE_ERROR_VECTOR, "divide error"),
+    (BREAKPOINT_VECTOR, "breakpoint"),
+    (DOUBLE_FAULT_VECTOR, "double fault"),
+    (InterruptIndex::Timer as u8, "timer"),
+    (InterruptIndex::Keyboard as u8, "keyboard"),
+    (InterruptIndex::PrimarySpurious as u8, "irq 7"),
+    (InterruptIndex::SecondarySpurious as u8, "irq 15"),
+];
+
+/// IRQ lines left unmasked by `init_pics`: the timer, the keyboard, and the secondary PIC's cascade into the
+/// primary, without which none of the secondary's lines could get through.
+const UNMASKED_IRQS: [u8; 3] = [0, 1, 2];
+
+/// Command to make the next read of a PIC's command port return its in-service register.
+const READ_IN_SERVICE: u8 = 0x0B;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of times the handler for each vector has run.
+static COUNTS: [AtomicUsize; 256] = [ZERO_COUNT; 256];
+
+/// Count that the handler for a vector has run, which handlers do first.
+#[inline]
+pub fn count(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Get the vector, name and number of times run of every interrupt handler.
+pub fn counts() -> impl Iterator<Item = (u8, &'static str, usize)> {
+    HANDLED_VECTORS.iter().map(|(vector, name)| {
+        (
+            *vector,
+            *name,
+            COUNTS[*vector as usize].load(Ordering::Relaxed),
+        )
+    })
+}
+
 /// Programmable interrupt controller
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -34,6 +87,7 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             idt.double_fault
@@ -44,6 +98,9 @@ lazy_static! {
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(crate::input::keyboard_interrupt_handler);
+        idt[InterruptIndex::PrimarySpurious.as_usize()].set_handler_fn(primary_spurious_handler);
+        idt[InterruptIndex::SecondarySpurious.as_usize()]
+            .set_handler_fn(secondary_spurious_handler);
 
         idt
     };
@@ -53,9 +110,74 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Initialise the PICs, masking every IRQ line without a handler so unexpected devices can't interrupt.
+pub fn init_pics() {
+    let mask = !UNMASKED_IRQS.iter().fold(0u16, |mask, irq| mask | 1 << irq);
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        pics.write_masks(mask as u8, (mask >> 8) as u8);
+    }
+}
+
+/// Get which IRQ lines are masked, with bit n set if IRQ n is.
+pub fn irq_mask() -> u16 {
+    let [primary, secondary] =
+        interrupts::without_interrupts(|| unsafe { PICS.lock().read_masks() });
+    u16::from_le_bytes([primary, secondary])
+}
+
+/// Mask an IRQ line (0 to 15), so its interrupts are ignored until it is unmasked.
+pub fn mask_irq(irq: u8) {
+    set_irq_masked(irq, true);
+}
+
+/// Unmask an IRQ line (0 to 15), so its interrupts are delivered.
+/// Lines on the secondary PIC (8 to 15) also need IRQ 2 unmasked, as it is masked by default.
+pub fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+}
+
+fn set_irq_masked(irq: u8, masked: bool) {
+    // The keyboard handler locks the PICs too, so it mustn't interrupt this
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [primary, secondary] = unsafe { pics.read_masks() };
+        let mut mask = u16::from_le_bytes([primary, secondary]);
+        mask.set_bit(irq as usize, masked);
+        let [primary, secondary] = mask.to_le_bytes();
+        unsafe { pics.write_masks(primary, secondary) };
+    });
+}
+
+/// Read which IRQs the PICs are servicing, with bit n set if IRQ n is.
+/// The PICs have to be locked first, so nothing else uses their command ports meanwhile.
+fn in_service_irqs(_pics: &ChainedPics) -> u16 {
+    let mut primary: Port<u8> = Port::new(0x20); // primary PIC command port
+    let mut secondary: Port<u8> = Port::new(0xA0); // secondary PIC command port
+    unsafe {
+        primary.write(READ_IN_SERVICE);
+        secondary.write(READ_IN_SERVICE);
+        u16::from_le_bytes([primary.read(), secondary.read()])
+    }
+}
+
+/// Divide error exception handler, for dividing by zero or a quotient too big for its register.
+/// Returning would run the faulting instruction again, so the CPU is halted instead once the error is shown.
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    count(DIVIDE_ERROR_VECTOR);
+    interrupt_print!(
+        "EXCEPTION: DIVIDE ERROR at {:#x}\n{:#?}\nthe cpu has been halted\n",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame
+    );
+    crate::idle_loop();
+}
+
 /// Breakpoint exception handler
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    count(BREAKPOINT_VECTOR);
+    interrupt_print!("EXCEPTION: BREAKPOINT\n{:#?}\n", stack_frame);
 }
 
 /// Double exception handler, basically a crash but not quite
@@ -63,6 +185,7 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    count(DOUBLE_FAULT_VECTOR);
     panic!(
         "{}: AN EXTREMELY LARGE OOF OCCURRED, NOT POG:\n{:?}",
         error_code, stack_frame
@@ -71,10 +194,42 @@ extern "x86-interrupt" fn double_fault_handler(
 
 /// Timer interrupt handler
 extern "x86-interrupt" fn timer_interrupt_handler(_: InterruptStackFrame) {
+    count(InterruptIndex::Timer.as_u8());
     crate::time::handle_pit_interrupt();
+    crate::statusbar::handle_tick();
+    crate::screensaver::handle_tick();
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
 }
+
+/// IRQ 7 handler, which is masked so only runs for spurious interrupts.
+/// A spurious IRQ 7 isn't in service, so it mustn't be acknowledged or a real interrupt would be ended instead.
+extern "x86-interrupt" fn primary_spurious_handler(_: InterruptStackFrame) {
+    let vector = InterruptIndex::PrimarySpurious.as_u8();
+    count(vector);
+
+    let mut pics = PICS.lock();
+    if in_service_irqs(&pics).get_bit(7) {
+        unsafe { pics.notify_end_of_interrupt(vector) };
+    }
+}
+
+/// IRQ 15 handler, which is masked so only runs for spurious interrupts.
+/// The secondary PIC mustn't be acknowledged for a spurious IRQ 15, but the primary still must, as it did pass the
+/// cascade interrupt on.
+extern "x86-interrupt" fn secondary_spurious_handler(_: InterruptStackFrame) {
+    let vector = InterruptIndex::SecondarySpurious.as_u8();
+    count(vector);
+
+    let mut pics = PICS.lock();
+    let acknowledged = if in_service_irqs(&pics).get_bit(15) {
+        vector
+    } else {
+        // Only the primary PIC is acknowledged for its own vectors
+        PIC_1_OFFSET
+    };
+    unsafe { pics.notify_end_of_interrupt(acknowledged) };
+}
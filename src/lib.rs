@@ -1,37 +1,58 @@
 #![no_std]
+#![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 pub mod allocator; // heap allocation
 pub mod ata; // drive management
+pub mod block; // block device abstraction
+pub mod calc; // arithmetic expressions
 pub mod conhost; // console input
+pub mod cpu; // processor identification
+pub mod editor; // line editor
 pub mod fs; // filesystem
 pub mod gdt; // stack allocation for interrupts
+pub mod graphics; // vga graphics mode
 pub mod input; // input handling
 pub mod interrupts; // interrupt and exception handling
 pub mod mem; // paging
+pub mod pattern; // wildcard pattern matching
+pub mod screensaver; // animation shown after a while without input
+pub mod selftest; // smoke tests
+pub mod serial; // serial port output
+pub mod speaker; // pc speaker
+pub mod statusbar; // status bar at the top of the screen
 pub mod time; // everything to do with time
 pub mod vga; // console output
 extern crate alloc; // lower level heap allocation
 
+use alloc::format;
 use bootloader::BootInfo;
 use core::fmt::Display;
-use vga::okay;
+use vga::{info, okay};
 use x86_64::addr::VirtAddr;
+use x86_64::instructions::port::Port;
 
 /// Initialises the kernel
 pub fn init(boot_info: &'static BootInfo) {
+    serial::init(); // initialise serial port so logs can be mirrored to it
     gdt::init(); // initialise global descriptor table
     interrupts::init_idt(); // initialise interrupt descriptor table
     okay("initialised stack allocation\n");
-    unsafe { interrupts::PICS.lock().initialize() }; // initialise interrupt controller
+    interrupts::init_pics(); // initialise interrupt controller, masking unused IRQs
     x86_64::instructions::interrupts::enable(); // enable interrupts
     okay("initialised interrupt handling\n");
+    input::update_keyboard_leds(); // the LEDs may not match the initial lock key states
     time::init();
     okay("initialised time functions\n");
 
     // Initialise heap allocation
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    graphics::init(boot_info.physical_memory_offset);
+    mem::record_memory_map(&boot_info.memory_map, boot_info.physical_memory_offset);
     let mut mapper = unsafe { mem::mapper(physical_memory_offset) };
     let mut frame_allocator = unsafe { mem::BootInfoFrameAllocator::new(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
@@ -40,7 +61,8 @@ pub fn init(boot_info: &'static BootInfo) {
     // Initialise disks and filesystem
     ata::init();
     okay("initialised hard disk drivers\n");
-    fs::detect_fs();
+    // Nobody is there to answer when running tests, so never create a filesystem then
+    fs::detect_fs(|prompt| !cfg!(test) && conhost::confirm(prompt));
 }
 
 /// Represents a status code from a process.
@@ -54,6 +76,14 @@ pub enum ExitCode {
     NotMountedError,
     NotEmptyError,
     InvalidCommandError,
+    PermissionError,
+    NoFilesystemError,
+    UnsupportedFilesystemError,
+    DiskFullError,
+    VerifyError,
+    TimeoutError,
+    AlreadyExistsError,
+    EncryptedError,
 }
 
 impl Display for ExitCode {
@@ -61,30 +91,119 @@ impl Display for ExitCode {
         write!(
             f,
             "{}",
+            // There's deliberately no wildcard arm, so every new variant has to be given a message
             match self {
                 ExitCode::Success => "process exited successfully",
                 ExitCode::Error => "an unknown error occurred",
                 ExitCode::ParseError => "an error was encountered parsing the command",
                 ExitCode::NotFoundError => "the requested file or directory was not found",
-                ExitCode::NotEmptyError => "the directory is not empty",
-                ExitCode::InvalidCommandError => "command not found",
                 ExitCode::NotMountedError =>
                     "no filesystem is mounted so file operations are unavailable",
+                ExitCode::NotEmptyError => "the directory is not empty",
+                ExitCode::InvalidCommandError => "command not found",
+                ExitCode::PermissionError => "the file or directory is read-only",
+                ExitCode::NoFilesystemError => "no filesystem was found on the drive",
+                ExitCode::UnsupportedFilesystemError =>
+                    "the filesystem was created by a newer version of pogostick",
+                ExitCode::DiskFullError => "there isn't enough free space on the disk",
+                ExitCode::VerifyError =>
+                    "the data read back from the disk didn't match what was written",
+                ExitCode::TimeoutError => "the drive took too long to respond",
+                ExitCode::AlreadyExistsError => "a file or directory with that name already exists",
+                ExitCode::EncryptedError => "the file is encrypted, so it needs its passphrase",
             }
         )
     }
 }
 
+/// Represents a status reported to QEMU through its `isa-debug-exit` device.
+/// QEMU then exits with the status `(code << 1) | 1`.
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exits QEMU with the given status using the `isa-debug-exit` device on port 0xf4.
+/// Has no effect if the device isn't present, so callers must be prepared for it to return.
+pub fn test_exit(code: QemuExitCode) {
+    unsafe { Port::new(0xf4).write(code as u32) };
+}
+
+/// Runs the tests when the kernel is built with `cargo test`, then exits QEMU with the result.
+/// Functions marked with `#[test_case]` run first and fail by panicking, then the self-tests in `selftest` run.
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    info(&format!("running {} test case(s)\n", tests.len()));
+    for test in tests {
+        test();
+    }
+
+    selftest::run();
+    // The self-tests exit QEMU themselves, so this is only reached without the exit device
+    idle_loop();
+}
+
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
+/// Entry point for `cargo test`, which initialises the kernel then runs the tests instead of the shell.
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
+    test_main();
+    idle_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    unsafe { force_unlock_all() };
+    serial_println!("[ PANIC ] {}", info);
+    test_exit(QemuExitCode::Failed);
+    idle_loop();
+}
+
+/// Whether a panic reboots the machine rather than halting it so the message can be read.
+/// Set by building with the `reboot-on-panic` feature.
+pub const REBOOT_ON_PANIC: bool = cfg!(feature = "reboot-on-panic");
+
+/// Reboots the machine by pulsing the CPU reset line through the keyboard controller.
+/// If that doesn't work, the CPU is halted instead.
+pub fn reboot() -> ! {
+    let mut status_port: Port<u8> = Port::new(0x64);
+    unsafe {
+        // Wait for the controller's input buffer to be empty before sending the command
+        while status_port.read() & 0x02 != 0 {
+            core::hint::spin_loop();
+        }
+        status_port.write(0xFE);
+    }
+    idle_loop();
+}
+
+/// Forcibly unlock the kernel's global mutexes, so they can still be used after a panic.
+/// Whatever held them won't get to finish with them, so only call this when that can't continue anyway.
+pub unsafe fn force_unlock_all() {
+    vga::WRITER.force_unlock();
+    fs::FILESYSTEM.force_unlock();
+    ata::BUSES.force_unlock();
+    block::DEVICES.force_unlock();
+    serial::SERIAL1.force_unlock();
+}
+
 /// Forever sends halt instructions allowing the CPU to idle
 pub fn idle_loop() -> ! {
     loop {
-        x86_64::instructions::hlt();
+        idle();
     }
 }
 
 /// Alias for `x86_64::instructions::hlt();`
+/// Time spent halted here is counted towards `time::cpu_idle_percent`.
 pub fn idle() {
+    time::IDLING.store(true, core::sync::atomic::Ordering::Relaxed);
     x86_64::instructions::hlt();
+    time::IDLING.store(false, core::sync::atomic::Ordering::Relaxed);
 }
 
 #[alloc_error_handler]
@@ -3,7 +3,7 @@
 
 extern crate alloc;
 use bootloader::{entry_point, BootInfo};
-use pogostick::{conhost, println};
+use pogostick::{conhost, serial_println, vga};
 
 entry_point!(kernel_main);
 
@@ -14,6 +14,13 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
-    println!("{}", _info);
+    unsafe { pogostick::force_unlock_all() };
+    vga::emergency_print(format_args!("\n{}\n", _info));
+    // Log the message before rebooting, so it isn't lost with the screen
+    serial_println!("[ PANIC ] {}", _info);
+
+    if pogostick::REBOOT_ON_PANIC {
+        pogostick::reboot();
+    }
     pogostick::idle_loop();
 }
@@ -2,43 +2,93 @@
 // Allocates frames and manages pages, otherwise uses `allocator.rs`.
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::{
     registers::control::Cr3,
     structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
     PhysAddr, VirtAddr,
 };
 
+/// Summary of the bootloader's memory map.
+/// The map is only looked at during `init`, so what's worth knowing afterwards is copied into this.
+#[derive(Debug, Clone, Copy)]
+pub struct MemorySummary {
+    pub usable_bytes: u64,
+    pub usable_regions: usize,
+    pub reserved_regions: usize, // regions which can't be allocated from, such as the kernel's or the firmware's
+    pub highest_addr: u64,       // end of the highest region
+    pub physical_memory_offset: u64,
+}
+
+lazy_static! {
+    static ref MEMORY_SUMMARY: Mutex<Option<MemorySummary>> = Mutex::new(None);
+}
+
+/// Summarise the memory map so it can be looked at with `memory_summary` after `init`.
+pub fn record_memory_map(memory_map: &MemoryMap, physical_memory_offset: u64) {
+    let usable = memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable);
+
+    *MEMORY_SUMMARY.lock() = Some(MemorySummary {
+        usable_bytes: usable
+            .clone()
+            .map(|region| region.range.end_addr() - region.range.start_addr())
+            .sum(),
+        usable_regions: usable.count(),
+        reserved_regions: memory_map
+            .iter()
+            .filter(|region| region.region_type != MemoryRegionType::Usable)
+            .count(),
+        highest_addr: memory_map
+            .iter()
+            .map(|region| region.range.end_addr())
+            .max()
+            .unwrap_or(0),
+        physical_memory_offset,
+    });
+}
+
+/// Get the summary of the memory map made during `init`, or `None` if it hasn't been made yet.
+pub fn memory_summary() -> Option<MemorySummary> {
+    *MEMORY_SUMMARY.lock()
+}
+
 /// A frame allocator relying on the bootloader's memory map
+/// Remembers where it got to in the map, so allocating a frame doesn't have to skip over every frame allocated before it.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    region: usize,  // index of the memory map region frames are being allocated from
+    next_addr: u64, // start address of the next frame in that region, or 0 for the start of the region
 }
 
 impl BootInfoFrameAllocator {
     /// Create new allocator
+    /// Unsafe as the caller must guarantee that every region of the memory map marked as usable really is unused.
     pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator {
             memory_map,
-            next: 0,
+            region: 0,
+            next_addr: 0,
         }
     }
-
-    /// Find usable frames
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
-            .iter() // iterate over frames
-            .filter(|r| r.region_type == MemoryRegionType::Usable) // filter out unusable frames
-            .map(|r| r.range.start_addr()..r.range.end_addr()) // map each region to address range
-            .flat_map(|r| r.step_by(4096)) // get down to just start addresses
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr))) // create `PhysFrame` types
-    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        // Move on through the regions until one has a usable frame left, stopping at the end of the map
+        loop {
+            let region = self.memory_map.get(self.region)?;
+            let addr = self.next_addr.max(region.range.start_addr());
+            if region.region_type == MemoryRegionType::Usable && addr < region.range.end_addr() {
+                self.next_addr = addr + 4096;
+                return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+            }
+
+            self.region += 1;
+            self.next_addr = 0;
+        }
     }
 }
 
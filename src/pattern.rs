@@ -0,0 +1,156 @@
+// Pattern matching
+// Wildcard patterns shared by commands which match names or text, such as `grep` and `cat *.log`.
+//
+// `*` matches any run of characters, `?` matches any one character, and `[abc]` matches one of the characters in the
+// brackets, which can include ranges such as `[a-z]` and be negated with `[!abc]`. A backslash makes the character
+// after it match itself, so `\*` only matches a star.
+
+use alloc::vec::Vec;
+
+/// Part of a pattern, which matches one character except for `Star`.
+enum Token {
+    Literal(char),
+    Any,
+    Star,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    /// Checks whether a character matches the token, which is never true for `Star`.
+    fn matches(&self, character: char) -> bool {
+        match self {
+            Token::Literal(literal) => *literal == character,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, ranges } => {
+                let in_class = ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&character));
+                in_class != *negated
+            }
+        }
+    }
+}
+
+/// Checks whether the whole of the text matches the pattern, as file names are matched.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    matches_tokens(&parse(pattern), &text)
+}
+
+/// Checks whether any part of the text matches the pattern, as lines are searched by `grep`.
+/// A pattern starting with `^` has to match at the start of the text, and one ending with `$` at the end.
+pub fn matches_anywhere(pattern: &str, text: &str) -> bool {
+    let (anchored_start, pattern) = match pattern.strip_prefix('^') {
+        Some(rest) => (true, rest),
+        None => (false, pattern),
+    };
+    let (anchored_end, pattern) = match pattern.strip_suffix('$') {
+        Some(rest) if !rest.ends_with('\\') => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let mut tokens = parse(pattern);
+    if !anchored_start {
+        tokens.insert(0, Token::Star);
+    }
+    if !anchored_end {
+        tokens.push(Token::Star);
+    }
+
+    let text: Vec<char> = text.chars().collect();
+    matches_tokens(&tokens, &text)
+}
+
+/// Splits a pattern into tokens.
+/// A `[` without a closing `]` matches itself, as does a backslash at the end.
+fn parse(pattern: &str) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let token = match chars[i] {
+            '*' => Token::Star,
+            '?' => Token::Any,
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                Token::Literal(chars[i])
+            }
+            '[' => match parse_class(&chars[i + 1..]) {
+                Some((class, length)) => {
+                    i += length;
+                    class
+                }
+                None => Token::Literal('['),
+            },
+            character => Token::Literal(character),
+        };
+        tokens.push(token);
+        i += 1;
+    }
+
+    tokens
+}
+
+/// Parses the inside of a character class, starting just after the `[`.
+/// Returns the class and the number of characters it used including the `]`, or None if it isn't closed.
+/// A `]` straight after the `[` (or `[!`) is part of the class rather than closing it.
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let negated = chars.first() == Some(&'!');
+    let mut i = negated as usize;
+    let mut ranges = Vec::new();
+
+    loop {
+        let start = *chars.get(i)?;
+        if start == ']' && !ranges.is_empty() {
+            return Some((Token::Class { negated, ranges }, i + 1));
+        }
+
+        // A dash before the closing bracket is just a dash
+        match (chars.get(i + 1), chars.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                ranges.push((start, end));
+                i += 3;
+            }
+            _ => {
+                ranges.push((start, start));
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Checks whether the whole of the text matches the tokens.
+/// When a match fails after a `Star`, it is retried with the star matching one more character, which only ever has
+/// to go back to the latest star, so this takes at most the product of the lengths rather than exponential time.
+fn matches_tokens(tokens: &[Token], text: &[char]) -> bool {
+    let (mut t, mut c) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while c < text.len() {
+        match tokens.get(t) {
+            Some(Token::Star) => {
+                backtrack = Some((t, c));
+                t += 1;
+            }
+            Some(token) if token.matches(text[c]) => {
+                t += 1;
+                c += 1;
+            }
+            _ => match backtrack {
+                Some((star_t, star_c)) => {
+                    backtrack = Some((star_t, star_c + 1));
+                    t = star_t + 1;
+                    c = star_c + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    tokens[t..].iter().all(|token| matches!(token, Token::Star))
+}
@@ -0,0 +1,175 @@
+// Screensaver
+// Covers the screen with bouncing text after a while without input, putting it back on the next keypress.
+
+use crate::time;
+use crate::vga::{Colour, ColourCode, Writer, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Seconds without input before the screensaver starts, unless another time is set.
+pub const DEFAULT_TIMEOUT: usize = 60;
+
+/// Software timer which fires when there hasn't been any input for the timeout.
+const IDLE_TIMER: usize = 0x5C00;
+/// Software timer which fires when the animation should move on a frame.
+const FRAME_TIMER: usize = 0x5C01;
+/// Milliseconds between frames of the animation.
+const FRAME_INTERVAL: usize = 150;
+
+/// Text which bounces around the screen.
+const TEXT: &[u8] = b"pogostick";
+/// Colours the text cycles through each time it bounces.
+const COLOURS: [Colour; 6] = [
+    Colour::LightCyan,
+    Colour::LightGreen,
+    Colour::Yellow,
+    Colour::LightRed,
+    Colour::Pink,
+    Colour::LightBlue,
+];
+
+/// Whether the screensaver starts after the timeout, which it doesn't until it is turned on.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Seconds without input before the screensaver starts.
+static TIMEOUT: AtomicUsize = AtomicUsize::new(DEFAULT_TIMEOUT);
+/// Whether the screensaver is showing.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Whether there has been input since the screensaver started, so it should stop at the next tick.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+/// Position and direction of the bouncing text.
+struct Bouncer {
+    row: usize,
+    col: usize,
+    down: bool,
+    right: bool,
+    colour: usize,
+}
+
+impl Bouncer {
+    /// Rub out the text, move it diagonally and draw it again, turning and changing colour at the edges.
+    fn draw_frame(&mut self, writer: &mut Writer) {
+        let blank = ColourCode::new(Colour::Black, Colour::Black);
+        for offset in 0..TEXT.len() {
+            writer.draw_overlay(b' ', blank, self.row, self.col + offset);
+        }
+
+        let last_row = BUFFER_HEIGHT - 1;
+        let last_col = BUFFER_WIDTH - TEXT.len();
+        if (self.down && self.row >= last_row) || (!self.down && self.row == 0) {
+            self.down = !self.down;
+            self.colour = (self.colour + 1) % COLOURS.len();
+        }
+        if (self.right && self.col >= last_col) || (!self.right && self.col == 0) {
+            self.right = !self.right;
+            self.colour = (self.colour + 1) % COLOURS.len();
+        }
+        self.row = if self.down {
+            self.row + 1
+        } else {
+            self.row - 1
+        };
+        self.col = if self.right {
+            self.col + 1
+        } else {
+            self.col - 1
+        };
+
+        let colour = ColourCode::new(COLOURS[self.colour], Colour::Black);
+        for (offset, byte) in TEXT.iter().enumerate() {
+            writer.draw_overlay(*byte, colour, self.row, self.col + offset);
+        }
+    }
+}
+
+lazy_static! {
+    static ref BOUNCER: Mutex<Bouncer> = Mutex::new(Bouncer {
+        row: 0,
+        col: 0,
+        down: true,
+        right: true,
+        colour: 0,
+    });
+}
+
+/// Turn the screensaver on or off, setting how many seconds without input it waits for before starting.
+pub fn set(enabled: bool, timeout: usize) {
+    TIMEOUT.store(timeout.max(1), Ordering::Relaxed);
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if enabled {
+        restart_idle_timer();
+    } else {
+        time::cancel_timer(IDLE_TIMER);
+    }
+}
+
+/// Get whether the screensaver is on, and how many seconds without input it waits for.
+pub fn settings() -> (bool, usize) {
+    (
+        ENABLED.load(Ordering::Relaxed),
+        TIMEOUT.load(Ordering::Relaxed),
+    )
+}
+
+/// Start waiting for the timeout again, as there has been input.
+fn restart_idle_timer() {
+    time::set_timer(TIMEOUT.load(Ordering::Relaxed) * 1000, IDLE_TIMER, false);
+}
+
+/// Note that there has been input, so the screensaver is stopped or its timeout starts again.
+/// Returns true if the screensaver was showing, so the input only woke it up and shouldn't be used.
+/// Called by the keyboard interrupt handler.
+pub fn handle_input() -> bool {
+    if ENABLED.load(Ordering::Relaxed) {
+        restart_idle_timer();
+    }
+
+    if ACTIVE.load(Ordering::Relaxed) {
+        WOKEN.store(true, Ordering::Relaxed);
+        true
+    } else {
+        false
+    }
+}
+
+/// Start or stop the screensaver, or draw the next frame of it, when its timers say so.
+/// Called by the timer interrupt handler.
+/// If the writer is locked, nothing is drawn and the timers are checked again next tick.
+pub fn handle_tick() {
+    let active = ACTIVE.load(Ordering::Relaxed);
+    if !active && !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut writer = match WRITER.try_lock() {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    if active && WOKEN.swap(false, Ordering::Relaxed) {
+        time::cancel_timer(FRAME_TIMER);
+        writer.hide_overlay();
+        ACTIVE.store(false, Ordering::Relaxed);
+    } else if active && time::timer_fired(FRAME_TIMER) {
+        if let Some(mut bouncer) = BOUNCER.try_lock() {
+            bouncer.draw_frame(&mut writer);
+        }
+    } else if !active && time::timer_fired(IDLE_TIMER) {
+        writer.show_overlay();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                writer.draw_overlay(
+                    b' ',
+                    ColourCode::new(Colour::Black, Colour::Black),
+                    row,
+                    col,
+                );
+            }
+        }
+
+        WOKEN.store(false, Ordering::Relaxed);
+        ACTIVE.store(true, Ordering::Relaxed);
+        time::set_timer(FRAME_INTERVAL, FRAME_TIMER, true);
+    }
+}
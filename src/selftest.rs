@@ -0,0 +1,1441 @@
+// Self-test runner
+// Runs smoke tests from inside the kernel then exits QEMU with the result, so they can be scripted.
+// They run from the `selftest` command, or from `cargo test` through `test_runner` in a kernel built for testing.
+// QEMU must be started with `-device isa-debug-exit,iobase=0xf4,iosize=0x04` for the exit to work.
+
+use crate::block::{self, BlockDevice, RamDrive};
+use crate::fs::{self, FileSystem, FileType, FileWriter, Superblock};
+use crate::input::{Completion, STDIN};
+use crate::time::{self, DateTime};
+use crate::vga::{err, info, okay};
+use crate::{allocator, ata, conhost, speaker, ExitCode, QemuExitCode};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+/// Every test to run, with its name.
+/// Each test returns whether it passed.
+const TESTS: &[(&str, fn() -> bool)] = &[
+    ("ata: drives report a capacity", ata_drives_have_sectors),
+    ("ata: reading a sector is repeatable", ata_read_repeatable),
+    (
+        "ata: drives are numbered by bus then position",
+        ata_drives_numbered,
+    ),
+    ("heap: freed memory is reused", heap_reuse),
+    ("time: unix timestamps round trip", time_unix_round_trip),
+    ("time: ordering across month boundaries", time_ordering),
+    (
+        "time: years past 2099 keep their century",
+        time_next_century,
+    ),
+    ("time: software timers fire and repeat", time_timers),
+    (
+        "interrupts: timer interrupts are counted",
+        interrupts_counted,
+    ),
+    ("interrupts: unused irq lines are masked", interrupts_masked),
+    (
+        "speaker: note names parse to frequencies",
+        speaker_parse_note,
+    ),
+    (
+        "block: free runs skip used and reserved sectors",
+        block_available_run,
+    ),
+    (
+        "block: unreadable sectors are never available",
+        block_unreadable_not_available,
+    ),
+    (
+        "block: verified writes catch bad sectors",
+        block_verified_write,
+    ),
+    (
+        "block: RAM drives refuse sectors past their end",
+        block_ram_drive_bounds,
+    ),
+    (
+        "block: several drives are addressed separately",
+        block_several_drives,
+    ),
+    (
+        "block: devices of different types are numbered together",
+        block_mixed_devices,
+    ),
+    ("input: pasted lines stay separate", input_pasted_lines),
+    ("input: hidden lines handle backspace", input_hidden_line),
+    ("input: confirmation needs a yes", input_confirm),
+    (
+        "input: key repeat settings round to the nearest supported",
+        input_typematic,
+    ),
+    ("pattern: wildcards match whole names", pattern_names),
+    (
+        "shell: mistyped commands get suggestions",
+        shell_suggestions,
+    ),
+    ("shell: every command has its own name", shell_command_names),
+    ("shell: command names complete", shell_completion),
+    ("calc: operators follow precedence", calc_precedence),
+    ("calc: malformed expressions are refused", calc_errors),
+    ("pattern: anchors fix text to its ends", pattern_text),
+    ("fs: multi-sector files read back intact", fs_write_and_read),
+    ("fs: files are split into full sectors", fs_write_chunks),
+    ("fs: file handles read and seek", fs_file_handle),
+    (
+        "fs: file handles decode compressed files",
+        fs_compressed_file_handle,
+    ),
+    ("fs: files record when they were written", fs_modified_time),
+    ("fs: encrypted files need the passphrase", fs_encrypted_file),
+    ("fs: compression never grows files", fs_compressed_file),
+    ("fs: deleting a file frees its sectors", fs_delete_file),
+    ("fs: free space is recounted after changes", fs_free_sectors),
+    ("fs: files can be created in directories", fs_create_dir),
+    ("fs: listings report entry types", fs_list_entries),
+    (
+        "fs: entries show directories with a slash",
+        fs_file_type_helpers,
+    ),
+    ("fs: directories span several tables", fs_large_dir),
+    ("fs: new directories are empty", fs_new_dir_empty),
+    ("fs: formatting frees old sectors", fs_format_clears),
+    ("fs: directories delete recursively", fs_delete_recursive),
+    ("fs: full disks are left unchanged", fs_disk_full),
+    ("fs: replacing a file keeps its flags", fs_replace_file),
+    (
+        "fs: reserved sectors are never allocated",
+        fs_reserved_sectors,
+    ),
+    ("fs: the default drive is remembered", fs_default_drive),
+    (
+        "fs: interrupted table updates are finished",
+        fs_journal_replay,
+    ),
+    ("fs: soft deleted files can be restored", fs_undelete),
+    (
+        "fs: soft deleting marks older disks with the new version",
+        fs_undelete_older_version,
+    ),
+    (
+        "fs: deleted files are purged when space is needed",
+        fs_purge_deleted,
+    ),
+    ("fs: files copy across drives", fs_copy_across_drives),
+    ("fs: formatted output is written to files", fs_file_writer),
+    (
+        "fs: writers keep the old file if it can't be replaced",
+        fs_file_writer_disk_full,
+    ),
+    (
+        "fs: writers can replace the file they were given",
+        fs_file_writer_same_file,
+    ),
+    ("fs: disks without a superblock open", fs_legacy_disk),
+    ("fs: newer format versions are refused", fs_newer_version),
+    (
+        "fs: superblocks of smaller filesystems reload",
+        fs_smaller_superblock,
+    ),
+    ("fs: oversized size fields are clamped", fs_size_clamped),
+    ("fs: continuation cycles are broken", fs_cycles_broken),
+];
+
+/// Runs every test, printing the results, then exits QEMU with the overall result.
+/// If QEMU can't be exited, the overall result is returned instead.
+pub fn run() -> ExitCode {
+    let mut failed = 0;
+
+    info(&format!("running {} tests\n", TESTS.len()));
+    for (name, test) in TESTS {
+        if test() {
+            okay(&format!("{}\n", name));
+        } else {
+            err(name);
+            failed += 1;
+        }
+    }
+
+    if failed == 0 {
+        okay("all tests passed\n");
+        crate::test_exit(QemuExitCode::Success);
+        ExitCode::Success
+    } else {
+        err(&format!("{} of {} tests failed", failed, TESTS.len()));
+        crate::test_exit(QemuExitCode::Failed);
+        ExitCode::Error
+    }
+}
+
+fn ata_drives_have_sectors() -> bool {
+    block::DEVICES
+        .lock()
+        .iter()
+        .filter_map(|device| device.as_ata())
+        .all(|drive| drive.sectors > 0)
+}
+
+fn ata_read_repeatable() -> bool {
+    let devices = block::DEVICES.lock();
+    let mut drives = devices.iter().filter_map(|device| device.as_ata());
+    drives.all(|drive| {
+        let mut first = [0_u8; 512];
+        let mut second = [0xFF_u8; 512];
+        let last_sector = drive.addressable_sectors() - 1;
+        drive.read(last_sector, &mut first).is_ok()
+            && drive.read(last_sector, &mut second).is_ok()
+            && first == second
+    })
+}
+
+fn ata_drives_numbered() -> bool {
+    let bus_ids: Vec<u8> = ata::BUSES.lock().iter().map(|bus| bus.id()).collect();
+    let positions: Vec<(u8, u8)> = block::DEVICES
+        .lock()
+        .iter()
+        .filter_map(|device| device.as_ata())
+        .map(|drive| (drive.bus_id, drive.drive_index))
+        .collect();
+
+    // Each bus has a master and a slave, and no drive is identified twice
+    positions
+        .iter()
+        .all(|(bus_id, drive_index)| bus_ids.contains(bus_id) && *drive_index < 2)
+        && positions.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+fn heap_reuse() -> bool {
+    let used_before = allocator::stats().used;
+
+    // Small allocations come from fixed-size blocks, so a freed block is handed out again
+    let first = Box::new([0_u8; 100]);
+    let first_addr = &*first as *const [u8; 100] as usize;
+    drop(first);
+    let small_reused = (0..1000).all(|_| {
+        let block = Box::new([1_u8; 100]);
+        &*block as *const [u8; 100] as usize == first_addr
+    });
+
+    // Larger allocations come from the fallback heap, and would run out of memory if it didn't reuse them
+    let large_reused = (0..100).all(|i| {
+        let buffer = vec![i as u8; 64 * 1024];
+        buffer[buffer.len() - 1] == i as u8
+    });
+
+    small_reused && large_reused && allocator::stats().used == used_before
+}
+
+fn time_unix_round_trip() -> bool {
+    [946_684_800_u64, 951_782_400, 1_615_125_000, 4_102_444_799]
+        .iter()
+        .all(|timestamp| DateTime::from_unix(*timestamp).to_unix() == *timestamp)
+}
+
+fn time_ordering() -> bool {
+    let end_of_january = DateTime::from_unix(1_612_137_599); // 23:59:59, 31 January 2021
+    let start_of_february = DateTime::from_unix(1_612_137_600); // 00:00, 1 February 2021
+    end_of_january < start_of_february && end_of_january.clone() == end_of_january
+}
+
+fn time_next_century() -> bool {
+    let new_year = DateTime::from_unix(4_102_444_800); // 00:00, 1 January 2100
+    new_year.to_unix() == 4_102_444_800
+        && new_year.to_short_string() == "01 Jan 2100 00:00"
+        && format!("{}", DateTime::from_unix(946_684_800)).ends_with("January 2000")
+}
+
+fn time_timers() -> bool {
+    // IDs well away from any the kernel uses
+    let set = time::set_timer(5, 1001, false) && time::set_timer(5, 1002, true);
+    let early = time::timer_fired(1001);
+
+    time::sleep_ms(20);
+    let one_shot = time::timer_fired(1001) && !time::timer_fired(1001);
+    let periodic = time::timer_fired(1002);
+
+    time::sleep_ms(20);
+    let repeated = time::timer_fired(1002) && !time::timer_fired(1001);
+    time::cancel_timer(1002);
+
+    set && !early && one_shot && periodic && repeated
+}
+
+fn interrupts_counted() -> bool {
+    let timer_count = || {
+        crate::interrupts::counts()
+            .find(|(_, name, _)| *name == "timer")
+            .map(|(_, _, count)| count)
+    };
+
+    let before = timer_count();
+    time::sleep_ms(20);
+    let after = timer_count();
+    matches!((before, after), (Some(before), Some(after)) if after > before)
+}
+
+fn interrupts_masked() -> bool {
+    use crate::interrupts::{irq_mask, mask_irq, unmask_irq};
+
+    // Only the timer, keyboard and cascade lines are left unmasked
+    let initial = irq_mask();
+    unmask_irq(5);
+    let unmasked = irq_mask();
+    mask_irq(5);
+
+    initial == 0xFFF8 && unmasked == 0xFFD8 && irq_mask() == initial
+}
+
+fn speaker_parse_note() -> bool {
+    speaker::parse_note("A4") == Some(440)
+        && speaker::parse_note("a5") == Some(880)
+        && speaker::parse_note("C4") == Some(262)
+        && speaker::parse_note("C#4") == speaker::parse_note("Db4")
+        && speaker::parse_note("B#3") == speaker::parse_note("C4")
+        && speaker::parse_note("R") == Some(0)
+        && speaker::parse_note("H4").is_none()
+        && speaker::parse_note("A9").is_none()
+        && speaker::parse_note("A").is_none()
+}
+
+fn block_available_run() -> bool {
+    let drive = RamDrive::new(16);
+    drive.write(12, &[1; 512]).ok();
+
+    drive.find_available_run(16, 3) == Some(13)
+        && drive.find_available_run(16, 4) == Some(8)
+        && drive.find_available_run(16, 16).is_none()
+        && drive.find_available_run(12, 4) == Some(8)
+        && drive.find_available_run(10, 2) == Some(8)
+}
+
+/// A RAM drive with a bad sector, which silently ignores writes to it.
+struct BadSectorDrive {
+    drive: RamDrive,
+    bad_sector: u32,
+}
+
+impl BlockDevice for BadSectorDrive {
+    fn read(&self, block: u32, buf: &mut [u8]) -> Result<(), ExitCode> {
+        self.drive.read(block, buf)
+    }
+
+    fn write(&self, block: u32, buf: &[u8]) -> Result<(), ExitCode> {
+        if block != self.bad_sector {
+            self.drive.write(block, buf)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn sectors(&self) -> u32 {
+        self.drive.sectors()
+    }
+}
+
+/// A RAM drive with a sector which can never be read, like a drive which times out.
+struct UnreadableSectorDrive {
+    drive: RamDrive,
+    bad_sector: u32,
+}
+
+impl BlockDevice for UnreadableSectorDrive {
+    fn read(&self, block: u32, buf: &mut [u8]) -> Result<(), ExitCode> {
+        if block != self.bad_sector {
+            self.drive.read(block, buf)
+        } else {
+            Err(ExitCode::TimeoutError)
+        }
+    }
+
+    fn write(&self, block: u32, buf: &[u8]) -> Result<(), ExitCode> {
+        self.drive.write(block, buf)
+    }
+
+    fn sectors(&self) -> u32 {
+        self.drive.sectors()
+    }
+}
+
+fn block_unreadable_not_available() -> bool {
+    let drive = UnreadableSectorDrive {
+        drive: RamDrive::new(16),
+        bad_sector: 10,
+    };
+
+    // Sectors 1 to 15 are zero, but the unreadable one may be in use so must never be handed out
+    drive.find_available_sectors(16, 15).is_none()
+        && drive
+            .find_available_sectors(16, 14)
+            .map_or(false, |sectors| !sectors.contains(&10))
+        && drive.find_available_run(16, 5) == Some(11)
+        && drive.find_available_run(16, 9) == Some(1)
+        && drive.find_available_run(16, 10).is_none()
+        && matches!(
+            drive.write_verified(10, &[1; 512]),
+            Err(ExitCode::TimeoutError)
+        )
+}
+
+fn block_verified_write() -> bool {
+    let drive = BadSectorDrive {
+        drive: RamDrive::new(16),
+        bad_sector: 3,
+    };
+
+    drive.write_verified(2, &[1; 512]).is_ok()
+        && matches!(
+            drive.write_verified(3, &[1; 512]),
+            Err(ExitCode::VerifyError)
+        )
+}
+
+fn block_ram_drive_bounds() -> bool {
+    let drive = RamDrive::new(16);
+    let mut buf = [0_u8; 512];
+    let mut short_buf = [0_u8; 100];
+
+    drive.read(15, &mut buf).is_ok()
+        && drive.read(16, &mut buf).is_err()
+        && drive.write(u32::MAX, &buf).is_err()
+        && drive.read(1, &mut short_buf).is_err()
+        && drive.write(1, &short_buf).is_err()
+}
+
+fn block_mixed_devices() -> bool {
+    with_ram_drive(|ram_index| {
+        block::DEVICES.lock().push(Box::new(BadSectorDrive {
+            drive: RamDrive::new(16),
+            bad_sector: 3,
+        }));
+        let bad_index = block::device_count() - 1;
+
+        let descriptions = (
+            block::with_device(ram_index, |drive| drive.description()),
+            block::with_device(bad_index, |drive| drive.description()),
+        );
+        let verified = (
+            block::with_device(ram_index, |drive| {
+                drive.write_verified(3, &[1; 512]).is_ok()
+            }),
+            block::with_device(bad_index, |drive| {
+                drive.write_verified(3, &[1; 512]).is_ok()
+            }),
+        );
+        let ata_count = block::DEVICES
+            .lock()
+            .iter()
+            .filter(|device| device.as_ata().is_some())
+            .count();
+        block::DEVICES.lock().pop();
+
+        // ATA drives are identified at boot, so they come before any devices added since
+        bad_index == ram_index + 1
+            && ata_count <= ram_index
+            && block::with_device(ram_index, |drive| drive.as_ata().is_none()) == Some(true)
+            && descriptions.0.as_deref() == Some("RAM drive (32 KB)")
+            && descriptions.1.as_deref() == Some("block device (8 KB)")
+            && verified == (Some(true), Some(false))
+    })
+}
+
+fn block_several_drives() -> bool {
+    with_ram_drive(|first| {
+        with_ram_drive(|second| {
+            block::with_device(first, |drive| drive.write(1, &[0xAB; 512]));
+            let mut first_buf = [0_u8; 512];
+            let mut second_buf = [0_u8; 512];
+            block::with_device(first, |drive| drive.read(1, &mut first_buf));
+            block::with_device(second, |drive| drive.read(1, &mut second_buf));
+
+            // Each drive gets its own filesystem, addressed by its index
+            let formatted = matches!(fs::format_drive(first), ExitCode::Success)
+                && matches!(fs::format_drive(second), ExitCode::Success);
+            let mut mounts = fs::FILESYSTEM.lock();
+            let path = vec![String::from("only_on_first")];
+            let written = mounts
+                .get_mut(Some(first as u8))
+                .map(|filesystem| filesystem.write_file(&path, vec![1]));
+            let separate = mounts
+                .get(Some(first as u8))
+                .map_or(false, |filesystem| filesystem.get_file(&path).is_some())
+                && mounts
+                    .get(Some(second as u8))
+                    .map_or(false, |filesystem| filesystem.get_file(&path).is_none());
+            mounts.unmount(first as u8);
+            mounts.unmount(second as u8);
+
+            second == first + 1
+                && block::device_count() == second + 1
+                && first_buf == [0xAB; 512]
+                && second_buf == [0; 512]
+                && formatted
+                && matches!(written, Some(ExitCode::Success))
+                && separate
+        })
+    })
+}
+
+fn input_typematic() -> bool {
+    use crate::input::{typematic_byte, typematic_settings};
+
+    let (delay, rate) = typematic_settings(typematic_byte(600, 12.0));
+    typematic_byte(250, 30.0) == 0x00
+        && typematic_byte(1000, 2.0) == 0x7F
+        && typematic_byte(500, 10.9) == 0x2B
+        && typematic_byte(0, 100.0) == 0x00
+        && delay == 500
+        && rate > 11.0
+        && rate < 13.0
+}
+
+fn pattern_names() -> bool {
+    use crate::pattern::matches;
+
+    matches("*.log", "boot.log")
+        && !matches("*.log", "boot.log.old")
+        && matches("a*b*c", "abbbcbc")
+        && !matches("a*b*c", "abcb")
+        && matches("", "")
+        && !matches("", "a")
+        && matches("*", "")
+        && matches("file?", "file1")
+        && !matches("file?", "file")
+        && matches("[a-c]x[!0-9]", "bxz")
+        && !matches("[a-c]x[!0-9]", "bx5")
+        && matches("[]]", "]")
+        && matches("[a-]", "-")
+        && matches("\\*", "*")
+        && !matches("\\*", "a")
+        && matches("[ab", "[ab")
+}
+
+fn pattern_text() -> bool {
+    use crate::pattern::matches_anywhere;
+
+    matches_anywhere("lo w", "hello world")
+        && matches_anywhere("", "anything")
+        && matches_anywhere("^hel", "hello")
+        && !matches_anywhere("^ello", "hello")
+        && matches_anywhere("lo$", "hello")
+        && !matches_anywhere("hel$", "hello")
+        && matches_anywhere("^h*o$", "hello")
+        && matches_anywhere("cost \\$", "cost $5")
+        && matches_anywhere("[0-9][0-9]", "version 12")
+        && !matches_anywhere("[0-9][0-9]", "version 1.2")
+}
+
+fn shell_suggestions() -> bool {
+    conhost::suggest_command("lss") == Some("ls")
+        && conhost::suggest_command("grpe") == Some("grep")
+        && conhost::suggest_command("mkdri") == Some("mkdir")
+        && conhost::suggest_command("screensave") == Some("screensaver")
+        && conhost::suggest_command("qq").is_none()
+        && conhost::suggest_command("").is_none()
+        && conhost::suggest_command("completely").is_none()
+}
+
+fn shell_command_names() -> bool {
+    let mut names: Vec<&str> = conhost::command_names().collect();
+    let count = names.len();
+    names.sort_unstable();
+    names.dedup();
+    names.len() == count && !names.contains(&"crashtest")
+}
+
+fn shell_completion() -> bool {
+    let appended = |line| match conhost::complete_command(line) {
+        Completion::Append(text) => Some(text),
+        _ => None,
+    };
+    let listed = |line| match conhost::complete_command(line) {
+        Completion::Candidates(candidates) => candidates,
+        _ => Vec::new(),
+    };
+
+    appended("unm").as_deref() == Some("ount ")
+        && appended("dis").as_deref() == Some("k")
+        && listed("mk") == ["mkdir", "mkfs"]
+        && listed("rm") == ["rm", "rmdir"]
+        && matches!(conhost::complete_command("zz"), Completion::None)
+        && matches!(conhost::complete_command("cat fi"), Completion::None)
+}
+
+fn calc_precedence() -> bool {
+    use crate::calc::evaluate;
+
+    evaluate("1 + 2 * 3") == Ok(7.0)
+        && evaluate("(1 + 2) * 3") == Ok(9.0)
+        && evaluate("10 - 4 - 3") == Ok(3.0)
+        && evaluate("8 / 4 / 2") == Ok(1.0)
+        && evaluate("2 * (3 + 4.5) / -5") == Ok(-3.0)
+        && evaluate("--2") == Ok(2.0)
+        && evaluate("-(1 - 3) * 2") == Ok(4.0)
+}
+
+fn calc_errors() -> bool {
+    use crate::calc::{evaluate, CalcError};
+
+    let nested = "(".repeat(100) + "1" + &")".repeat(100);
+    evaluate("1 / (2 - 2)") == Err(CalcError::DivideByZero)
+        && evaluate("") == Err(CalcError::Syntax)
+        && evaluate("1 +") == Err(CalcError::Syntax)
+        && evaluate("(2") == Err(CalcError::Syntax)
+        && evaluate("1)") == Err(CalcError::Syntax)
+        && evaluate("2 3") == Err(CalcError::Syntax)
+        && evaluate("1.2.3") == Err(CalcError::Syntax)
+        && evaluate("x") == Err(CalcError::Syntax)
+        && evaluate(&nested) == Err(CalcError::Syntax)
+}
+
+fn input_confirm() -> bool {
+    STDIN.clear();
+    STDIN.feed("Yynx");
+
+    let passed = conhost::confirm("selftest: upper case yes?")
+        && conhost::confirm("selftest: lower case yes?")
+        && !conhost::confirm("selftest: no?")
+        && !conhost::confirm("selftest: anything else?");
+    STDIN.clear();
+    passed
+}
+
+fn input_pasted_lines() -> bool {
+    STDIN.clear();
+    STDIN.feed("wt a b\nrt a\n");
+
+    let passed = STDIN.get_str() == "wt a b" && STDIN.get_str() == "rt a";
+    STDIN.clear();
+    passed
+}
+
+fn input_hidden_line() -> bool {
+    STDIN.clear();
+    STDIN.feed("pa\x08ss\n");
+
+    let passed = STDIN.get_str_hidden(false) == "pss";
+    STDIN.clear();
+    passed
+}
+
+fn fs_write_and_read() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("file")];
+        let bytes: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+
+        matches!(
+            filesystem.write_file(&path, bytes.clone()),
+            ExitCode::Success
+        ) && filesystem.get_file(&path).and_then(|file| file.read().ok()) == Some(bytes)
+    })
+}
+
+fn fs_write_chunks() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("file")];
+        let empty_path = vec![String::from("empty")];
+        let bytes: Vec<u8> = (0..2000).map(|i| (i * 7 % 256) as u8).collect();
+
+        filesystem.write_file(&path, bytes.clone());
+        filesystem.write_file(&empty_path, Vec::new());
+
+        // 2000 bytes fill three sectors then part of a fourth
+        let file = match filesystem.get_file(&path) {
+            Some(file) => file,
+            None => return false,
+        };
+        file.read().ok() == Some(bytes)
+            && file.sectors().len() == 4
+            && file.size() == 2000
+            && filesystem
+                .get_file(&empty_path)
+                .and_then(|file| file.read().ok())
+                == Some(Vec::new())
+    })
+}
+
+fn fs_modified_time() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("file")];
+        let before = DateTime::get();
+        filesystem.write_file(&path, vec![1; 600]);
+        let after = DateTime::get();
+
+        // The timestamp takes up some of the entry sector, so it must not be overwritten by the data
+        match filesystem.get_file(&path) {
+            Some(file) => {
+                file.modified().map_or(false, |modified| {
+                    modified.to_unix() >= before.to_unix() && modified.to_unix() <= after.to_unix()
+                }) && file.read().ok() == Some(vec![1; 600])
+                    && file.sectors().len() == 2
+            }
+            None => false,
+        }
+    })
+}
+
+fn fs_encrypted_file() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("secret")];
+        let bytes = b"hello world".to_vec();
+        filesystem.write_file_encrypted(&path, bytes.clone(), "pogo");
+
+        match filesystem.get_file(&path) {
+            Some(file) => {
+                file.is_encrypted()
+                    && matches!(file.read(), Err(ExitCode::EncryptedError))
+                    && file.read_with_passphrase("stick") != bytes
+                    && file.read_with_passphrase("pogo") == bytes
+            }
+            None => false,
+        }
+    })
+}
+
+fn fs_compressed_file() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let runs = vec![String::from("runs")];
+        let varied = vec![String::from("varied")];
+        let run_bytes = vec![7; 1000];
+        let varied_bytes: Vec<u8> = (0..200).collect();
+        filesystem.write_file_compressed(&runs, run_bytes.clone());
+        filesystem.write_file_compressed(&varied, varied_bytes.clone());
+
+        // Data without runs would double in size, so it is stored as it is
+        match (filesystem.get_file(&runs), filesystem.get_file(&varied)) {
+            (Some(runs), Some(varied)) => {
+                runs.is_compressed()
+                    && runs.stored_size() < 1000
+                    && runs.size() == 1000
+                    && runs.read().ok() == Some(run_bytes)
+                    && !varied.is_compressed()
+                    && varied.size() == 200
+                    && varied.read().ok() == Some(varied_bytes)
+            }
+            _ => false,
+        }
+    })
+}
+
+fn fs_file_handle() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("file")];
+        let bytes: Vec<u8> = (0..2000).map(|i| (i * 7 % 256) as u8).collect();
+        filesystem.write_file(&path, bytes.clone());
+        let mut handle = match filesystem.open_file(&path) {
+            Ok(handle) => handle,
+            Err(_) => return false,
+        };
+
+        // Read sequentially in chunks which don't line up with the sectors
+        let mut sequential = Vec::new();
+        let mut buf = [0_u8; 300];
+        loop {
+            let count = handle.read(&mut buf);
+            sequential.extend_from_slice(&buf[..count]);
+            if count < buf.len() {
+                break;
+            }
+        }
+
+        let mut seek_and_read = |position: usize, length: usize| {
+            let mut buf = vec![0_u8; length];
+            handle.seek(position);
+            let count = handle.read(&mut buf);
+            buf.truncate(count);
+            buf
+        };
+        sequential == bytes
+            && seek_and_read(1000, 20) == bytes[1000..1020]
+            && seek_and_read(5, 600) == bytes[5..605]
+            && seek_and_read(1990, 20) == bytes[1990..]
+            && seek_and_read(2500, 20).is_empty()
+    })
+}
+
+fn fs_compressed_file_handle() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("runs")];
+        let bytes: Vec<u8> = (0..2000).map(|i| (i / 100) as u8).collect();
+        filesystem.write_file_compressed(&path, bytes.clone());
+        let mut handle = match filesystem.open_file(&path) {
+            Ok(handle) => handle,
+            Err(_) => return false,
+        };
+
+        let mut buf = [0_u8; 20];
+        handle.seek(1490);
+        let count = handle.read(&mut buf);
+        handle.file().is_compressed() && count == 20 && buf[..] == bytes[1490..1510]
+    })
+}
+
+fn fs_delete_file() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let path = vec![String::from("file")];
+        let sectors_before = sectors_in_use(drive_index);
+
+        matches!(
+            filesystem.write_file(&path, vec![1; 1500]),
+            ExitCode::Success
+        ) && sectors_in_use(drive_index) > sectors_before
+            && matches!(filesystem.delete_file(&path), ExitCode::Success)
+            && filesystem.get_file(&path).is_none()
+            && sectors_in_use(drive_index) == sectors_before
+    })
+}
+
+fn fs_free_sectors() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("file")];
+        let free = |filesystem: &FileSystem| filesystem.free_sectors().unwrap_or(0);
+        let before = free(filesystem);
+
+        filesystem.write_file(&path, vec![1; 1500]);
+        let written = free(filesystem);
+        filesystem.delete_file(&path);
+
+        written == before - 3 && free(filesystem) == before
+    })
+}
+
+fn fs_create_dir() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let dir = vec![String::from("dir")];
+        let file = vec![String::from("dir"), String::from("file")];
+
+        matches!(filesystem.create_dir(&dir), ExitCode::Success)
+            && matches!(
+                filesystem.write_file(&file, vec![1, 2, 3]),
+                ExitCode::Success
+            )
+            && filesystem.list_files(&Vec::new()) == Some(vec![String::from("dir/")])
+            && filesystem.list_files(&dir) == Some(vec![String::from("file")])
+            && filesystem.get_file(&file).and_then(|file| file.read().ok()) == Some(vec![1, 2, 3])
+    })
+}
+
+fn fs_list_entries() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        filesystem.create_dir(&vec![String::from("b")]);
+        filesystem.write_file(&vec![String::from("a")], vec![1]);
+
+        match filesystem.list_entries(&Vec::new()).as_deref() {
+            Some([FileType::File(a), FileType::Dir(b)]) => a.name == "a" && b.name == "b",
+            _ => false,
+        }
+    })
+}
+
+fn fs_file_type_helpers() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        filesystem.create_dir(&vec![String::from("b")]);
+        filesystem.write_file(&vec![String::from("a")], vec![1]);
+
+        let entries = filesystem.list_entries(&Vec::new()).unwrap_or_default();
+        let names: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        names == ["a", "b/"]
+            && entries
+                .iter()
+                .map(|entry| entry.is_dir())
+                .eq([false, true].iter().copied())
+            && entries[1].name() == "b"
+            && filesystem
+                .get_dir(&vec![String::from("b")])
+                .map(|dir| dir.entry_addr)
+                == Some(entries[1].entry_addr())
+    })
+}
+
+fn fs_copy_across_drives() -> bool {
+    with_ram_drive(|first| {
+        with_ram_drive(|second| {
+            let mut mounts = fs::Mounts::new();
+            for drive_index in &[first, second] {
+                fs::format_drive(*drive_index);
+                fs::FILESYSTEM.lock().unmount(*drive_index as u8);
+                match FileSystem::open(*drive_index as u8) {
+                    Ok(filesystem) => mounts.mount(filesystem),
+                    Err(_) => return false,
+                }
+            }
+
+            let (first, second) = (Some(first as u8), Some(second as u8));
+            let path = vec![String::from("file")];
+            let copy = vec![String::from("copy")];
+            let bytes: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+            mounts
+                .get_mut(first)
+                .unwrap()
+                .write_file(&path, bytes.clone());
+
+            let across = mounts.copy_file(first, &path, second, &copy);
+            let within = mounts.copy_file(first, &path, first, &copy);
+
+            let copied = |drive| {
+                mounts
+                    .get(drive)?
+                    .get_file(&copy)
+                    .and_then(|file| file.read().ok())
+            };
+            matches!(across, ExitCode::Success)
+                && matches!(within, ExitCode::Success)
+                && copied(first) == Some(bytes.clone())
+                && copied(second) == Some(bytes)
+        })
+    })
+}
+
+fn fs_file_writer() -> bool {
+    with_ram_drive(|drive_index| {
+        fs::format_drive(drive_index);
+        let drive = Some(drive_index as u8);
+        let path = vec![String::from("log")];
+
+        write!(FileWriter::new(drive, path.clone()), "first {}", 1).ok();
+        let mut writer = FileWriter::append(drive, path.clone());
+        write!(writer, ", second").ok();
+        let flushed = matches!(writer.flush(), ExitCode::Success);
+        write!(writer, " {:>3}", 2).ok();
+        drop(writer);
+
+        let mut mounts = fs::FILESYSTEM.lock();
+        let contents = mounts
+            .get(drive)
+            .and_then(|filesystem| filesystem.get_file(&path))
+            .and_then(|file| file.read().ok());
+        let files = mounts
+            .get(drive)
+            .and_then(|filesystem| filesystem.list_files(&Vec::new()));
+        mounts.unmount(drive_index as u8);
+
+        flushed
+            && contents.as_deref() == Some(&b"first 1, second   2"[..])
+            && files.map(|files| files.len()) == Some(1)
+    })
+}
+
+fn fs_file_writer_disk_full() -> bool {
+    with_ram_drive(|drive_index| {
+        fs::format_drive(drive_index);
+        let drive = Some(drive_index as u8);
+        let path = vec![String::from("log")];
+        write!(FileWriter::new(drive, path.clone()), "kept").ok();
+
+        // Output too big for the drive must not cost the file its old contents
+        let mut writer = FileWriter::new(drive, path.clone());
+        write!(writer, "{}", "x".repeat(64 * 512)).ok();
+        let refused = matches!(writer.flush(), ExitCode::DiskFullError);
+        drop(writer);
+
+        let mut mounts = fs::FILESYSTEM.lock();
+        let contents = mounts
+            .get(drive)
+            .and_then(|filesystem| filesystem.get_file(&path))
+            .and_then(|file| file.read().ok());
+        mounts.unmount(drive_index as u8);
+
+        refused && contents.as_deref() == Some(&b"kept"[..])
+    })
+}
+
+fn fs_file_writer_same_file() -> bool {
+    with_ram_drive(|drive_index| {
+        fs::format_drive(drive_index);
+        let drive = Some(drive_index as u8);
+        let path = vec![String::from("list")];
+        write!(FileWriter::new(drive, path.clone()), "b\nc\na").ok();
+
+        // Like `sort -o list list`, which reads the file and then writes it back in order
+        let mounts = fs::FILESYSTEM.lock();
+        let read = |mounts: &fs::Mounts| {
+            mounts
+                .get(drive)
+                .and_then(|filesystem| filesystem.get_file(&path))
+                .and_then(|file| file.read().ok())
+        };
+        let text = String::from_utf8(read(&mounts).unwrap_or_default()).unwrap_or_default();
+        drop(mounts);
+
+        let mut lines: Vec<&str> = text.lines().collect();
+        lines.sort_unstable();
+        let mut writer = FileWriter::new(drive, path.clone());
+        for line in lines {
+            writeln!(writer, "{}", line).ok();
+        }
+        let flushed = matches!(writer.flush(), ExitCode::Success);
+        drop(writer);
+
+        let mut mounts = fs::FILESYSTEM.lock();
+        let contents = read(&mounts);
+        mounts.unmount(drive_index as u8);
+
+        flushed && contents.as_deref() == Some(&b"a\nb\nc\n"[..])
+    })
+}
+
+fn fs_disk_full() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let big = vec![String::from("big")];
+        let sectors_before = sectors_in_use(drive_index);
+        let too_big = filesystem.write_file(&big, vec![1; 64 * 512]);
+        let unchanged =
+            sectors_in_use(drive_index) == sectors_before && filesystem.get_file(&big).is_none();
+
+        // Fill the drive with small files, which also need new directory tables along the way
+        let mut count = 0;
+        let full = loop {
+            match filesystem.write_file(&vec![format!("f{}", count)], vec![1]) {
+                ExitCode::Success => count += 1,
+                error_code => break error_code,
+            }
+        };
+        let sectors_full = sectors_in_use(drive_index);
+
+        matches!(too_big, ExitCode::DiskFullError)
+            && unchanged
+            && matches!(full, ExitCode::DiskFullError)
+            && matches!(
+                filesystem.create_dir(&vec![String::from("dir")]),
+                ExitCode::DiskFullError
+            )
+            && sectors_in_use(drive_index) == sectors_full
+            && filesystem.list_files(&Vec::new()).map(|files| files.len()) == Some(count)
+    })
+}
+
+fn fs_replace_file() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("kept")];
+        let written = matches!(
+            filesystem.write_file_compressed(&path, vec![7; 2000]),
+            ExitCode::Success
+        ) && matches!(
+            filesystem.set_flags(&path, fs::FLAG_HIDDEN, true),
+            ExitCode::Success
+        );
+
+        let replaced = matches!(
+            filesystem.replace_file(&path, vec![8; 3000]),
+            ExitCode::Success
+        ) && filesystem.get_file(&path).map_or(false, |file| {
+            file.is_compressed() && file.is_hidden() && file.read().ok() == Some(vec![8; 3000])
+        });
+
+        // Bytes which don't compress are too big for the drive, so the old contents must survive
+        let too_big = (0..30000).map(|i| i as u8).collect();
+        let refused = matches!(
+            filesystem.replace_file(&path, too_big),
+            ExitCode::DiskFullError
+        ) && filesystem.get_file(&path).and_then(|file| file.read().ok())
+            == Some(vec![8; 3000]);
+
+        written
+            && replaced
+            && refused
+            && filesystem
+                .list_entries(&Vec::new())
+                .map(|objects| objects.len())
+                == Some(1)
+    })
+}
+
+fn fs_reserved_sectors() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let superblock = filesystem.superblock.clone();
+        let mut count = 0;
+        while let ExitCode::Success = filesystem.write_file(&vec![format!("f{}", count)], vec![1]) {
+            count += 1;
+        }
+
+        let reserved_unused = filesystem.list_entries(&vec![]).map_or(false, |objects| {
+            objects.iter().all(|object| match object {
+                FileType::File(f) => f
+                    .sectors()
+                    .iter()
+                    .all(|addr| *addr < superblock.reserved_start()),
+                FileType::Dir(_) => false,
+            })
+        });
+
+        superblock.reserved_sectors == fs::DEFAULT_RESERVED_SECTORS
+            && superblock.root_addr < superblock.reserved_start()
+            && count > 0
+            && reserved_unused
+            && matches!(fs::format_drive_with(drive_index, 0), ExitCode::ParseError)
+            && matches!(fs::format_drive_with(drive_index, 40), ExitCode::ParseError)
+    })
+}
+
+fn fs_default_drive() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let formatted = !filesystem.superblock.is_default;
+
+        // Setting it through the mounts would clear the flag on the real disks too
+        let mut superblock = filesystem.superblock.clone();
+        superblock.is_default = true;
+        let written = superblock.update_physical_drive(drive_index).is_ok();
+
+        formatted
+            && written
+            && Superblock::load(drive_index).map_or(false, |superblock| superblock.is_default)
+            && matches!(
+                fs::FILESYSTEM.lock().set_default(drive_index as u8),
+                ExitCode::NotMountedError
+            )
+    })
+}
+
+fn fs_journal_replay() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let journal = fs::Journal::new(&filesystem.superblock, drive_index);
+        let mut table = filesystem.entry_table.clone();
+        table.add_file("recorded", 1);
+
+        // Record the update as if the drive was reset before the table was written
+        let recorded = journal.record(&[table.clone()]).is_ok();
+        let before_replay = filesystem.get_file(&vec!["recorded".to_string()]).is_none();
+        let replayed = match FileSystem::open(drive_index as u8) {
+            Ok(reopened) => reopened.get_file(&vec!["recorded".to_string()]).is_some(),
+            Err(_) => false,
+        };
+
+        // A header which was only partly written is discarded
+        table.add_file("torn", 2);
+        journal.record(&[table]).ok();
+        let header_addr = filesystem.superblock.reserved_start();
+        let mut buf = [0_u8; 512];
+        block::with_device(drive_index, |drive| {
+            drive.read(header_addr, &mut buf).ok();
+            buf[511] ^= 0xFF;
+            drive.write(header_addr, &buf).ok();
+        });
+        let discarded = match FileSystem::open(drive_index as u8) {
+            Ok(reopened) => reopened.get_file(&vec!["torn".to_string()]).is_none(),
+            Err(_) => false,
+        };
+
+        recorded && before_replay && replayed && discarded && matches!(journal.replay(), Ok(false))
+    })
+}
+
+fn fs_undelete() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let path = vec![String::from("small")];
+        let bytes = b"restore me".to_vec();
+        filesystem.write_file(&path, bytes.clone());
+        let sectors_before = sectors_in_use(drive_index);
+
+        let deleted = matches!(filesystem.soft_delete_file(&path), ExitCode::Success)
+            && filesystem.get_file(&path).is_none()
+            && sectors_in_use(drive_index) == sectors_before
+            && filesystem.list_deleted(&[]).map(|files| files.len()) == Some(1);
+
+        deleted
+            && matches!(filesystem.undelete_file(&path), ExitCode::Success)
+            && filesystem.get_file(&path).and_then(|file| file.read().ok()) == Some(bytes)
+            && filesystem.list_deleted(&[]).map(|files| files.len()) == Some(0)
+            && matches!(
+                filesystem.undelete_file(&path),
+                ExitCode::AlreadyExistsError
+            )
+    })
+}
+
+fn fs_undelete_older_version() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let path = vec![String::from("file")];
+        filesystem.write_file(&path, vec![1, 2, 3]);
+        filesystem.superblock.version = 2;
+        filesystem
+            .superblock
+            .update_physical_drive(drive_index)
+            .ok();
+
+        matches!(filesystem.soft_delete_file(&path), ExitCode::Success)
+            && Superblock::load(drive_index)
+                .map_or(false, |superblock| superblock.version == fs::FORMAT_VERSION)
+    })
+}
+
+fn fs_purge_deleted() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let path = vec![String::from("old")];
+        filesystem.write_file(&path, vec![1; 1500]);
+        filesystem.soft_delete_file(&path);
+
+        // Filling the disk needs the deleted file's sectors
+        let mut count = 0;
+        while let ExitCode::Success = filesystem.write_file(&vec![format!("f{}", count)], vec![1]) {
+            count += 1;
+        }
+
+        filesystem.list_deleted(&[]).map(|files| files.len()) == Some(0)
+            && matches!(filesystem.undelete_file(&path), ExitCode::NotFoundError)
+    })
+}
+
+fn fs_delete_recursive() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let dir = vec![String::from("dir")];
+        let inner_dir = vec![String::from("dir"), String::from("inner")];
+        let file = vec![
+            String::from("dir"),
+            String::from("inner"),
+            String::from("file"),
+        ];
+        let sectors_before = sectors_in_use(drive_index);
+
+        filesystem.create_dir(&dir);
+        filesystem.create_dir(&inner_dir);
+        filesystem.write_file(&file, vec![1; 1500]);
+
+        matches!(
+            filesystem.delete_object(&dir, false),
+            ExitCode::NotEmptyError
+        ) && matches!(filesystem.delete_object(&dir, true), ExitCode::Success)
+            && filesystem.get_dir(&dir).is_none()
+            && sectors_in_use(drive_index) == sectors_before
+    })
+}
+
+fn fs_large_dir() -> bool {
+    with_ram_filesystem(|filesystem, _| {
+        let dir = vec![String::from("dir")];
+        let file_path = |i: usize| vec![String::from("dir"), format!("file{:02}", i)];
+        filesystem.create_dir(&dir);
+
+        // 20 entries need three tables, with a gap left in the first by deleting one
+        let written = (0..20).all(|i| {
+            matches!(
+                filesystem.write_file(&file_path(i), vec![i as u8]),
+                ExitCode::Success
+            )
+        });
+        let deleted = matches!(filesystem.delete_file(&file_path(3)), ExitCode::Success);
+        let refilled = matches!(
+            filesystem.write_file(&file_path(20), vec![20]),
+            ExitCode::Success
+        );
+
+        let expected: Vec<String> = (0..21)
+            .filter(|i| *i != 3)
+            .map(|i| format!("file{:02}", i))
+            .collect();
+        written
+            && deleted
+            && refilled
+            && filesystem.get_dir(&dir).map(|d| d.sectors().len()) == Some(3)
+            && filesystem.list_files(&dir) == Some(expected)
+            && (0..21).filter(|i| *i != 3).all(|i| {
+                filesystem
+                    .get_file(&file_path(i))
+                    .and_then(|file| file.read().ok())
+                    == Some(vec![i as u8])
+            })
+    })
+}
+
+fn fs_new_dir_empty() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let outer = vec![String::from("a")];
+        let inner = vec![String::from("a"), String::from("b")];
+
+        let created = matches!(filesystem.create_dir(&outer), ExitCode::Success)
+            && matches!(filesystem.create_dir(&inner), ExitCode::Success);
+
+        // Each entry must point at a valid, empty directory table
+        let valid_table = |path: &Vec<String>| match filesystem.get_dir(path) {
+            Some(dir) => {
+                let mut buf = [0_u8; 512];
+                block::with_device(drive_index, |drive| drive.read(dir.entry_addr, &mut buf));
+                &buf[508..512] == b"POGO"
+            }
+            None => false,
+        };
+
+        created
+            && valid_table(&outer)
+            && valid_table(&inner)
+            && filesystem.list_files(&outer) == Some(vec![String::from("b/")])
+            && filesystem.list_files(&inner) == Some(Vec::new())
+    })
+}
+
+fn fs_format_clears() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let sectors_before = sectors_in_use(drive_index);
+
+        matches!(
+            filesystem.create_dir(&vec![String::from("dir")]),
+            ExitCode::Success
+        ) && matches!(
+            filesystem.write_file(&vec![String::from("file")], vec![1; 1500]),
+            ExitCode::Success
+        ) && matches!(fs::format_drive(drive_index), ExitCode::Success)
+            && matches!(
+                fs::FILESYSTEM.lock().unmount(drive_index as u8),
+                ExitCode::Success
+            )
+            && sectors_in_use(drive_index) == sectors_before
+    })
+}
+
+fn fs_legacy_disk() -> bool {
+    with_ram_drive(|drive_index| {
+        let mut entry_table = [0_u8; 512];
+        entry_table[508..512].copy_from_slice(b"POGO");
+        block::with_device(drive_index, |drive| drive.write(63, &entry_table));
+
+        let path = vec![String::from("file")];
+        match FileSystem::open(drive_index as u8) {
+            Ok(mut filesystem) => {
+                filesystem.superblock.version == 0
+                    && filesystem.entry_sector == 63
+                    && matches!(
+                        filesystem.write_file(&path, vec![1, 2, 3]),
+                        ExitCode::Success
+                    )
+                    && filesystem.get_file(&path).and_then(|file| file.read().ok())
+                        == Some(vec![1, 2, 3])
+            }
+            Err(_) => false,
+        }
+    })
+}
+
+fn fs_smaller_superblock() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        // A filesystem which doesn't fill its drive still keeps its superblock in the drive's last sector
+        let mut superblock = filesystem.superblock.clone();
+        superblock.total_sectors -= 8;
+        superblock.root_addr -= 8;
+        superblock.update_physical_drive(drive_index).is_ok()
+            && Superblock::load(drive_index).map_or(false, |loaded| {
+                loaded.total_sectors == superblock.total_sectors
+            })
+    })
+}
+
+fn fs_newer_version() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let mut superblock = filesystem.superblock.clone();
+        superblock.version = fs::FORMAT_VERSION + 1;
+        superblock.update_physical_drive(drive_index).is_ok()
+            && matches!(
+                FileSystem::open(drive_index as u8),
+                Err(ExitCode::UnsupportedFilesystemError)
+            )
+    })
+}
+
+fn fs_size_clamped() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let path = vec![String::from("file")];
+        filesystem.write_file(&path, vec![1; 100]);
+        let file = match filesystem.get_file(&path) {
+            Some(file) => file,
+            None => return false,
+        };
+
+        // Corrupt the size field, both with and without the checksum flag
+        [0xFFFF_u16, 0x7FFF].iter().all(|size_field| {
+            let mut buf = [0_u8; 512];
+            block::with_device(drive_index, |drive| {
+                drive.read(file.entry_addr, &mut buf).ok();
+                buf[4..6].copy_from_slice(&size_field.to_be_bytes());
+                drive.write(file.entry_addr, &buf).ok();
+            });
+
+            let sector = fs::DataSector::load(file.entry_addr, drive_index);
+            sector.is_corrupted
+                && sector.size as usize <= 506
+                && file.read().map_or(false, |bytes| bytes.len() <= 506)
+        })
+    })
+}
+
+fn fs_cycles_broken() -> bool {
+    with_ram_filesystem(|filesystem, drive_index| {
+        let path = vec![String::from("file")];
+        filesystem.write_file(&path, vec![1; 1500]);
+        let file = match filesystem.get_file(&path) {
+            Some(file) => file,
+            None => return false,
+        };
+
+        // Link the last sector of the file back to the first, and the root table to itself
+        let sectors = file.sectors();
+        set_continuation(drive_index, sectors[sectors.len() - 1], file.entry_addr);
+        set_continuation(
+            drive_index,
+            filesystem.entry_sector,
+            filesystem.entry_sector,
+        );
+        filesystem.sync();
+
+        file.sectors() == sectors
+            && file.read().map_or(false, |bytes| bytes.len() == 1500)
+            && filesystem.list_files(&Vec::new()) == Some(vec![String::from("file")])
+            && filesystem
+                .get_file(&vec![String::from("missing")])
+                .is_none()
+    })
+}
+
+/// Overwrites the continuation address of a sector.
+fn set_continuation(drive_index: usize, addr: u32, continuation_addr: u32) {
+    let mut buf = [0_u8; 512];
+    block::with_device(drive_index, |drive| {
+        drive.read(addr, &mut buf).ok();
+        buf[0..4].copy_from_slice(&continuation_addr.to_be_bytes());
+        drive.write(addr, &buf).ok();
+    });
+}
+
+/// Runs a test against a filesystem on a new RAM drive, which is removed afterwards.
+/// The test is given the filesystem and the drive's index.
+fn with_ram_filesystem(test: impl FnOnce(&mut FileSystem, usize) -> bool) -> bool {
+    with_ram_drive(|drive_index| {
+        fs::format_drive(drive_index);
+        fs::FILESYSTEM.lock().unmount(drive_index as u8);
+
+        match FileSystem::open(drive_index as u8) {
+            Ok(mut filesystem) => test(&mut filesystem, drive_index),
+            Err(_) => false,
+        }
+    })
+}
+
+/// Runs a test against a new, empty RAM drive, which is removed afterwards.
+/// The test is given the drive's index.
+fn with_ram_drive(test: impl FnOnce(usize) -> bool) -> bool {
+    block::DEVICES.lock().push(Box::new(RamDrive::new(64)));
+    let drive_index = block::device_count() - 1;
+
+    let passed = test(drive_index);
+
+    block::DEVICES.lock().pop();
+    passed
+}
+
+/// Counts the sectors of a drive which aren't entirely zero.
+/// Reserved sectors are left out, as the journal in them isn't cleared after use.
+fn sectors_in_use(drive_index: usize) -> usize {
+    let reserved_start = fs::Superblock::load(drive_index).map_or(u32::MAX, |s| s.reserved_start());
+    block::with_device(drive_index, |drive| {
+        (0..drive.sectors().min(reserved_start))
+            .filter(|sector| {
+                let mut buf = [0_u8; 512];
+                drive.read(*sector, &mut buf).ok();
+                buf.iter().any(|byte| *byte != 0)
+            })
+            .count()
+    })
+    .unwrap_or(0)
+}
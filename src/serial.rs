@@ -0,0 +1,109 @@
+// Serial port output
+// Writes to COM1 so logs can be captured when running headless (e.g. QEMU with `-serial stdio`).
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// Base I/O port of COM1.
+const COM1: u16 = 0x3F8;
+
+/// Whether the log helpers in `vga.rs` also write to the serial port.
+static MIRROR_LOGS: AtomicBool = AtomicBool::new(true);
+
+/// Represents a 16550 UART serial port.
+pub struct SerialPort {
+    data: Port<u8>,
+    interrupt_enable: Port<u8>,
+    fifo_control: Port<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: Port<u8>,
+}
+
+impl SerialPort {
+    /// Creates port references for the serial port at the given base port.
+    pub fn new(base: u16) -> Self {
+        Self {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+        }
+    }
+
+    /// Initialises the serial port at 38400 baud with 8 data bits, no parity and one stop bit.
+    pub fn init(&mut self) {
+        unsafe {
+            self.interrupt_enable.write(0x00); // disable interrupts
+            self.line_control.write(0x80); // enable DLAB so the data ports set the baud rate divisor
+            self.data.write(0x03); // divisor low byte (115200 / 3 = 38400 baud)
+            self.interrupt_enable.write(0x00); // divisor high byte
+            self.line_control.write(0x03); // disable DLAB, 8 bits, no parity, one stop bit
+            self.fifo_control.write(0xC7); // enable and clear FIFOs with a 14 byte threshold
+            self.modem_control.write(0x0B); // data terminal ready, request to send, IRQs enabled
+        }
+    }
+
+    /// Sends a byte, waiting until the transmit buffer is empty.
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.line_status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1));
+}
+
+/// Initialise COM1
+pub fn init() {
+    SERIAL1.lock().init();
+}
+
+/// Sets whether the log helpers also write to the serial port.
+pub fn set_log_mirroring(enabled: bool) {
+    MIRROR_LOGS.store(enabled, Ordering::Relaxed);
+}
+
+/// Writes a log message to the serial port if mirroring is enabled.
+pub fn mirror_log(level: &str, args: core::fmt::Arguments) {
+    if MIRROR_LOGS.load(Ordering::Relaxed) {
+        _print(format_args!("[ {} ] {}", level, args));
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).unwrap();
+    });
+}
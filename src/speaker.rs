@@ -0,0 +1,99 @@
+// PC speaker
+// Plays tones by connecting channel 2 of the PIT, set up as a square wave generator, to the speaker.
+
+use crate::time::sleep_ms;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const PIT_FREQUENCY: u32 = 3_579_545 / 3; // frequency the PIT divides down from (see OSDev wiki)
+const SPEAKER_GATE_BITS: u8 = 0b11; // bits of port 0x61 which enable PIT channel 2 and connect it to the speaker
+
+/// Frequencies of the notes C4 to B4 in millihertz, which the other octaves are found by doubling or halving.
+const OCTAVE_4: [u32; 12] = [
+    261_626, 277_183, 293_665, 311_127, 329_628, 349_228, 369_994, 391_995, 415_305, 440_000,
+    466_164, 493_883,
+];
+
+/// Lowest frequency the PIT's 16-bit divider can produce, in hertz.
+pub const MIN_FREQUENCY: u32 = PIT_FREQUENCY / u16::MAX as u32 + 1;
+
+/// Plays a tone of the given frequency in hertz for the given number of milliseconds.
+/// The frequency is clamped to what the PIT can produce, and the speaker gate bits are put back as they were afterwards.
+pub fn beep(frequency: u32, duration_ms: u64) {
+    let divider = (PIT_FREQUENCY / frequency.max(MIN_FREQUENCY)).max(1) as u16;
+
+    let previous_gate = without_interrupts(|| {
+        let mut control_port: Port<u8> = Port::new(0x43);
+        let mut data_port: Port<u8> = Port::new(0x42);
+        let mut gate_port: Port<u8> = Port::new(0x61);
+        unsafe {
+            //  10 - channel 2, drives the speaker
+            //  11 - access mode lobyte/hibyte
+            // 011 - square wave generator
+            //   0 - binary mode
+            control_port.write(0b1011_0110);
+            data_port.write(divider as u8);
+            data_port.write((divider >> 8) as u8);
+
+            let previous_gate = gate_port.read();
+            gate_port.write(previous_gate | SPEAKER_GATE_BITS);
+            previous_gate
+        }
+    });
+
+    sleep_ms(duration_ms);
+
+    // Only restore the bits which were changed, as the rest of the port is used for other things
+    without_interrupts(|| {
+        let mut gate_port: Port<u8> = Port::new(0x61);
+        unsafe {
+            let gate = gate_port.read();
+            gate_port.write((gate & !SPEAKER_GATE_BITS) | (previous_gate & SPEAKER_GATE_BITS));
+        }
+    });
+}
+
+/// Parses a note name such as `A4`, `C#5` or `Eb3` into its frequency in hertz, rounded to the nearest hertz.
+/// Rests are written as `R` and have a frequency of 0.
+/// Returns `None` if the name isn't a note in octaves 0 to 8.
+pub fn parse_note(name: &str) -> Option<u32> {
+    if name.eq_ignore_ascii_case("r") {
+        return Some(0);
+    }
+
+    let mut chars = name.chars();
+    let mut semitone: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+
+    let mut octave = chars.as_str();
+    if let Some(rest) = octave.strip_prefix('#') {
+        semitone += 1;
+        octave = rest;
+    } else if let Some(rest) = octave.strip_prefix('b') {
+        semitone -= 1;
+        octave = rest;
+    }
+
+    let mut octave = octave
+        .parse::<i32>()
+        .ok()
+        .filter(|octave| (0..=8).contains(octave))?;
+    // Notes like B#4 and Cb4 cross into the next or previous octave
+    octave += semitone.div_euclid(12);
+    let millihertz = OCTAVE_4[semitone.rem_euclid(12) as usize];
+
+    let millihertz = if octave >= 4 {
+        millihertz << (octave - 4)
+    } else {
+        millihertz >> (4 - octave)
+    };
+    Some((millihertz + 500) / 1000)
+}
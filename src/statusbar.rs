@@ -0,0 +1,91 @@
+// Status bar
+// Keeps the top row of the screen showing the current path, free disk space and time.
+
+use crate::time::{self, DateTime};
+use crate::vga::{Colour, ColourCode, BUFFER_WIDTH, WRITER};
+use alloc::{format, string::String};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Whether the status bar has been set up, so the timer should redraw it.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Second of uptime when the status bar was last redrawn, so it is only redrawn once a second.
+static LAST_DRAWN: AtomicUsize = AtomicUsize::new(0);
+
+/// Represents what the status bar shows, apart from the time.
+pub struct Status {
+    pub path: String,
+    pub free_kb: Option<u32>,
+}
+
+lazy_static! {
+    static ref STATUS: Mutex<Status> = Mutex::new(Status {
+        path: String::from("/"),
+        free_kb: None,
+    });
+}
+
+/// Reserve the top row of the screen for the status bar and draw it.
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_top_margin(1);
+        draw();
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Update the path and free space shown in the status bar, then redraw it.
+pub fn set(path: String, free_kb: Option<u32>) {
+    interrupts::without_interrupts(|| {
+        *STATUS.lock() = Status { path, free_kb };
+        if ENABLED.load(Ordering::Relaxed) {
+            draw();
+        }
+    });
+}
+
+/// Redraw the status bar if a second has passed, so the clock stays up to date.
+/// Called by the timer interrupt handler.
+pub fn handle_tick() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let second = time::uptime() as usize;
+    if LAST_DRAWN.swap(second, Ordering::Relaxed) != second {
+        draw();
+    }
+}
+
+/// Draw the status bar on the top row without moving the cursor.
+/// If the writer or status is locked (e.g. when called from an interrupt handler), it is drawn next time instead.
+fn draw() {
+    let status = match STATUS.try_lock() {
+        Some(status) => status,
+        None => return,
+    };
+    let mut writer = match WRITER.try_lock() {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    let right = match status.free_kb {
+        Some(free_kb) => format!(
+            "{} KB free | {} ",
+            free_kb,
+            DateTime::get().to_short_string()
+        ),
+        None => format!("{} ", DateTime::get().to_short_string()),
+    };
+    let path_width = BUFFER_WIDTH.saturating_sub(right.len() + 2);
+    let path: String = status.path.chars().take(path_width).collect();
+    let padding = BUFFER_WIDTH - 1 - path.chars().count() - right.len();
+    let text = format!(" {}{}{}", path, " ".repeat(padding), right);
+
+    let cursor = writer.save_cursor();
+    writer.goto(0, 0);
+    writer.write_string_colour(&text, ColourCode::new(Colour::Black, Colour::LightGray));
+    writer.restore_cursor(cursor);
+}
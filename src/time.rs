@@ -1,13 +1,45 @@
-use alloc::vec::Vec;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{format, string::String};
+use core::cmp::Ordering as CmpOrdering;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::{fmt::Display, hint::spin_loop};
 use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 
 static TICKS: AtomicUsize = AtomicUsize::new(0); // ticks since start
+static IDLE_TICKS: AtomicUsize = AtomicUsize::new(0); // ticks which interrupted `idle`
+
+/// Whether the CPU is halted waiting for an interrupt, set by `idle` and `idle_loop`.
+pub static IDLING: AtomicBool = AtomicBool::new(false);
 const PIT_DIVIDER: usize = 1193; // divider for PIT frequency (see OSDev wiki)
 const PIT_INTERVAL: f64 = PIT_DIVIDER as f64 / (3_579_545.0 / 3.0); // interval between PIT ticks
 
+const RTC_READ_ATTEMPTS: usize = 4; // times to re-read the RTC if it changed between reads
+const RTC_UPDATE_POLLS: usize = 100_000; // times to check the RTC's update-in-progress flag before reading anyway
+
+/// Maximum number of software timers which can be set at once.
+pub const MAX_TIMERS: usize = 8;
+
+/// A slot for a software timer, checked by the PIT interrupt handler.
+/// Only atomics are used so the handler never has to lock or allocate.
+struct Timer {
+    in_use: AtomicBool,
+    id: AtomicUsize,
+    deadline: AtomicUsize, // tick at which the timer next fires, or 0 if it isn't running
+    period: AtomicUsize,   // 0 for one-shot timers
+    fired: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_TIMER: Timer = Timer {
+    in_use: AtomicBool::new(false),
+    id: AtomicUsize::new(0),
+    deadline: AtomicUsize::new(0),
+    period: AtomicUsize::new(0),
+    fired: AtomicBool::new(false),
+};
+
+static TIMERS: [Timer; MAX_TIMERS] = [EMPTY_TIMER; MAX_TIMERS];
+
 pub fn init() {
     without_interrupts(|| {
         let divider_bytes = (PIT_DIVIDER as u16).to_le_bytes();
@@ -33,14 +65,117 @@ pub fn uptime() -> f64 {
     PIT_INTERVAL * TICKS.load(Ordering::Relaxed) as f64
 }
 
+/// Get how many times per second the PIT interrupt should fire.
+pub fn pit_frequency() -> f64 {
+    1.0 / PIT_INTERVAL
+}
+
+/// Sleeps for at least the given number of milliseconds, halting the CPU between timer ticks.
+/// Only as accurate as `uptime`.
+pub fn sleep_ms(milliseconds: u64) {
+    let end = uptime() + milliseconds as f64 / 1000.0;
+    while uptime() < end {
+        crate::idle();
+    }
+}
+
+/// Get the percentage of time since boot which the CPU has spent halted in `idle`.
+/// Only approximate, as a tick is counted as idle if the CPU was halted when it happened.
+pub fn cpu_idle_percent() -> f64 {
+    let ticks = TICKS.load(Ordering::Relaxed);
+    if ticks == 0 {
+        return 100.0;
+    }
+    IDLE_TICKS.load(Ordering::Relaxed) as f64 / ticks as f64 * 100.0
+}
+
 pub fn handle_pit_interrupt() {
     // For some reason it's exactly half the correct speed so add 2 instead of 1
     // TODO: figure out why
-    TICKS.fetch_add(2, Ordering::Relaxed);
+    let ticks = TICKS.fetch_add(2, Ordering::Relaxed) + 2;
+    if IDLING.load(Ordering::Relaxed) {
+        IDLE_TICKS.fetch_add(2, Ordering::Relaxed);
+    }
+
+    for timer in &TIMERS {
+        let deadline = timer.deadline.load(Ordering::Acquire);
+        if deadline != 0 && ticks >= deadline {
+            timer.fired.store(true, Ordering::Release);
+            let period = timer.period.load(Ordering::Relaxed);
+            if period == 0 {
+                timer.deadline.store(0, Ordering::Release);
+            } else {
+                timer.deadline.store(ticks + period, Ordering::Release);
+            }
+        }
+    }
 }
 
-/// Represents a time
-#[allow(dead_code)]
+/// Sets a timer with the given ID to fire after the given number of ticks (roughly milliseconds),
+/// then every that many ticks if it's periodic. Setting an ID which is already in use restarts that timer.
+/// Returns false if all `MAX_TIMERS` slots are in use.
+pub fn set_timer(ticks: usize, id: usize, periodic: bool) -> bool {
+    let ticks = ticks.max(1);
+    let timer = match find_timer(id) {
+        Some(timer) => timer,
+        None => match TIMERS.iter().find(|timer| {
+            timer
+                .in_use
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        }) {
+            Some(timer) => timer,
+            None => return false,
+        },
+    };
+
+    // Stop the timer while it's being changed so the interrupt handler doesn't see it half-set
+    timer.deadline.store(0, Ordering::Release);
+    timer.id.store(id, Ordering::Relaxed);
+    timer
+        .period
+        .store(if periodic { ticks } else { 0 }, Ordering::Relaxed);
+    timer.fired.store(false, Ordering::Release);
+    timer
+        .deadline
+        .store(TICKS.load(Ordering::Relaxed) + ticks, Ordering::Release);
+    true
+}
+
+/// Checks whether the timer with the given ID has fired since this was last called.
+/// One-shot timers are freed once their firing has been seen.
+pub fn timer_fired(id: usize) -> bool {
+    match find_timer(id) {
+        Some(timer) => {
+            let fired = timer.fired.swap(false, Ordering::AcqRel);
+            if fired && timer.deadline.load(Ordering::Acquire) == 0 {
+                timer.in_use.store(false, Ordering::Release);
+            }
+            fired
+        }
+        None => false,
+    }
+}
+
+/// Stops the timer with the given ID and frees its slot.
+pub fn cancel_timer(id: usize) {
+    if let Some(timer) = find_timer(id) {
+        timer.deadline.store(0, Ordering::Release);
+        timer.fired.store(false, Ordering::Release);
+        timer.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// Finds the slot of the timer with the given ID, if it's set.
+fn find_timer(id: usize) -> Option<&'static Timer> {
+    TIMERS.iter().find(|timer| {
+        timer.in_use.load(Ordering::Acquire) && timer.id.load(Ordering::Relaxed) == id
+    })
+}
+
+/// Represents a time.
+/// Comparisons are made via the equivalent Unix timestamp, so the weekday is ignored.
+#[derive(Clone)]
 pub struct DateTime {
     second: u8,
     minute: u8,
@@ -48,23 +183,21 @@ pub struct DateTime {
     weekday: u8,
     day: u8,
     month: u8,
-    year: u8,
+    year: u16,
 }
 
 impl DateTime {
     /// Get the current time
     pub fn get() -> Self {
-        let mut control_port: Port<u8> = Port::new(0x70);
-        let mut data_port: Port<u8> = Port::new(0x71);
-
-        let mut raw_values: Vec<u8> = Vec::new();
-
-        // Iterate over registers and read them
-        for register in &[0x00_u8, 0x02, 0x04, 0x06, 0x07, 0x08, 0x09, 0x0B] {
-            unsafe {
-                control_port.write(*register);
-                raw_values.push(data_port.read());
+        // The registers could change between reads if the RTC updated part-way through,
+        // so read them until two reads in a row match (giving up after a few tries)
+        let mut raw_values = Self::read_registers();
+        for _ in 0..RTC_READ_ATTEMPTS {
+            let next_values = Self::read_registers();
+            if next_values == raw_values {
+                break;
             }
+            raw_values = next_values;
         }
 
         // BCD Mode (https://wiki.osdev.org/CMOS#Format_of_Bytes)
@@ -77,8 +210,15 @@ impl DateTime {
             raw_values[4] = (raw_values[4] & 0x0F) + ((raw_values[4] / 16) * 10);
             raw_values[5] = (raw_values[5] & 0x0F) + ((raw_values[5] / 16) * 10);
             raw_values[6] = (raw_values[6] & 0x0F) + ((raw_values[6] / 16) * 10);
+            raw_values[8] = (raw_values[8] & 0x0F) + ((raw_values[8] / 16) * 10);
         }
 
+        // The century register isn't standard, so only trust it if it holds a plausible century
+        let century = match raw_values[8] {
+            century @ 19..=99 => century as u16,
+            _ => 20,
+        };
+
         // 12-hour to 24-hour conversion
         if (raw_values[7] & 0x02 == 0) && (raw_values[2] & 0x80 == 0) {
             raw_values[2] = ((raw_values[2] & 0x7F) + 12) % 24;
@@ -91,10 +231,116 @@ impl DateTime {
             weekday: raw_values[3],
             day: raw_values[4],
             month: raw_values[5],
-            year: raw_values[6],
+            year: century * 100 + raw_values[6] as u16,
         }
     }
 
+    /// Read the raw time registers from the RTC, once it isn't in the middle of an update.
+    fn read_registers() -> [u8; 9] {
+        let mut control_port: Port<u8> = Port::new(0x70);
+        let mut data_port: Port<u8> = Port::new(0x71);
+
+        let mut raw_values = [0_u8; 9];
+
+        // Iterate over registers and read them
+        // Interrupts are disabled so the status bar can't select a different register part-way through
+        without_interrupts(|| {
+            // Wait for the update-in-progress flag to clear, which takes at most a couple of milliseconds
+            for _ in 0..RTC_UPDATE_POLLS {
+                unsafe {
+                    control_port.write(0x0A);
+                    if data_port.read() & 0x80 == 0 {
+                        break;
+                    }
+                }
+                spin_loop();
+            }
+
+            for (value, register) in raw_values
+                .iter_mut()
+                .zip(&[0x00_u8, 0x02, 0x04, 0x06, 0x07, 0x08, 0x09, 0x0B, 0x32])
+            {
+                unsafe {
+                    control_port.write(*register);
+                    *value = data_port.read();
+                }
+            }
+        });
+
+        raw_values
+    }
+
+    /// Convert the time to a Unix timestamp (seconds since midnight on 1 January 1970).
+    /// Uses the days-from-civil algorithm, which treats March as the first month of the year
+    /// so that the leap day falls at the very end.
+    pub fn to_unix(&self) -> u64 {
+        let year = self.year as i64;
+        let month = self.month as i64;
+        let (shifted_year, shifted_month) = if month <= 2 {
+            (year - 1, month + 9)
+        } else {
+            (year, month - 3)
+        };
+
+        let era = shifted_year / 400;
+        let year_of_era = shifted_year - era * 400;
+        let day_of_year = (153 * shifted_month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        (days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64)
+            as u64
+    }
+
+    /// Create a time from a Unix timestamp (seconds since midnight on 1 January 1970).
+    /// The inverse of `to_unix`, also working out the weekday.
+    pub fn from_unix(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+
+        let shifted_days = days + 719_468;
+        let era = shifted_days / 146_097;
+        let day_of_era = shifted_days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            second: (seconds % 60) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            hour: (seconds / 3600) as u8,
+            weekday: ((days + 4) % 7 + 1) as u8, // 1 January 1970 was a Thursday
+            day: day as u8,
+            month: month as u8,
+            year: year as u16,
+        }
+    }
+
+    /// Format only the time of day, e.g. `12:30`
+    pub fn to_time_string(&self) -> String {
+        format!("{:02}:{:02}", self.hour, self.minute)
+    }
+
+    /// Format the date and time compactly with a fixed width, e.g. `15 Oct 2026 12:30`
+    pub fn to_short_string(&self) -> String {
+        format!(
+            "{:02} {} {} {:02}:{:02}",
+            self.day,
+            &self.get_month_name()[..3],
+            self.year,
+            self.hour,
+            self.minute
+        )
+    }
+
     /// Get the name of the day, e.g. Monday
     pub fn get_day_name(&self) -> &'static str {
         match self.weekday {
@@ -133,7 +379,7 @@ impl Display for DateTime {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "{:02}:{:02}, {} {} {} 20{:02}",
+            "{:02}:{:02}, {} {} {} {}",
             self.hour,
             self.minute,
             self.get_day_name(),
@@ -144,6 +390,26 @@ impl Display for DateTime {
     }
 }
 
+impl PartialEq for DateTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_unix() == other.to_unix()
+    }
+}
+
+impl Eq for DateTime {}
+
+impl PartialOrd for DateTime {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DateTime {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.to_unix().cmp(&other.to_unix())
+    }
+}
+
 /// Gets number of CPU operations completed
 pub fn rdtsc() -> u64 {
     unsafe {
@@ -46,6 +46,14 @@ struct ScreenChar {
     colour_code: ColourCode,
 }
 
+/// Shape of the blinking text cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Hidden,
+}
+
 pub const BUFFER_HEIGHT: usize = 25;
 pub const BUFFER_WIDTH: usize = 80;
 
@@ -54,23 +62,105 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// A position on the screen saved with `Writer::save_cursor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorPosition {
+    pub row: usize,
+    pub col: usize,
+}
+
 pub struct Writer {
     pub column_position: usize,
+    /// Row being written to, which is the bottom row unless the cursor has been moved with `goto`.
+    pub row_position: usize,
+    /// Number of rows at the top of the screen which are kept when the output scrolls or is cleared.
+    top_margin: usize,
     colour_code: ColourCode,
+    cursor_shape: CursorShape,
     buffer: &'static mut Buffer,
+    /// Copy of the screen which everything is drawn into first, so scrolling never has to read video memory.
+    back_buffer: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    /// Whether drawing only goes to the back buffer until `present` is called, to avoid flicker in full-screen redraws.
+    buffered: bool,
+    /// Whether the screen is covered by an overlay, such as the screensaver, so drawing only goes to the back buffer
+    /// until the overlay is hidden.
+    overlay: bool,
+    /// Whether the cursor should be shown, which it isn't while an overlay is.
+    cursor_visible: bool,
 }
 
 impl Writer {
+    /// Create a writer for the screen, keeping whatever is already on it.
     pub fn new() -> Writer {
+        let buffer = unsafe { &mut *(0xb8000 as *mut Buffer) };
+        let mut back_buffer = [[ScreenChar {
+            ascii: b' ',
+            colour_code: ColourCode::new(Colour::White, Colour::Black),
+        }; BUFFER_WIDTH]; BUFFER_HEIGHT];
+        for (row, chars) in back_buffer.iter_mut().enumerate() {
+            for (col, character) in chars.iter_mut().enumerate() {
+                *character = buffer.chars[row][col].read();
+            }
+        }
+
         Writer {
             column_position: 0,
+            row_position: BUFFER_HEIGHT - 1,
+            top_margin: 0,
             colour_code: ColourCode::new(Colour::White, Colour::Black),
-            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            cursor_shape: CursorShape::Underline,
+            buffer,
+            back_buffer,
+            buffered: false,
+            overlay: false,
+            cursor_visible: true,
+        }
+    }
+
+    /// Start or stop drawing only to the back buffer.
+    /// While buffered, nothing appears on screen until `present` is called, so a full-screen redraw can be shown
+    /// all at once. Stopping presents the back buffer, so the screen is up to date again.
+    pub fn set_buffered(&mut self, buffered: bool) {
+        self.buffered = buffered;
+        if !buffered {
+            self.present();
+        }
+    }
+
+    /// Copy the back buffer to the screen in one pass and move the cursor to where it should be.
+    /// Does nothing while an overlay is shown, as the back buffer is presented when it is hidden.
+    pub fn present(&mut self) {
+        if self.overlay {
+            return;
+        }
+
+        for (row, chars) in self.back_buffer.iter().enumerate() {
+            for (col, character) in chars.iter().enumerate() {
+                self.buffer.chars[row][col].write(*character);
+            }
+        }
+
+        let buffered = core::mem::replace(&mut self.buffered, false);
+        unsafe { self.update_cursor(self.column_position, self.row_position) };
+        self.buffered = buffered;
+    }
+
+    /// Draw a character to the back buffer, and to the screen too unless drawing is buffered.
+    fn put_char(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.back_buffer[row][col] = character;
+        if !self.buffered && !self.overlay {
+            self.buffer.chars[row][col].write(character);
         }
     }
 
     /// Set cursor position
+    /// Does nothing while drawing is buffered or an overlay is shown, as the cursor is moved when the back buffer is
+    /// presented.
     unsafe fn update_cursor(&mut self, x: usize, y: usize) {
+        if self.buffered || self.overlay {
+            return;
+        }
+
         let mut cursor_port_1: Port<u8> = Port::new(0x3D4); // these two registers work together to store a `u16`
         let mut cursor_port_2: Port<u8> = Port::new(0x3D5); // they are separate though so we address them separately
         let pos = y as u16 * BUFFER_WIDTH as u16 + x as u16;
@@ -82,6 +172,133 @@ impl Writer {
         cursor_port_2.write(((pos >> 8) & 0xFF) as u8);
     }
 
+    /// Move the cursor to the given position, so output continues from there.
+    /// Returns false and leaves the cursor where it was if the position is off the screen.
+    pub fn goto(&mut self, row: usize, col: usize) -> bool {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return false;
+        }
+
+        self.row_position = row;
+        self.column_position = col;
+        unsafe { self.update_cursor(col, row) };
+        true
+    }
+
+    /// Reserve rows at the top of the screen which the output doesn't scroll into or clear, such as for a status bar.
+    /// The bottom row can't be reserved, as that is where the output is written.
+    pub fn set_top_margin(&mut self, rows: usize) {
+        self.top_margin = rows.min(BUFFER_HEIGHT - 1);
+    }
+
+    /// Get the number of rows the output scrolls through, which is every row below the top margin.
+    pub fn scrolling_rows(&self) -> usize {
+        BUFFER_HEIGHT - self.top_margin
+    }
+
+    /// Get the current position of the cursor, to return to later with `restore_cursor`.
+    pub fn save_cursor(&self) -> CursorPosition {
+        CursorPosition {
+            row: self.row_position,
+            col: self.column_position,
+        }
+    }
+
+    /// Move the cursor back to a position from `save_cursor`.
+    pub fn restore_cursor(&mut self, position: CursorPosition) {
+        self.row_position = position.row;
+        self.column_position = position.col;
+        unsafe { self.update_cursor(position.col, position.row) };
+    }
+
+    /// Set the shape of the cursor, which is kept until it is changed again.
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.cursor_shape = shape;
+        self.cursor_visible = true;
+        unsafe { self.write_cursor_shape(shape) };
+    }
+
+    /// Get the shape of the cursor, as last set with `set_cursor_shape`.
+    pub fn cursor_shape(&self) -> CursorShape {
+        self.cursor_shape
+    }
+
+    /// Temporarily hide the cursor (e.g. during bulk output) without forgetting its shape.
+    pub fn hide_cursor(&mut self) {
+        self.cursor_visible = false;
+        unsafe { self.write_cursor_shape(CursorShape::Hidden) };
+    }
+
+    /// Show the cursor again after it was hidden with `hide_cursor`.
+    pub fn show_cursor(&mut self) {
+        self.cursor_visible = true;
+        unsafe { self.write_cursor_shape(self.cursor_shape) };
+    }
+
+    /// Cover the screen with an overlay drawn with `draw_overlay`, hiding the cursor.
+    /// Output carries on into the back buffer, and is shown when the overlay is hidden.
+    pub fn show_overlay(&mut self) {
+        unsafe { self.write_cursor_shape(CursorShape::Hidden) };
+        self.overlay = true;
+    }
+
+    /// Draw a character on the overlay, if it is shown.
+    pub fn draw_overlay(&mut self, byte: u8, colour_code: ColourCode, row: usize, col: usize) {
+        if self.overlay && row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii: byte,
+                colour_code,
+            });
+        }
+    }
+
+    /// Hide the overlay, putting back what it covered and the cursor if it was shown.
+    pub fn hide_overlay(&mut self) {
+        self.overlay = false;
+        if self.cursor_visible {
+            unsafe { self.write_cursor_shape(self.cursor_shape) };
+        }
+        if !self.buffered {
+            self.present();
+        }
+    }
+
+    /// Checks if the screen is covered by an overlay.
+    pub fn is_overlay_shown(&self) -> bool {
+        self.overlay
+    }
+
+    /// Set the cursor's start and end scanlines, or disable it
+    /// Does nothing while an overlay is shown, as the cursor is put back when it is hidden.
+    unsafe fn write_cursor_shape(&mut self, shape: CursorShape) {
+        if self.overlay {
+            return;
+        }
+
+        let mut index_port: Port<u8> = Port::new(0x3D4);
+        let mut data_port: Port<u8> = Port::new(0x3D5);
+
+        // characters are 16 scanlines tall in the default text mode
+        let (start, end) = match shape {
+            CursorShape::Block => (0, 15),
+            CursorShape::Underline => (14, 15),
+            CursorShape::Hidden => {
+                // bit 5 of the cursor start register disables the cursor
+                index_port.write(0x0A);
+                data_port.write(0x20);
+                return;
+            }
+        };
+
+        // keep the reserved upper bits of each register as they were
+        index_port.write(0x0A);
+        let previous_start = data_port.read();
+        data_port.write((previous_start & 0xC0) | start);
+        index_port.write(0x0B);
+        let previous_end = data_port.read();
+        data_port.write((previous_end & 0xE0) | end);
+    }
+
     /// Write a character to the output
     pub fn write_char(&mut self, byte: u8) {
         match byte {
@@ -91,26 +308,42 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii: byte,
-                    colour_code: self.colour_code,
-                });
+                self.put_char(
+                    row,
+                    col,
+                    ScreenChar {
+                        ascii: byte,
+                        colour_code: self.colour_code,
+                    },
+                );
 
                 self.column_position += 1;
-                unsafe { self.update_cursor(self.column_position, BUFFER_HEIGHT - 1) };
+                unsafe { self.update_cursor(self.column_position, self.row_position) };
             }
         }
     }
 
-    /// Overwrite the last character of the output
+    /// Overwrite the last character of the output.
+    /// If the cursor is at the start of the row, it moves back to the end of the previous row,
+    /// scrolling the output back if it is on the bottom row.
     pub fn overwrite_char(&mut self, byte: u8) {
+        if self.column_position == 0 {
+            if self.row_position == BUFFER_HEIGHT - 1 {
+                self.previous_line();
+            } else if self.row_position > self.top_margin {
+                self.row_position -= 1;
+                self.column_position = BUFFER_WIDTH;
+            } else {
+                return;
+            }
+        }
         self.column_position -= 1;
         self.write_char(byte);
         self.column_position -= 1;
-        unsafe { self.update_cursor(self.column_position, BUFFER_HEIGHT - 1) };
+        unsafe { self.update_cursor(self.column_position, self.row_position) };
     }
 
     /// Write a string to the output
@@ -130,34 +363,99 @@ impl Writer {
         self.colour_code = ColourCode::new(Colour::White, Colour::Black);
     }
 
-    /// Write a character at a specific position to the output
+    /// Write a character at a specific position to the output, moving the cursor to just after it.
+    /// Use `save_cursor` and `restore_cursor` to put the cursor back afterwards.
     pub fn write_char_at(&mut self, byte: u8, row: usize, col: usize) {
-        self.buffer.chars[row][col].write(ScreenChar {
-            ascii: byte,
-            colour_code: self.colour_code,
-        });
+        if self.goto(row, col) {
+            self.write_char(byte);
+        }
     }
 
-    /// Create a new line
+    /// Draw a progress bar across the given row, such as `[####    ]  50%`.
+    /// The bar is drawn in place, so it doesn't scroll the output or move the cursor.
+    pub fn draw_progress(&mut self, row: usize, fraction: f64) {
+        let cursor = self.save_cursor();
+        let fraction = fraction.max(0.0).min(1.0);
+        let percentage = (fraction * 100.0) as usize;
+        let bar_width = BUFFER_WIDTH - 7; // leave room for the brackets and percentage
+        let filled = (fraction * bar_width as f64) as usize;
+
+        self.write_char_at(b'[', row, 0);
+        for col in 0..bar_width {
+            let block = if col < filled { 0xDB } else { 0xB0 }; // full and light shade blocks
+            self.write_char_at(block, row, col + 1);
+        }
+        self.write_char_at(b']', row, bar_width + 1);
+
+        // Right-align the percentage, padding with spaces instead of leading zeros
+        let digits = [percentage / 100, (percentage / 10) % 10, percentage % 10];
+        for (index, digit) in digits.iter().enumerate() {
+            let is_padding = index < 2 && digits[..=index].iter().all(|d| *d == 0);
+            let byte = if is_padding {
+                b' '
+            } else {
+                b'0' + *digit as u8
+            };
+            self.write_char_at(byte, row, bar_width + 3 + index);
+        }
+        self.write_char_at(b'%', row, bar_width + 6);
+
+        self.restore_cursor(cursor);
+    }
+
+    /// Create a new line.
+    /// The output only scrolls if the cursor is on the bottom row, otherwise it moves down a row.
     pub fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
-            }
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            self.column_position = 0;
+            unsafe { self.update_cursor(0, self.row_position) };
+            return;
         }
+
+        // Scroll the back buffer, then show it all at once rather than moving every character on screen
+        self.back_buffer
+            .copy_within(self.top_margin + 1..BUFFER_HEIGHT, self.top_margin);
         self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
+        if !self.buffered {
+            self.present();
+        }
+    }
+
+    /// Scroll the output down a row, undoing a new line so the cursor is at the end of the previous row.
+    /// The top row below the margin is left blank as whatever was there has already scrolled off the screen.
+    fn previous_line(&mut self) {
+        self.back_buffer
+            .copy_within(self.top_margin..BUFFER_HEIGHT - 1, self.top_margin + 1);
+        self.clear_row(self.top_margin);
+        self.column_position = BUFFER_WIDTH;
+        if !self.buffered {
+            self.present();
+        }
     }
 
-    /// Clear a row of the output with blank characters
+    /// Clear every row except the one being written to and the top margin, leaving the current line on screen.
+    pub fn clear_above(&mut self) {
+        for row in self.top_margin..BUFFER_HEIGHT {
+            if row != self.row_position {
+                self.clear_row(row);
+            }
+        }
+    }
+
+    /// Clear a row of the output with blank characters, unless it is in the top margin
     fn clear_row(&mut self, row: usize) {
+        if row < self.top_margin {
+            return;
+        }
+
         let blank_char = ScreenChar {
             ascii: b' ',
             colour_code: ColourCode::new(Colour::White, Colour::Black),
         };
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank_char);
+            self.put_char(row, col, blank_char);
         }
     }
 }
@@ -170,11 +468,7 @@ impl Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        colour_code: ColourCode::new(Colour::White, Colour::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new());
 }
 
 #[macro_export]
@@ -188,7 +482,18 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Print from inside an interrupt handler.
+///
+/// Code outside interrupt handlers must only lock `WRITER` with interrupts disabled, as `print!` and the
+/// log helpers do, so a handler should never find it locked. If it somehow is, waiting for it would
+/// deadlock the kernel since the holder can't run until the handler returns, so this drops the output instead.
+#[macro_export]
+macro_rules! interrupt_print {
+    ($($arg:tt)*) => ($crate::vga::_try_print(format_args!($($arg)*)));
+}
+
 pub fn err(string: &str) -> ExitCode {
+    crate::serial::mirror_log("ERR", format_args!("{}\n", string));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.write_char(b'[');
@@ -201,6 +506,7 @@ pub fn err(string: &str) -> ExitCode {
 }
 
 pub fn warn(string: &str) {
+    crate::serial::mirror_log("WARN", format_args!("{}", string));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.write_char(b'[');
@@ -211,6 +517,7 @@ pub fn warn(string: &str) {
 }
 
 pub fn info(string: &str) {
+    crate::serial::mirror_log("INFO", format_args!("{}", string));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.write_char(b'[');
@@ -220,7 +527,35 @@ pub fn info(string: &str) {
     });
 }
 
+/// Set the shape of the cursor.
+pub fn set_cursor_shape(shape: CursorShape) {
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_cursor_shape(shape);
+    });
+}
+
+/// Hide or show the cursor, keeping its shape for when it is shown again.
+pub fn set_cursor_visible(visible: bool) {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        if visible {
+            writer.show_cursor();
+        } else {
+            writer.hide_cursor();
+        }
+    });
+}
+
+/// Draw a progress bar across the given row, without scrolling the output.
+/// `fraction` is clamped between 0 and 1.
+pub fn progress(row: usize, fraction: f64) {
+    interrupts::without_interrupts(|| {
+        WRITER.lock().draw_progress(row, fraction);
+    });
+}
+
 pub fn okay(string: &str) -> ExitCode {
+    crate::serial::mirror_log("OKAY", format_args!("{}", string));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.write_char(b'[');
@@ -237,3 +572,31 @@ pub fn _print(args: core::fmt::Arguments) {
         WRITER.lock().write_fmt(args).unwrap();
     });
 }
+
+/// Print to the console if `WRITER` isn't already locked, otherwise discard the output.
+/// Returns whether the output was printed.
+#[doc(hidden)]
+pub fn _try_print(args: core::fmt::Arguments) -> bool {
+    if let Some(mut writer) = WRITER.try_lock() {
+        writer.write_fmt(args).unwrap();
+        true
+    } else {
+        false
+    }
+}
+
+/// Print to the console even if `WRITER` is already locked, by forcibly unlocking it first.
+/// Only use this when the kernel can't continue (e.g. from the panic handler),
+/// as whatever was holding the lock will be interrupted part-way through its output.
+pub fn emergency_print(args: core::fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        unsafe { WRITER.force_unlock() };
+        let mut writer = WRITER.lock();
+        // Whatever was drawing a frame won't finish it, so show the output straight away
+        writer.hide_overlay();
+        writer.set_buffered(false);
+        writer.colour_code = ColourCode::new(Colour::LightRed, Colour::Black);
+        writer.write_fmt(args).unwrap();
+        writer.colour_code = ColourCode::new(Colour::White, Colour::Black);
+    });
+}